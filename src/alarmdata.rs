@@ -1,20 +1,26 @@
-use sqlx::{PgPool, Row,Column};
-use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Row};
 use std::collections::{BTreeMap, HashMap};
-use serde_json;
+use std::collections::hash_map::Entry;
 use std::{fs};
 use std::error::Error;
 use chrono::NaiveDateTime;
 use tracing::debug;
 
-use crate::variants::{ChipRecord,AlarmDetail,LotUnitData,AlarmCounts};
+use crate::variants::{AlarmDetail,LotUnitData,AlarmCounts};
+use crate::rollup::rollup_range;
 
-pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,start_date:&str,end_date:&str) -> Result<HashMap<String, LotUnitData>,Box<dyn Error>> {
-
-    //アラームコード一覧をjsonから読み込み
+//アラームコード一覧(ステーションごとのアラームコード→説明)をjsonから読み込む
+pub fn load_alarm_detail(alarm_json_path:&str) -> Result<AlarmDetail,Box<dyn Error>> {
     let s=fs::read_to_string(alarm_json_path)?;
     let alarm_detail:AlarmDetail = serde_json::from_str(&s)?;
     debug!("Loaded alarm detail from JSON: {:#?}", alarm_detail);
+    Ok(alarm_detail)
+}
+
+pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,start_date:&str,end_date:&str) -> Result<HashMap<String, LotUnitData>,Box<dyn Error>> {
+
+    //アラームコード一覧をjsonから読み込み
+    let alarm_detail = load_alarm_detail(alarm_json_path)?;
 
     //alarm_detailから各ユニット毎のキー一覧を追加する
     let mut ld_alarmcode_vec:Vec<i32>=vec![];
@@ -63,16 +69,13 @@ pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,st
         uld_alarm:BTreeMap::new(),
     };
 
-    AlarmCounts::new(
-        &mut alarm_count_base,
-        ld_alarmcode_vec,
-        dc1_alarmcode_vec,
-        ac1_alarmcode_vec,
-        ac2_alarmcode_vec,
-        dc2_alarmcode_vec,
-        ip_alarmcode_vec,
-        uld_alarmcode_vec,
-    );
+    alarm_count_base.add_codes("LD", ld_alarmcode_vec);
+    alarm_count_base.add_codes("DC1", dc1_alarmcode_vec);
+    alarm_count_base.add_codes("AC1", ac1_alarmcode_vec);
+    alarm_count_base.add_codes("AC2", ac2_alarmcode_vec);
+    alarm_count_base.add_codes("DC2", dc2_alarmcode_vec);
+    alarm_count_base.add_codes("IP", ip_alarmcode_vec);
+    alarm_count_base.add_codes("ULD", uld_alarmcode_vec);
 
     // プールから接続を使用
 
@@ -80,24 +83,42 @@ pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,st
     let start_dt = NaiveDateTime::parse_from_str(start_date, "%Y-%m-%d %H:%M:%S")?;
     let end_dt = NaiveDateTime::parse_from_str(end_date, "%Y-%m-%d %H:%M:%S")?;
 
-    // アラームデータを取得
+    //1時間全体が含まれる部分は時間単位の集計テーブル(ロールアップ)から集計し、期間の端の残りだけ生データから集計する
+    let rollup_hours = rollup_range(pool, start_date, end_date, None).await?;
+    // lot_name をキーに LotUnitData を格納
+    let mut all_lots_hashmap: HashMap<String, LotUnitData> = match rollup_hours {
+        Some((start_hour, end_hour)) => {
+            debug!("Alarm data from rollup: {} - {}", start_hour, end_hour);
+            get_alarmdata_from_rollup(pool, &alarm_count_base, machine_id, start_hour, end_hour).await?
+        },
+        None => HashMap::new(),
+    };
+
+    // アラームデータを取得(ロールアップで集計した時間は除く)
     let sql = "SELECT type_name, lot_name, ld_alarm, dc1_alarm, ac1_alarm, ac2_alarm, dc2_alarm, ip_alarm, uld_alarm
                FROM CHIPDATA
                WHERE machine_id = $1 AND ld_pickup_date BETWEEN $2 AND $3
+                 AND ($4::timestamp IS NULL OR NOT (ld_pickup_date >= $4 AND ld_pickup_date < $5))
                ORDER BY serial ASC";
 
     let rows = sqlx::query(sql)
         .bind(machine_id)
         .bind(start_dt)
         .bind(end_dt)
+        .bind(rollup_hours.map(|(start_hour, _)| start_hour))
+        .bind(rollup_hours.map(|(_, end_hour)| end_hour))
         .fetch_all(pool)
         .await?;
 
-    // ロット名一覧を取得してlotdateテーブルから日付情報を取得
-    let mut lot_dates: HashMap<String, (String, String)> = HashMap::new();
-
-    // lot_name をキーに LotUnitData を格納
-    let mut all_lots_hashmap: HashMap<String, LotUnitData> = HashMap::new();
+    // 新しく出てきたロットの日付情報をlotdateテーブルからまとめて取得
+    let mut new_lot_names: Vec<String> = vec![];
+    for row in &rows {
+        let lot_name: Option<String> = row.try_get(1)?;
+        if let Some(lot_name) = lot_name && !all_lots_hashmap.contains_key(&lot_name) && !new_lot_names.contains(&lot_name) {
+            new_lot_names.push(lot_name);
+        }
+    }
+    let lot_dates = get_lot_dates(pool, &new_lot_names).await?;
 
     for row in rows {
         let type_name: Option<String> = row.try_get(0)?;
@@ -120,79 +141,48 @@ pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,st
             None => continue
         };
 
-        // lot_start_timeとlot_end_timeをキャッシュから取得、なければDBから取得
-        if !lot_dates.contains_key(&lot_name) {
-            let sql = "SELECT start_date, end_date FROM lotdate WHERE lot_name = $1";
-            let metadata = sqlx::query(sql)
-                .bind(&lot_name)
-                .fetch_one(pool)
-                .await?;
-
-            let start_date: NaiveDateTime = metadata.try_get("start_date")?;
-            let end_date: NaiveDateTime = metadata.try_get("end_date")?;
-
-            lot_dates.insert(
-                lot_name.clone(),
-                (start_date.to_string(), end_date.to_string())
-            );
-        }
-
-        let (lot_start_time, lot_end_time) = lot_dates.get(&lot_name).unwrap();
-
-        // HashMapにキーが無ければ新規作成
-        let lot_entry = all_lots_hashmap
-            .entry(lot_name.clone())
-            .or_insert_with(|| {
-                LotUnitData {
-                    machine_id: machine_id,
-                    type_name: type_name.clone(),
+        // HashMapにキーが無ければ新規作成(lotdateテーブルに無いロットはスキップ)
+        let lot_entry = match all_lots_hashmap.entry(lot_name) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match lot_dates.get(entry.key()) {
+                Some((lot_start_time, lot_end_time)) => entry.insert(LotUnitData {
+                    machine_id,
+                    type_name,
                     lot_start_time: lot_start_time.clone(),
                     lot_end_time: lot_end_time.clone(),
                     alarm_counts: alarm_count_base.clone()
-                }
-            });
+                }),
+                None => continue
+            },
+        };
 
         // 各アラームをカウント
-        if let Some(code) = ld_alarm {
-            if let Some(count) = lot_entry.alarm_counts.ld_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = ld_alarm && let Some(count) = lot_entry.alarm_counts.ld_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = dc1_alarm {
-            if let Some(count) = lot_entry.alarm_counts.dc1_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = dc1_alarm && let Some(count) = lot_entry.alarm_counts.dc1_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = ac1_alarm {
-            if let Some(count) = lot_entry.alarm_counts.ac1_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = ac1_alarm && let Some(count) = lot_entry.alarm_counts.ac1_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = ac2_alarm {
-            if let Some(count) = lot_entry.alarm_counts.ac2_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = ac2_alarm && let Some(count) = lot_entry.alarm_counts.ac2_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = dc2_alarm {
-            if let Some(count) = lot_entry.alarm_counts.dc2_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = dc2_alarm && let Some(count) = lot_entry.alarm_counts.dc2_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = ip_alarm {
-            if let Some(count) = lot_entry.alarm_counts.ip_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = ip_alarm && let Some(count) = lot_entry.alarm_counts.ip_alarm.get_mut(&code) {
+            *count += 1;
         }
 
-        if let Some(code) = uld_alarm {
-            if let Some(count) = lot_entry.alarm_counts.uld_alarm.get_mut(&code) {
-                *count += 1;
-            }
+        if let Some(code) = uld_alarm && let Some(count) = lot_entry.alarm_counts.uld_alarm.get_mut(&code) {
+            *count += 1;
         }
     }
 
@@ -200,3 +190,74 @@ pub async fn get_alarmdata(pool: &PgPool, alarm_json_path:&str,machine_id:i32,st
 
     Ok(all_lots_hashmap)
 }
+
+//時間単位の集計テーブルからロットごとのアラーム件数を集計する(生データと同じくロット名・品種名がNULLのチップは除く)
+async fn get_alarmdata_from_rollup(pool: &PgPool, alarm_count_base:&AlarmCounts, machine_id:i32, start_hour:NaiveDateTime, end_hour:NaiveDateTime) -> Result<HashMap<String, LotUnitData>,Box<dyn Error>> {
+    // ロットごとの品種名(複数ある場合は最初の時間のもの)
+    let sql = "SELECT lot_name, (array_agg(type_name ORDER BY hour_start))[1]
+               FROM chipdata_hourly
+               WHERE machine_id = $1 AND hour_start >= $2 AND hour_start < $3 AND lot_name IS NOT NULL AND type_name IS NOT NULL
+               GROUP BY lot_name";
+    let lots: Vec<(String, String)> = sqlx::query_as(sql)
+        .bind(machine_id)
+        .bind(start_hour)
+        .bind(end_hour)
+        .fetch_all(pool)
+        .await?;
+
+    let lot_names: Vec<String> = lots.iter().map(|(lot_name, _)| lot_name.clone()).collect();
+    let lot_dates = get_lot_dates(pool, &lot_names).await?;
+
+    // lotdateテーブルに無いロットはスキップ
+    let mut all_lots_hashmap: HashMap<String, LotUnitData> = HashMap::new();
+    for (lot_name, type_name) in lots {
+        let Some((lot_start_time, lot_end_time)) = lot_dates.get(&lot_name) else {
+            continue;
+        };
+        all_lots_hashmap.insert(lot_name, LotUnitData {
+            machine_id,
+            type_name,
+            lot_start_time: lot_start_time.clone(),
+            lot_end_time: lot_end_time.clone(),
+            alarm_counts: alarm_count_base.clone()
+        });
+    }
+
+    // ステーション・アラームコードごとの件数(jsonに無いアラームコードは数えない)
+    let sql = "SELECT lot_name, station, alarm_code, SUM(alarm_count)::bigint
+               FROM chipdata_hourly_alarms
+               WHERE machine_id = $1 AND hour_start >= $2 AND hour_start < $3 AND lot_name IS NOT NULL AND type_name IS NOT NULL
+               GROUP BY 1, 2, 3";
+    let alarm_rows: Vec<(String, String, i32, i64)> = sqlx::query_as(sql)
+        .bind(machine_id)
+        .bind(start_hour)
+        .bind(end_hour)
+        .fetch_all(pool)
+        .await?;
+
+    for (lot_name, station, code, alarm_count) in alarm_rows {
+        if let Some(lot_entry) = all_lots_hashmap.get_mut(&lot_name)
+            && let Some(counts) = lot_entry.alarm_counts.station_mut(&station)
+            && let Some(count) = counts.get_mut(&code) {
+            *count += alarm_count as u32;
+        }
+    }
+
+    Ok(all_lots_hashmap)
+}
+
+//ロット名→(開始日時, 終了日時)をlotdateテーブルからまとめて取得する(lotdateに無いロットは含まれない)
+async fn get_lot_dates(pool: &PgPool, lot_names: &[String]) -> Result<HashMap<String, (String, String)>,Box<dyn Error>> {
+    if lot_names.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let sql = "SELECT lot_name, start_date, end_date FROM lotdate WHERE lot_name = ANY($1)";
+    let rows: Vec<(String, NaiveDateTime, NaiveDateTime)> = sqlx::query_as(sql)
+        .bind(lot_names)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|(lot_name, start_date, end_date)| (lot_name, (start_date.to_string(), end_date.to_string())))
+        .collect())
+}
@@ -1,114 +1,90 @@
 /* プロット用のアラームデータを取得する関数 */
-use sqlx::{PgPool, Row};
+use sqlx::PgPool;
 use std::error::Error;
 use std::collections::HashMap;
 
 use crate::graph::variants::*;
+use crate::graph::plotdata::{query_density_grid,query_histogram_buckets};
+use crate::graph::histogram::bucket_counts_to_bins;
+
+//アラームのチップだけの集計結果(通常データの取得と同時に集計し、通常データの取得後にdata_mapへ格納する)
+pub enum AlarmCounts{
+    Histogram(HashMap<String,Vec<i32>>),        //ユニット名→width_bucketのバケットごとの件数
+    DensityPlot(HashMap<String,Vec<Vec<i32>>>), //ユニット名→グリッドごとの件数(arr[x][y])
+}
 
 /* histogram */
-//プロット分割しないヒストグラムのアラーム部分だけのデータを取得
-pub async fn plot_histogram_without_unit_only_alarm_data(_total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],bin_info:&HistogramBinInfo)->Result<(),Box<dyn Error>>{
-    data_map.entry("alarm_data".to_string()).or_insert(vec![]);
+//ヒストグラムのアラーム部分だけを通常データと同じビンを使ってSQL側で集計する
+pub async fn query_histogram_only_alarm_data(pool:&PgPool,sql:&str,params:&[String],bin_edges:&[f64])->Result<AlarmCounts,Box<dyn Error>>{
+    Ok(AlarmCounts::Histogram(query_histogram_buckets(pool, sql, params, bin_edges).await?))
+}
 
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
-    }
-    let rows_data = query.fetch_all(pool).await?;
+//ヒストグラムのアラーム部分を"alarm_" + ユニット名 のキーに格納する(ユニット分割しない場合はalarm_data)
+fn plot_histogram_only_alarm_data(data_map:&mut HashMap<String,Vec<PlotData>>,unit_buckets:HashMap<String,Vec<i32>>,bin_info:&HistogramBinInfo){
+    for (unit_name, bucket_counts) in unit_buckets {
+        let (bin_counts, _, _) = bucket_counts_to_bins(&bucket_counts, &bin_info.outliers);
 
-    let mut query_rows: Vec<i32> = Vec::new();
-    for row in rows_data {
-        if let Ok(Some(x_value)) = row.try_get::<Option<i32>, _>(0) {
-            query_rows.push(x_value);
+        // BinnedHistogramDataとして格納
+        let rows = data_map.entry("alarm_".to_string()+&unit_name).or_insert(vec![]);
+        for (bin_index, count) in bin_counts.into_iter().enumerate() {
+            rows.push(PlotData::BinnedHistogram(BinnedHistogramData {
+                bin_index,
+                count,
+            }));
         }
     }
+}
 
-    if query_rows.is_empty() || bin_info.bin_edges.is_empty(){
-        return Ok(());
-    }
-
-    // 通常データと同じビンを使ってアラームデータを集計
-    let x_min = bin_info.bin_edges[0];
-    let bin_width = bin_info.bin_width;
-    let bin_count = bin_info.bin_edges.len() - 1;
-
-    let mut bin_counts = vec![0; bin_count];
-    for value in query_rows {
-        let bin_index = if bin_width == 0.0 {
-            0
-        } else {
-            (((value - x_min) as f64 / bin_width) as usize).min(bin_count - 1)
-        };
-        bin_counts[bin_index] += 1;
-    }
-
-    // アラームデータを "alarm_data" キーに保存
-    let rows = data_map.get_mut("alarm_data").unwrap();
-    for (bin_index, count) in bin_counts.into_iter().enumerate() {
-        rows.push(PlotData::BinnedHistogram(BinnedHistogramData {
-            bin_index,
-            count,
-        }));
-    }
-
-    Ok(())
-
+/* density plot */
+//密度プロットのアラーム部分だけを通常データと同じグリッドを使ってSQL側で集計する
+pub async fn query_densityplot_only_alarm_data(pool:&PgPool,sql:&str,params:&[String],grid_data:&GridData,graph_condition:&GraphCondition)->Result<AlarmCounts,Box<dyn Error>>{
+    Ok(AlarmCounts::DensityPlot(query_density_grid(pool, sql, params, grid_data, graph_condition).await?))
 }
 
-//プロット分割するヒストグラムのアラームデータを取得
-pub async fn plot_histogram_with_unit_only_alarm_data(_total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],bin_info:&HistogramBinInfo)->Result<(),Box<dyn Error>>{
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
+//アラームの集計結果をdata_mapに追加する(通常データを格納した後に呼ぶ)
+//密度プロットのoverlayがRatioの場合は通常データのグリッド毎の個数で割った比率を格納する
+pub fn push_alarm_counts(data_map:&mut HashMap<String,Vec<PlotData>>,alarm_counts:AlarmCounts,grid_data:&GridData,graph_condition:&GraphCondition){
+    match alarm_counts {
+        AlarmCounts::Histogram(unit_buckets) => {
+            if let Some(ref bin_info) = grid_data.histogram_bin_info {
+                plot_histogram_only_alarm_data(data_map, unit_buckets, bin_info);
+            }
+        },
+        AlarmCounts::DensityPlot(unit_arr) => {
+            for (unit_name, arr) in unit_arr {
+                push_alarm_grid(data_map, &unit_name, "alarm_".to_string()+&unit_name, &arr, graph_condition);
+            }
+        },
     }
-    let rows_data = query.fetch_all(pool).await?;
+}
 
-    let mut query_rows: Vec<(String,i32)> = Vec::new();
-    for row in rows_data {
-        let unit_name: String = row.try_get(0)?;
-        if let Ok(Some(x_value)) = row.try_get::<Option<i32>, _>(1) {
-            query_rows.push((unit_name, x_value));
+//アラームのグリッドデータをdata_mapに格納する
+//overlayがRatioの場合は通常データ(base_key)の同じグリッドの個数で割った比率を格納する
+fn push_alarm_grid(data_map:&mut HashMap<String,Vec<PlotData>>, base_key:&str, alarm_key:String, arr:&[Vec<i32>], graph_condition:&GraphCondition){
+    if graph_condition.alarm.overlay.eq_ignore_ascii_case("Ratio") {
+        //通常データのグリッド毎の個数を取得
+        let mut base_arr = vec![vec![0; graph_condition.bins_y as usize]; graph_condition.bins_x as usize];
+        if let Some(base_rows) = data_map.get(base_key) {
+            for data in base_rows {
+                if let PlotData::Heatmap(heatmap) = data {
+                    base_arr[heatmap.x_data as usize][heatmap.y_data as usize] = heatmap.z_data.unwrap_or(0);
+                }
+            }
         }
-    }
 
-    if query_rows.is_empty() || bin_info.bin_edges.is_empty(){
-        return Ok(());
-    }
-
-    // 通常データと同じビンを使用
-    let x_min = bin_info.bin_edges[0];
-    let bin_width = bin_info.bin_width;
-    let bin_count = bin_info.bin_edges.len() - 1;
-
-    // ユニットごとにデータを分けてビン化
-    let mut unit_data: HashMap<String, Vec<i32>> = HashMap::new();
-    for (unit_name, value) in query_rows {
-        unit_data.entry(unit_name).or_insert(vec![]).push(value);
-    }
-
-    // 各ユニットのアラームデータをビン化
-    for (unit_name, values) in unit_data {
-        let mut bin_counts = vec![0; bin_count];
-        for value in values {
-            let bin_index = if bin_width == 0.0 {
-                0
-            } else {
-                (((value - x_min) as f64 / bin_width) as usize).min(bin_count - 1)
-            };
-            bin_counts[bin_index] += 1;
+        let rows = data_map.entry(alarm_key).or_insert(vec![]);
+        for y in 0..graph_condition.bins_y as usize{
+            for x in 0..graph_condition.bins_x as usize{
+                let ratio = if base_arr[x][y] == 0 { None } else { Some(arr[x][y] as f64 / base_arr[x][y] as f64) };
+                rows.push(PlotData::HeatmapRatio(HeatmapRatioData{x_data:x as u32,y_data:y as u32,z_data:ratio}));
+            }
         }
-
-        // BinnedHistogramDataとして格納
-        for (bin_index, count) in bin_counts.into_iter().enumerate() {
-            data_map.entry("alarm_".to_string()+&unit_name).or_insert(vec![]).push(
-                PlotData::BinnedHistogram(BinnedHistogramData {
-                    bin_index,
-                    count,
-                })
-            );
+    } else {
+        let rows = data_map.entry(alarm_key).or_insert(vec![]);
+        for y in 0..graph_condition.bins_y{
+            for x in 0..graph_condition.bins_x{
+                rows.push(PlotData::Heatmap(HeatmapData{x_data:x,y_data:y,z_data:Some(arr[x as usize][y as usize])}));
+            }
         }
     }
-
-    Ok(())
-
 }
@@ -0,0 +1,177 @@
+/* CHIPDATAのカラム型を管理するモジュール */
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::error::Error;
+use indexmap::IndexMap;
+use once_cell::sync::{Lazy, OnceCell};
+use tracing::{debug, info, warn};
+
+//カラムの型
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize)]
+pub enum ColumnType{
+    Integer,    //SMALLINT/INTEGER/BIGINT
+    Real,       //REAL/DOUBLE PRECISION
+    Numeric,    //NUMERIC/DECIMAL
+    Timestamp,  //TIMESTAMP
+    Text,       //VARCHAR/TEXT
+}
+
+impl ColumnType{
+    //フィルター値をバインドする際のキャスト
+    pub fn cast(&self)->&'static str{
+        match self {
+            ColumnType::Integer => "::integer",
+            ColumnType::Real => "::double precision",
+            ColumnType::Numeric => "::numeric",
+            ColumnType::Timestamp => "::timestamp",
+            ColumnType::Text => "", // VARCHAR型などはキャスト不要
+        }
+    }
+
+    //PostgreSQLの型名から変換する
+    pub fn from_pg_type(pg_type:&str)->Option<ColumnType>{
+        match pg_type.to_lowercase().as_str() {
+            "smallint" | "integer" | "bigint" | "int2" | "int4" | "int8" => Some(ColumnType::Integer),
+            "real" | "double precision" | "float4" | "float8" => Some(ColumnType::Real),
+            "numeric" | "decimal" => Some(ColumnType::Numeric),
+            "timestamp" | "timestamp without time zone" | "timestamp with time zone" | "timestamptz" => Some(ColumnType::Timestamp),
+            "character varying" | "varchar" | "character" | "char" | "text" => Some(ColumnType::Text),
+            _ => None,
+        }
+    }
+
+    //数値としてプロット可能な型かどうか
+    pub fn is_numeric(&self)->bool{
+        matches!(self, ColumnType::Integer | ColumnType::Real | ColumnType::Numeric)
+    }
+}
+
+//画面表示用のカラム情報
+#[derive(Debug,Clone,Serialize)]
+pub struct ColumnInfo{
+    pub name:String,                //カラム名(大文字)
+    pub column_type:ColumnType,     //カラムの型
+    pub station:String,             //所属ステーション(LD,DC1,...,ULD 該当なしはCOMMON)
+    pub label:String,               //表示名
+    pub plottable:bool,             //グラフの軸に使用可能かどうか
+}
+
+//ステーションの接頭辞一覧
+const STATIONS: &[&str] = &["LD", "DC1", "AC1", "AC2", "DC2", "IP", "ULD"];
+
+//読み込み対象外のカラム一覧(カンマ区切り)
+static COLUMN_DENYLIST: Lazy<Vec<String>> = Lazy::new(|| {
+    env::var("COLUMN_DENYLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+});
+
+//カラム名(大文字)→カラム情報 のレジストリ
+//起動時にinformation_schemaから読み込む
+//セキュリティのため、このレジストリにあるカラム名のみがSQL文で使用可能です
+static COLUMN_REGISTRY: OnceCell<IndexMap<String,ColumnInfo>> = OnceCell::new();
+
+//CHIPDATAのカラム一覧と型をinformation_schemaから読み込んでレジストリに登録する
+pub async fn load_columns(pool:&PgPool)->Result<usize,Box<dyn Error>>{
+    let sql = "SELECT column_name, data_type FROM information_schema.columns
+               WHERE table_schema = current_schema() AND table_name = 'chipdata'
+               ORDER BY ordinal_position";
+    let rows = sqlx::query(sql).fetch_all(pool).await?;
+
+    let mut registry: IndexMap<String,ColumnInfo> = IndexMap::new();
+    for row in rows {
+        let name: String = row.try_get::<String, _>(0)?.to_uppercase();
+        let data_type: String = row.try_get(1)?;
+
+        //SQL文に埋め込むため英数字とアンダースコアのみのカラム名に限定する
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            warn!("Skipped column with unsupported name: {}", name);
+            continue;
+        }
+        if COLUMN_DENYLIST.contains(&name) {
+            debug!("Skipped denylisted column: {}", name);
+            continue;
+        }
+        let column_type = match ColumnType::from_pg_type(&data_type) {
+            Some(t) => t,
+            None => {
+                warn!("Skipped column with unsupported type: {} ({})", name, data_type);
+                continue;
+            }
+        };
+
+        let station = STATIONS.iter()
+            .find(|station| name.starts_with(&format!("{}_", station)))
+            .map(|station| station.to_string())
+            .unwrap_or("COMMON".to_string());
+        let label = name.replace('_', " ");
+        let plottable = column_type.is_numeric() || column_type == ColumnType::Timestamp;
+
+        registry.insert(name.clone(), ColumnInfo{name, column_type, station, label, plottable});
+    }
+
+    if registry.is_empty() {
+        return Err("No columns found for CHIPDATA in information_schema".into());
+    }
+
+    let column_count = registry.len();
+    COLUMN_REGISTRY.set(registry).map_err(|_| "Column registry is already loaded")?;
+    info!("Loaded {} CHIPDATA columns", column_count);
+
+    Ok(column_count)
+}
+
+//レジストリに登録されているカラム情報の一覧(テーブル定義順)
+pub fn column_list()->Vec<ColumnInfo>{
+    COLUMN_REGISTRY.get()
+        .map(|registry| registry.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+//カラムの型を取得する(登録されていないカラムはNone)
+pub fn column_type(column:&str)->Option<ColumnType>{
+    COLUMN_REGISTRY.get()?
+        .get(&column.to_uppercase())
+        .map(|info| info.column_type)
+}
+
+//TIMESTAMP型のカラムかどうか
+pub fn is_timestamp_column(column:&str)->bool{
+    column_type(column) == Some(ColumnType::Timestamp)
+}
+
+//SELECT句に記述するカラム式を返す
+//NUMERIC型はf64で取得できるようにdouble precisionにキャストする
+pub fn select_expr(column:&str)->String{
+    match column_type(column) {
+        Some(ColumnType::Numeric) => format!("{}::double precision AS {}", column, column),
+        _ => column.to_string(),
+    }
+}
+
+//テスト用のカラム一覧をレジストリに登録する(登録済みの場合は何もしない)
+#[cfg(test)]
+pub fn load_test_columns(){
+    COLUMN_REGISTRY.get_or_init(|| {
+        [
+            ("MACHINE_ID", ColumnType::Integer),
+            ("LOT_NAME", ColumnType::Text),
+            ("LD_PICKUP_DATE", ColumnType::Timestamp),
+            ("DC1_STAGE_Z", ColumnType::Real),
+            ("DC1_OFFSET", ColumnType::Numeric),
+            ("DC1_ALARM", ColumnType::Integer),
+        ].into_iter()
+            .map(|(name, column_type)| (name.to_string(), ColumnInfo{
+                name: name.to_string(),
+                column_type,
+                station: "COMMON".to_string(),
+                label: name.replace('_', " "),
+                plottable: column_type != ColumnType::Text,
+            }))
+            .collect()
+    });
+}
@@ -0,0 +1,220 @@
+/* 管理図(I-MR, Xbar-R, Xbar-S)のデータを作成する */
+use futures_util::TryStreamExt;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::error::Error;
+use std::{env,fs};
+use once_cell::sync::Lazy;
+use tracing::debug;
+
+use crate::graph::variants::*;
+use crate::graph::plotdata::get_number;
+use crate::graph::row_budget::stream_rows;
+
+static SPEC_JSON_PATH: Lazy<String> = Lazy::new(|| {
+    env::var("SPEC_JSON_PATH").unwrap_or("C:\\workspace\\server_backend\\assets\\spec_limits.json".to_string())
+});
+
+// 管理図係数 (群の大きさn=2..10): (A2, D3, D4, d2)
+const CONTROL_CHART_CONSTANTS: &[(f64, f64, f64, f64)] = &[
+    (1.880, 0.000, 3.267, 1.128), (1.023, 0.000, 2.574, 1.693), (0.729, 0.000, 2.282, 2.059),
+    (0.577, 0.000, 2.114, 2.326), (0.483, 0.000, 2.004, 2.534), (0.419, 0.076, 1.924, 2.704),
+    (0.373, 0.136, 1.864, 2.847), (0.337, 0.184, 1.816, 2.970), (0.308, 0.223, 1.777, 3.078),
+];
+
+// 群の大きさの平均がこれを超える場合は範囲(R)ではなく標準偏差(S)で管理図を作る
+const XBAR_R_MAX_SUBGROUP: f64 = 10.0;
+
+// 群の大きさに対応するXbar-R管理図の係数を返す(n=2..10)
+fn control_chart_constants(subgroup_size: f64) -> (f64, f64, f64, f64) {
+    let n = (subgroup_size.round() as usize).clamp(2, 10);
+    CONTROL_CHART_CONSTANTS[n - 2]
+}
+
+// 標準偏差の不偏化係数c4(n) = sqrt(2/(n-1)) * Γ(n/2) / Γ((n-1)/2)
+// Γの比はr(n+2) = r(n) * n/(n-1)で求め、nが大きい場合は近似式4(n-1)/(4n-3)を使う
+fn c4(n: f64) -> f64 {
+    let n = n.round().max(2.0);
+    if n > 100.0 {
+        return 4.0 * (n - 1.0) / (4.0 * n - 3.0);
+    }
+    let n = n as usize;
+    let (mut ratio, mut k) = if n.is_multiple_of(2) {
+        (1.0 / std::f64::consts::PI.sqrt(), 2)
+    } else {
+        (std::f64::consts::PI.sqrt() / 2.0, 3)
+    };
+    while k < n {
+        ratio *= k as f64 / (k - 1) as f64;
+        k += 2;
+    }
+    (2.0 / (n as f64 - 1.0)).sqrt() * ratio
+}
+
+// 規格値をjsonから読み込む(ファイルが無い場合は空、形式が不正な場合はエラー)
+fn load_spec_limits(spec_json_path: &str) -> Result<HashMap<String, SpecLimit>, Box<dyn Error>> {
+    let s = match fs::read_to_string(spec_json_path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("Spec limit JSON not found: {}", spec_json_path);
+            return Ok(HashMap::new());
+        },
+        Err(e) => return Err(e.into()),
+    };
+    let spec_limits: HashMap<String, SpecLimit> = serde_json::from_str(&s)
+        .map_err(|e| format!("Invalid spec limit JSON {}: {}", spec_json_path, e))?;
+    debug!("Loaded spec limits from JSON: {:?}", spec_limits);
+    Ok(spec_limits.into_iter().map(|(column, spec)| (column.to_uppercase(), spec)).collect())
+}
+
+//管理図のデータを取得
+//control_subgroupがChipの場合はI-MR管理図、それ以外(Lot/Hour/Shift/Day/Week)の場合は
+//群の大きさの平均がXBAR_R_MAX_SUBGROUP以下ならXbar-R管理図、超える場合はXbar-S管理図
+pub async fn plot_control_chart(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<ControlChartInfo,Box<dyn Error>>{
+    let mut rows_data = stream_rows(pool, sql, params);
+
+    let rows = data_map.entry("data".to_string()).or_insert(vec![]);
+    let is_individual = graph_condition.control_subgroup == "Chip";
+
+    //群ごとの値と範囲を取得
+    while let Some(row) = rows_data.try_next().await? {
+        if is_individual {
+            let x_data: Option<chrono::NaiveDateTime> = row.try_get(0).ok().flatten();
+            let value = match get_number(&row, 1) {
+                Some(v) => v,
+                None => continue,
+            };
+            // 移動範囲は直前の値との差の絶対値
+            let range = rows.last().and_then(|last| match last {
+                PlotData::ControlChart(prev) => Some((value - prev.value).abs()),
+                _ => None,
+            });
+            rows.push(PlotData::ControlChart(ControlChartData{
+                label: x_data.map(|d| d.to_string()).unwrap_or_default(),
+                x_data, value, range, std: None, count: 1,
+                out_of_control: false, range_out_of_control: false,
+            }));
+        } else {
+            let label: String = row.try_get::<Option<String>, _>(0).ok().flatten().unwrap_or_default();
+            let x_data: Option<chrono::NaiveDateTime> = row.try_get(1).ok().flatten();
+            let (value, range) = match (get_number(&row, 2), get_number(&row, 3)) {
+                (Some(v), Some(r)) => (v, r),
+                _ => continue,
+            };
+            let count: i64 = row.try_get(4).unwrap_or(0);
+            let std = get_number(&row, 5);
+            rows.push(PlotData::ControlChart(ControlChartData{
+                label, x_data, value, range: Some(range), std, count,
+                out_of_control: false, range_out_of_control: false,
+            }));
+        }
+    }
+
+    let points: Vec<&ControlChartData> = rows.iter()
+        .filter_map(|data| match data { PlotData::ControlChart(point) => Some(point), _ => None })
+        .collect();
+    if points.is_empty() {
+        return Err("No data for control chart".into());
+    }
+
+    //中心線を計算
+    let center_line = points.iter().map(|p| p.value).sum::<f64>() / points.len() as f64;
+
+    //I-MRは移動範囲(n=2)、Xbar-R/Xbar-Sは群の大きさの平均で係数を決める
+    let subgroup_size = if is_individual {
+        1.0
+    } else {
+        points.iter().map(|p| p.count as f64).sum::<f64>() / points.len() as f64
+    };
+    let is_xbar_s = !is_individual && subgroup_size > XBAR_R_MAX_SUBGROUP;
+
+    let (ucl, lcl, range_center_line, range_ucl, range_lcl, sigma) = if is_xbar_s {
+        //群内の標準偏差をプールし、c4で不偏化してσを推定する
+        let (sum_sq, dof) = points.iter()
+            .filter(|p| p.count > 1)
+            .filter_map(|p| p.std.map(|s| (s * s * (p.count - 1) as f64, (p.count - 1) as f64)))
+            .fold((0.0, 0.0), |(sum_sq, dof), (sq, d)| (sum_sq + sq, dof + d));
+        let sigma = if dof > 0.0 { (sum_sq / dof).sqrt() / c4(dof + 1.0) } else { 0.0 };
+        //管理限界は群の大きさの平均nで計算する: Xbar ± 3σ/√n, S: c4σ ± 3σ√(1-c4²)
+        let c4_n = c4(subgroup_size);
+        let x_width = 3.0 * sigma / subgroup_size.sqrt();
+        let s_width = 3.0 * sigma * (1.0 - c4_n * c4_n).sqrt();
+        (
+            center_line + x_width, center_line - x_width,
+            c4_n * sigma, c4_n * sigma + s_width, (c4_n * sigma - s_width).max(0.0),
+            sigma,
+        )
+    } else {
+        let ranges: Vec<f64> = points.iter().filter_map(|p| p.range).collect();
+        let range_center_line = if ranges.is_empty() { 0.0 } else { ranges.iter().sum::<f64>() / ranges.len() as f64 };
+        let (a2, d3, d4, d2) = control_chart_constants(if is_individual { 2.0 } else { subgroup_size });
+        let sigma = range_center_line / d2;
+        let (ucl, lcl) = if is_individual {
+            (center_line + 3.0 * sigma, center_line - 3.0 * sigma)
+        } else {
+            (center_line + a2 * range_center_line, center_line - a2 * range_center_line)
+        };
+        (ucl, lcl, range_center_line, d4 * range_center_line, d3 * range_center_line, sigma)
+    };
+    debug!("Control chart: CL={}, UCL={}, LCL={}, sigma={}", center_line, ucl, lcl, sigma);
+
+    //管理限界外の点に印をつける
+    for data in rows.iter_mut() {
+        if let PlotData::ControlChart(point) = data {
+            point.out_of_control = point.value > ucl || point.value < lcl;
+            //Xbar-Sは群の標準偏差、それ以外は範囲を判定する
+            let spread = if is_xbar_s { point.std } else { point.range };
+            point.range_out_of_control = spread.map(|r| r > range_ucl || r < range_lcl).unwrap_or(false);
+        }
+    }
+
+    //工程能力指数を計算
+    let spec_limit = load_spec_limits(&SPEC_JSON_PATH)?.remove(&graph_condition.graph_y_item.to_uppercase());
+    let lsl = spec_limit.as_ref().and_then(|s| s.lsl);
+    let usl = spec_limit.as_ref().and_then(|s| s.usl);
+    let target = spec_limit.as_ref().and_then(|s| s.target);
+    let (cp, cpk) = if sigma > 0.0 {
+        let cp = match (lsl, usl) {
+            (Some(l), Some(u)) => Some((u - l) / (6.0 * sigma)),
+            _ => None,
+        };
+        let cpu = usl.map(|u| (u - center_line) / (3.0 * sigma));
+        let cpl = lsl.map(|l| (center_line - l) / (3.0 * sigma));
+        let cpk = match (cpu, cpl) {
+            (Some(u), Some(l)) => Some(u.min(l)),
+            (u, l) => u.or(l),
+        };
+        (cp, cpk)
+    } else {
+        (None, None)
+    };
+
+    Ok(ControlChartInfo{
+        chart_type: if is_individual { "I-MR" } else if is_xbar_s { "Xbar-S" } else { "Xbar-R" }.to_string(),
+        subgroup_size, center_line, ucl, lcl,
+        range_center_line, range_ucl, range_lcl, sigma,
+        lsl, usl, target, cp, cpk,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c4_matches_table_and_approximation() {
+        for (n, expected) in [(2.0, 0.7979), (3.0, 0.8862), (5.0, 0.9400), (10.0, 0.9727), (25.0, 0.9896)] {
+            assert!((c4(n) - expected).abs() < 1e-4, "c4({}) = {}", n, c4(n));
+        }
+        //近似式を使う範囲(n>100)
+        assert!((c4(101.0) - 0.997503).abs() < 1e-5);
+        assert!(c4(1_000_000.0) < 1.0 && c4(1_000_000.0) > 0.9999);
+    }
+
+    #[test]
+    fn xbar_r_constants_are_clamped() {
+        assert_eq!(control_chart_constants(1.0), CONTROL_CHART_CONSTANTS[0]);
+        assert_eq!(control_chart_constants(5.4), CONTROL_CHART_CONSTANTS[3]);
+        assert_eq!(control_chart_constants(500.0), CONTROL_CHART_CONSTANTS[8]);
+    }
+}
@@ -0,0 +1,73 @@
+/* 相関行列のデータを取得する */
+use sqlx::{PgPool, Row};
+use std::error::Error;
+
+use crate::graph::variants::*;
+use crate::graph::sql::create_correlation_sql;
+use crate::graph::plotdata::get_number;
+
+//相関行列をヒートマップ用のセル一覧として取得する
+//対角成分と対称な成分も含めた全セルを返す
+pub async fn get_correlation_from_db(pool:&PgPool,condition:&CorrelationCondition)->Result<(Vec<String>,Vec<CorrelationCell>),Box<dyn Error>>{
+    let (sql, params) = create_correlation_sql(condition)
+        .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+
+    let mut query = sqlx::query(&sql);
+    for param in &params {
+        query = query.bind(param);
+    }
+    let row = query.fetch_one(pool).await?;
+
+    let columns: Vec<String> = condition.columns.iter().map(|c| c.to_uppercase()).collect();
+    let n = columns.len();
+
+    //対角成分: 件数は各カラムの値がある件数
+    let mut cells = Vec::with_capacity(n * n);
+    for (i, column) in columns.iter().enumerate() {
+        let count: i64 = row.try_get(i)?;
+        let has_variance: Option<bool> = row.try_get(n + i)?;
+        cells.push(CorrelationCell{
+            x_data: i, y_data: i,
+            x_item: column.clone(), y_item: column.clone(),
+            z_data: diagonal_value(count, has_variance),
+            count,
+        });
+    }
+
+    //ペアごとの相関係数と件数(SQLの列はi<jの順に並んでいる)
+    let mut index = 2 * n;
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let z_data = get_number(&row, index);
+            let count: i64 = row.try_get(index + 1)?;
+            index += 2;
+            for (x, y) in [(i, j), (j, i)] {
+                cells.push(CorrelationCell{
+                    x_data: x, y_data: y,
+                    x_item: columns[x].clone(), y_item: columns[y].clone(),
+                    z_data, count,
+                });
+            }
+        }
+    }
+
+    Ok((columns, cells))
+}
+
+//対角成分の相関係数(件数が2未満 or ばらつきが無い場合は、ペアの相関係数と同じくNone)
+fn diagonal_value(count:i64,has_variance:Option<bool>)->Option<f64>{
+    if count >= 2 && has_variance == Some(true) { Some(1.0) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagonal_is_null_without_variance() {
+        assert_eq!(diagonal_value(10, Some(true)), Some(1.0));
+        assert_eq!(diagonal_value(10, Some(false)), None);
+        assert_eq!(diagonal_value(1, Some(true)), None);
+        assert_eq!(diagonal_value(0, None), None);
+    }
+}
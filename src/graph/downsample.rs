@@ -0,0 +1,239 @@
+/* 時系列プロットの間引き処理 */
+use std::collections::HashMap;
+
+use crate::graph::variants::*;
+use crate::graph::overlay::OverlaySeries;
+
+//折れ線グラフの各系列をmax_points点以下に間引く
+//アラーム点は必ず残す(アラーム点だけでmax_pointsを超える場合はアラーム点を全て返す)
+//計算系列は元の系列と同じ点を残す
+//戻り値: 系列名→間引き前後の点数
+pub fn downsample_line_series(data_map:&mut HashMap<String,Vec<PlotData>>,overlays:&mut [OverlaySeries],max_points:usize,method:&str)->Result<HashMap<String,DownsampleInfo>,String>{
+    if max_points < 3 {
+        return Err(format!("max_points must be 3 or more: {}", max_points));
+    }
+    if method != "LTTB" && method != "MinMax" {
+        return Err(format!("Invalid downsample method: {}", method));
+    }
+
+    let mut downsample_info = HashMap::new();
+    for (series_name, rows) in data_map.iter_mut() {
+        let original_count = rows.len();
+        if original_count > max_points {
+            //x軸は時刻(秒)、時刻が無い点は並び順で代用する
+            let (xs, ys): (Vec<f64>, Vec<f64>) = rows.iter().enumerate().map(|(index, data)| match data {
+                PlotData::Line(line) => (
+                    line.x_data.map(|d| d.and_utc().timestamp_millis() as f64 / 1000.0).unwrap_or(index as f64),
+                    line.y_data.unwrap_or(0.0),
+                ),
+                _ => (index as f64, 0.0),
+            }).unzip();
+            let alarm_indices: Vec<usize> = rows.iter().enumerate()
+                .filter(|(_, data)| matches!(data, PlotData::Line(line) if line.is_alarm))
+                .map(|(index, _)| index)
+                .collect();
+
+            let keep = select_indices(&xs, &ys, &alarm_indices, max_points, method);
+            retain_indices(rows, &keep);
+            for overlay in overlays.iter_mut().filter(|overlay| &overlay.base == series_name) {
+                retain_indices(&mut overlay.rows, &keep);
+            }
+        }
+
+        downsample_info.insert(series_name.clone(), DownsampleInfo{
+            original_count,
+            returned_count: rows.len(),
+        });
+    }
+
+    Ok(downsample_info)
+}
+
+//昇順のインデックスの点のみを残す
+fn retain_indices(rows:&mut Vec<PlotData>,keep:&[usize]){
+    let mut keep_iter = keep.iter().peekable();
+    let mut index = 0;
+    rows.retain(|_| {
+        let is_kept = keep_iter.peek() == Some(&&index);
+        if is_kept {
+            keep_iter.next();
+        }
+        index += 1;
+        is_kept
+    });
+}
+
+//残す点のインデックスを昇順で返す
+fn select_indices(xs:&[f64],ys:&[f64],alarm_indices:&[usize],max_points:usize,method:&str)->Vec<usize>{
+    //アラーム点の分だけ通常の間引き後の点数を減らす
+    let threshold = max_points.saturating_sub(alarm_indices.len());
+    let mut keep = if threshold < 3 {
+        vec![]
+    } else if method == "MinMax" {
+        min_max_indices(ys, threshold)
+    } else {
+        lttb_indices(xs, ys, threshold)
+    };
+
+    keep.extend_from_slice(alarm_indices);
+    keep.sort_unstable();
+    keep.dedup();
+    keep
+}
+
+//LTTB(Largest-Triangle-Three-Buckets)で残す点のインデックスを返す
+//最初と最後の点は必ず残し、間を(threshold-2)個のバケットに分けて各バケットから面積最大の点を選ぶ
+fn lttb_indices(xs:&[f64],ys:&[f64],threshold:usize)->Vec<usize>{
+    let len = ys.len();
+    if threshold >= len {
+        return (0..len).collect();
+    }
+
+    let bucket_size = (len - 2) as f64 / (threshold - 2) as f64;
+    let mut indices = Vec::with_capacity(threshold);
+    let mut a = 0;
+    indices.push(a);
+
+    for i in 0..threshold - 2 {
+        //次のバケットの平均点
+        let next_start = ((i + 1) as f64 * bucket_size) as usize + 1;
+        let next_end = (((i + 2) as f64 * bucket_size) as usize + 1).min(len);
+        let next_len = (next_end - next_start).max(1) as f64;
+        let avg_x = xs[next_start..next_end].iter().sum::<f64>() / next_len;
+        let avg_y = ys[next_start..next_end].iter().sum::<f64>() / next_len;
+
+        //現在のバケットから三角形の面積が最大の点を選ぶ
+        let start = (i as f64 * bucket_size) as usize + 1;
+        let end = (((i + 1) as f64 * bucket_size) as usize + 1).min(len - 1);
+        let (ax, ay) = (xs[a], ys[a]);
+        let mut max_area = -1.0;
+        let mut max_index = start;
+        for j in start..end {
+            let area = ((ax - avg_x) * (ys[j] - ay) - (ax - xs[j]) * (avg_y - ay)).abs();
+            if area > max_area {
+                max_area = area;
+                max_index = j;
+            }
+        }
+        indices.push(max_index);
+        a = max_index;
+    }
+
+    indices.push(len - 1);
+    indices
+}
+
+//バケットごとに最小値と最大値の点を残す
+//最初と最後の点は必ず残す
+fn min_max_indices(ys:&[f64],threshold:usize)->Vec<usize>{
+    let len = ys.len();
+    if threshold >= len {
+        return (0..len).collect();
+    }
+
+    let bucket_count = ((threshold - 2) / 2).max(1);
+    let bucket_size = (len - 2) as f64 / bucket_count as f64;
+    let mut indices = Vec::with_capacity(threshold);
+    indices.push(0);
+
+    for i in 0..bucket_count {
+        let start = (i as f64 * bucket_size) as usize + 1;
+        let end = (((i + 1) as f64 * bucket_size) as usize + 1).min(len - 1);
+        if start >= end {
+            continue;
+        }
+        let mut min_index = start;
+        let mut max_index = start;
+        for j in start..end {
+            if ys[j] < ys[min_index] { min_index = j; }
+            if ys[j] > ys[max_index] { max_index = j; }
+        }
+        indices.push(min_index.min(max_index));
+        if min_index != max_index {
+            indices.push(min_index.max(max_index));
+        }
+    }
+
+    indices.push(len - 1);
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sorted_unique(indices: &[usize]) -> bool {
+        indices.windows(2).all(|w| w[0] < w[1])
+    }
+
+    #[test]
+    fn empty_and_short_series_are_kept() {
+        assert!(lttb_indices(&[], &[], 3).is_empty());
+        assert!(min_max_indices(&[], 3).is_empty());
+        assert_eq!(lttb_indices(&[0.0, 1.0], &[5.0, 6.0], 3), vec![0, 1]);
+        assert_eq!(min_max_indices(&[5.0, 6.0, 7.0], 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn lttb_keeps_ends_and_spike() {
+        let xs: Vec<f64> = (0..1000).map(|i| i as f64).collect();
+        let mut ys = vec![0.0; 1000];
+        ys[500] = 100.0;
+        let indices = lttb_indices(&xs, &ys, 50);
+        assert_eq!(indices.len(), 50);
+        assert_eq!(indices.first(), Some(&0));
+        assert_eq!(indices.last(), Some(&999));
+        assert!(indices.contains(&500));
+        assert!(is_sorted_unique(&indices));
+    }
+
+    #[test]
+    fn min_max_keeps_extremes_within_threshold() {
+        let ys: Vec<f64> = (0..1000).map(|i| ((i * 37) % 101) as f64).collect();
+        let indices = min_max_indices(&ys, 20);
+        assert!(indices.len() <= 20);
+        assert_eq!(indices.first(), Some(&0));
+        assert_eq!(indices.last(), Some(&999));
+        assert!(is_sorted_unique(&indices));
+        let max_y = ys[1..999].iter().cloned().fold(f64::MIN, f64::max);
+        assert!(indices.iter().any(|&i| ys[i] == max_y));
+    }
+
+    #[test]
+    fn alarm_points_are_always_kept() {
+        let xs: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        let ys: Vec<f64> = (0..100).map(|i| (i % 7) as f64).collect();
+        let alarms = vec![3, 40, 41, 97];
+        for method in ["LTTB", "MinMax"] {
+            let indices = select_indices(&xs, &ys, &alarms, 10, method);
+            assert!(indices.len() <= 10);
+            assert!(alarms.iter().all(|a| indices.contains(a)));
+            assert!(is_sorted_unique(&indices));
+        }
+        //アラーム点だけでmax_pointsを超える場合はアラーム点のみ返す
+        let many_alarms: Vec<usize> = (0..100).step_by(2).collect();
+        assert_eq!(select_indices(&xs, &ys, &many_alarms, 10, "LTTB"), many_alarms);
+    }
+
+    #[test]
+    fn nan_values_do_not_panic() {
+        let xs: Vec<f64> = (0..200).map(|i| i as f64).collect();
+        let ys: Vec<f64> = (0..200).map(|i| if i % 3 == 0 { f64::NAN } else { i as f64 }).collect();
+        let lttb = lttb_indices(&xs, &ys, 20);
+        assert_eq!(lttb.len(), 20);
+        assert!(is_sorted_unique(&lttb));
+        let min_max = min_max_indices(&ys, 20);
+        assert!(min_max.len() <= 20);
+        assert!(is_sorted_unique(&min_max));
+    }
+
+    #[test]
+    fn retain_keeps_only_selected_rows() {
+        let mut rows: Vec<PlotData> = (0..5).map(|i| PlotData::Line(LinePlotData{
+            x_data: None, y_data: Some(i as f64), is_alarm: false,
+        })).collect();
+        retain_indices(&mut rows, &[0, 3, 4]);
+        let ys: Vec<Option<f64>> = rows.iter().map(|r| match r { PlotData::Line(l) => l.y_data, _ => None }).collect();
+        assert_eq!(ys, vec![Some(0.0), Some(3.0), Some(4.0)]);
+    }
+}
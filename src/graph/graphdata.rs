@@ -1,122 +1,225 @@
 /* グラフ描画用のデータを取得するクレート */
-use sqlx::{PgPool, Row,Column};
+use sqlx::PgPool;
 use std::error::Error;
 use std::collections::HashMap;
+use std::env;
 use std::time::Instant;
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
 use tracing::{debug, info};
 
 //独自クレートのimport
 use crate::graph::variants::*;
-use crate::graph::sql::{create_alarm_sql,create_sql};
+use crate::graph::sql::{create_alarm_sql,create_sql,create_rollup_line_sql,create_rollup_statistics_sql,is_rollup_line_condition};
 use crate::graph::alarm_plotdata::*;
 use crate::graph::plotdata::*;
+use crate::graph::control_chart::plot_control_chart;
+use crate::graph::downsample::downsample_line_series;
+use crate::graph::overlay::create_line_overlays;
+use crate::graph::statistics::{get_statistics,query_statistics};
+use crate::graph::row_budget::{check_row_estimate, limit_sql};
+use crate::rollup::rollup_range;
+
+//グラフのクエリ(通常データ・アラーム・記述統計量)をサーバー全体で同時に実行する数の上限
+//全リクエストで共有し、同時に多数のグラフを要求されても接続プールを使い切らないようにする
+static GRAPH_QUERY_SEMAPHORE: Lazy<Semaphore> = Lazy::new(|| {
+    let concurrency = env::var("GRAPH_QUERY_CONCURRENCY").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(8);
+    Semaphore::new(concurrency)
+});
+
+//通常データとアラームで共通のビン・グリッド(先に決めてから両方を同時に集計する)
+#[derive(Default)]
+struct SharedBins{
+    bin_edges:Vec<f64>,             //ヒストグラムのビンの境界値
+    density_grid:Option<GridData>,  //密度プロットのグリッド(データが無い場合はNone)
+}
 
 //DBからデータを取得してHighChartで使用可能なデータに成形する
-pub async fn get_graphdata_from_db(pool:&PgPool,graph_condition:&GraphCondition)->Result<(HashMap<String,Vec<PlotData>>,GridData),Box<dyn Error>>{
+//戻り値: (系列名→プロットデータ, グリッド情報, 系列名→記述統計量)
+pub async fn get_graphdata_from_db(pool:&PgPool,graph_condition:&GraphCondition)->Result<(HashMap<String,Vec<PlotData>>,GridData,HashMap<String,SeriesStatistics>),Box<dyn Error>>{
     //sql文を作成（パラメータ化）
-    let (mut sql, params) = create_sql(&graph_condition)
+    let (mut sql, params) = create_sql(graph_condition)
         .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
 
-    // LinePlotの場合はUNION ALL全体に対してORDER BYを追加
-    if graph_condition.graph_type == "LinePlot" {
+    // LinePlotの場合はUNION ALL全体に対してORDER BYを追加(集計する場合はcreate_sqlで追加済)
+    let is_line_aggregate = graph_condition.graph_type == "LinePlot" && graph_condition.line_bucket != "None";
+    if graph_condition.graph_type == "LinePlot" && !is_line_aggregate {
         sql += " ORDER BY LD_PICKUP_DATE ASC";
     }
 
     debug!("Generated SQL: {}", sql);
 
-    // --- 件数を先に取得 ---
-    let count_sql = format!(
-        "SELECT COUNT(*) FROM ({}) AS subquery",
-        sql
-    );
-    debug!("Count SQL: {}", count_sql);
-
-    // パラメータをバインド
-    let mut count_query = sqlx::query_scalar(&count_sql);
-    for param in &params {
-        count_query = count_query.bind(param);
+    //時間単位で集計する折れ線グラフは、期間全体が集計テーブル(ロールアップ)で答えられる場合はそちらから取得する
+    if is_line_aggregate && let Some(result) = get_line_aggregate_from_rollup(pool, graph_condition).await? {
+        return Ok(result);
     }
 
-    let total_count: i64 = count_query.fetch_one(pool).await?;
-    debug!("Total count: {}", total_count);
+    // 生データを取得するグラフは行数の上限を超えないようにLIMITを付ける(集計するグラフはSQL側で件数が小さくなるため不要)
+    let is_raw_rows = match graph_condition.graph_type.as_str() {
+        "ScatterPlot" => true,
+        "LinePlot" => !is_line_aggregate,
+        "ControlChart" => graph_condition.control_subgroup == "Chip",
+        _ => false,
+    };
+    if is_raw_rows {
+        check_row_estimate(pool, &sql, &params).await?;
+        sql = limit_sql(&sql);
+    }
 
-    //ここにHighChartsで表示用のデータを全て入れる
-    let mut data_map:HashMap<String,Vec<PlotData>>=HashMap::new();
-    let mut grid_data=GridData{grid_x:0.,grid_y:0.,x_min:0,y_min:0,histogram_bin_info:None};
+    //ヒストグラム・密度プロットはアラームのチップだけを別のクエリで集計する(散布図は通常データのアラームカラムで分ける)
+    let is_alarm_overlay = !graph_condition.alarm.codes.is_empty() && (graph_condition.graph_type=="Histogram" || graph_condition.graph_type=="DensityPlot");
+
+    //通常データ・アラーム・記述統計量のクエリを同時に実行する
+    //いずれかが失敗した場合は残りのクエリも中断する(try_join!で未完了のfutureがdropされ、取得中のクエリも打ち切られる)
+    let plot_future = async {
+        //ヒストグラム・密度プロットはビン・グリッドを先に決めて、通常データとアラームを同時に集計する
+        let bins = {
+            let _permit = GRAPH_QUERY_SEMAPHORE.acquire().await?;
+            shared_bins(pool, graph_condition, &sql, &params).await?
+        };
+        let main_future = async {
+            let _permit = GRAPH_QUERY_SEMAPHORE.acquire().await?;
+            plot_main_data(pool, graph_condition, &sql, &params, is_line_aggregate, &bins).await
+        };
+        let alarm_future = async {
+            if !is_alarm_overlay {
+                return Ok(None);
+            }
+            let _permit = GRAPH_QUERY_SEMAPHORE.acquire().await?;
+            plot_alarm_data(pool, graph_condition, &bins).await
+        };
+        let ((mut data_map, grid_data), alarm_counts) = tokio::try_join!(main_future, alarm_future)?;
+        //密度プロットのRatioは通常データの個数を使うため、通常データを格納した後にアラームを追加する
+        if let Some(alarm_counts) = alarm_counts {
+            push_alarm_counts(&mut data_map, alarm_counts, &grid_data, graph_condition);
+        }
+        Ok::<_, Box<dyn Error>>((data_map, grid_data))
+    };
+    //系列ごとの記述統計量(with_statisticsを指定した場合のみ)
+    let statistics_future = async {
+        if !graph_condition.with_statistics {
+            return Ok(HashMap::new());
+        }
+        let _permit = GRAPH_QUERY_SEMAPHORE.acquire().await?;
+        get_statistics(pool, graph_condition).await
+    };
 
     let start=Instant::now();
+    let ((data_map, grid_data), statistics) = tokio::try_join!(plot_future, statistics_future)?;
+    info!("Graph data processing time: {:?}", start.elapsed());
+
+    Ok((data_map,grid_data,statistics))
+
+}
+
+//集計する折れ線グラフと記述統計量をロールアップテーブルから取得する(答えられない条件・期間の場合はNone)
+//記述統計量のパーセンタイルはロールアップに無いためNoneになる(grid_data.from_rollupで区別できるようにする)
+async fn get_line_aggregate_from_rollup(pool:&PgPool,graph_condition:&GraphCondition)->Result<Option<(HashMap<String,Vec<PlotData>>,GridData,HashMap<String,SeriesStatistics>)>,Box<dyn Error>>{
+    if !is_rollup_line_condition(graph_condition) {
+        return Ok(None);
+    }
+    let (start_hour, end_hour) = match rollup_range(pool, &graph_condition.start_date, &graph_condition.end_date, Some(&graph_condition.graph_y_item)).await? {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+
+    let start=Instant::now();
+    let (sql, params) = create_rollup_line_sql(graph_condition, &start_hour, &end_hour)
+        .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+    let mut data_map:HashMap<String,Vec<PlotData>>=HashMap::new();
+    plot_lineplot_aggregate(&mut data_map, pool, &sql, &params).await?;
+    let statistics = if graph_condition.with_statistics {
+        let (statistics_sql, statistics_params) = create_rollup_statistics_sql(graph_condition, &start_hour, &end_hour)
+            .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+        query_statistics(pool, &statistics_sql, &statistics_params).await?
+    } else {
+        HashMap::new()
+    };
+    info!("Graph data from rollup ({} - {}), processing time: {:?}", start_hour, end_hour, start.elapsed());
+
+    let grid_data = GridData{from_rollup: true, ..GridData::default()};
+    Ok(Some((data_map, grid_data, statistics)))
+}
+
+//ヒストグラムのビン・密度プロットのグリッドを決める(それ以外のグラフは空)
+async fn shared_bins(pool:&PgPool,graph_condition:&GraphCondition,sql:&str,params:&[String])->Result<SharedBins,Box<dyn Error>>{
+    Ok(match graph_condition.graph_type.as_str() {
+        "Histogram" => SharedBins{bin_edges: histogram_bin_edges(pool, sql, params, graph_condition).await?, ..Default::default()},
+        "DensityPlot" => SharedBins{density_grid: densityplot_grid(pool, sql, params, graph_condition).await?, ..Default::default()},
+        _ => SharedBins::default(),
+    })
+}
+
+//通常データを取得してグラフ種類ごとにdata_mapに格納する
+async fn plot_main_data(pool:&PgPool,graph_condition:&GraphCondition,sql:&str,params:&[String],is_line_aggregate:bool,bins:&SharedBins)->Result<(HashMap<String,Vec<PlotData>>,GridData),Box<dyn Error>>{
+    //ここにHighChartsで表示用のデータを全て入れる
+    let mut data_map:HashMap<String,Vec<PlotData>>=HashMap::new();
+    let mut grid_data=GridData::default();
 
     //グラフ種類ごとにデータを格納
     match graph_condition.plot_unit.as_str() {
         "None" => match graph_condition.graph_type.as_str() {
-            "ScatterPlot" => plot_scatterplot_without_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?,
-            "LinePlot" => plot_lineplot_without_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?,
+            "ScatterPlot" => plot_scatterplot_without_unit(&mut data_map, pool, sql, params, graph_condition).await?,
+            "LinePlot" if is_line_aggregate => plot_lineplot_aggregate(&mut data_map, pool, sql, params).await?,
+            "LinePlot" => plot_lineplot_without_unit(&mut data_map, pool, sql, params, graph_condition).await?,
             "Histogram" => {
-                grid_data.histogram_bin_info=Some(plot_histogram_without_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?);
+                grid_data.histogram_bin_info=Some(plot_histogram(&mut data_map, pool, sql, params, graph_condition, &bins.bin_edges).await?);
             }
             "DensityPlot" => {
-                grid_data = plot_densityplot_without_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?;
+                grid_data = plot_densityplot(&mut data_map, pool, sql, params, graph_condition, bins.density_grid.as_ref()).await?;
+            },
+            "ControlChart" => {
+                grid_data.control_chart_info=Some(plot_control_chart(&mut data_map, pool, sql, params, graph_condition).await?);
             },
-            _ => {},
+            "BoxPlot" => plot_boxplot(&mut data_map, pool, sql, params).await?,
+            _ => return Err(format!("Unsupported graph type: {} (plot_unit: None)", graph_condition.graph_type).into()),
         },
         _ => match graph_condition.graph_type.as_str() {
-            "ScatterPlot" => plot_scatterplot_with_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?,
-            "LinePlot" => plot_lineplot_with_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?,
+            "ScatterPlot" => plot_scatterplot_with_unit(&mut data_map, pool, sql, params, graph_condition).await?,
+            "LinePlot" if is_line_aggregate => plot_lineplot_aggregate(&mut data_map, pool, sql, params).await?,
+            "LinePlot" => plot_lineplot_with_unit(&mut data_map, pool, sql, params, graph_condition).await?,
             "Histogram" => {
-                grid_data.histogram_bin_info=Some(plot_histogram_with_unit(total_count, &mut data_map, pool, &sql, &params, &graph_condition).await?);
+                grid_data.histogram_bin_info=Some(plot_histogram(&mut data_map, pool, sql, params, graph_condition, &bins.bin_edges).await?);
             }
-            _ => {},
+            "DensityPlot" => {
+                grid_data = plot_densityplot(&mut data_map, pool, sql, params, graph_condition, bins.density_grid.as_ref()).await?;
+            },
+            "BoxPlot" => plot_boxplot(&mut data_map, pool, sql, params).await?,
+            _ => return Err(format!("Unsupported graph type: {} (plot_unit: {})", graph_condition.graph_type, graph_condition.plot_unit).into()),
         },
     };
 
-    let duration=start.elapsed();
-    info!("Graph data processing time: {:?}", duration);
-
-    //アラームのプロットを重ねる場合の処理を入れる
-    if !graph_condition.alarm.codes.is_empty() && graph_condition.graph_type!="LinePlot" {
-
-        //アラームデータ取得用のSQL文を生成（パラメータ化）
-        let (mut alarm_sql, alarm_params) = create_alarm_sql(&graph_condition)
+    //折れ線グラフは計算系列を作成してから、系列ごとにmax_points点まで間引く
+    if graph_condition.graph_type == "LinePlot" && !is_line_aggregate {
+        let mut overlays = create_line_overlays(&data_map, &graph_condition.line_overlays)
             .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
-
-        // LinePlotの場合はORDER BYを追加
-        if graph_condition.graph_type == "LinePlot" {
-            alarm_sql += " ORDER BY LD_PICKUP_DATE ASC";
+        if let Some(max_points) = graph_condition.max_points {
+            let downsample_info = downsample_line_series(&mut data_map, &mut overlays, max_points, &graph_condition.downsample_method)
+                .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+            debug!("Downsample info: {:?}", downsample_info);
+            grid_data.downsample_info = Some(downsample_info);
         }
-
-        // --- 件数を先に取得 ---
-        let count_sql = format!(
-            "SELECT COUNT(*) FROM ({}) AS subquery",
-            alarm_sql
-        );
-
-        let mut alarm_count_query = sqlx::query_scalar(&count_sql);
-        for param in &alarm_params {
-            alarm_count_query = alarm_count_query.bind(param);
+        for overlay in overlays {
+            data_map.insert(overlay.name, overlay.rows);
         }
-        let total_count: i64 = alarm_count_query.fetch_one(pool).await?;
-
-        //アラーム分のデータをdata_mapに追加する
-        match graph_condition.plot_unit.as_str() {
-            "None" => match graph_condition.graph_type.as_str() { //ユニット毎にデータをまとめない
-                "Histogram" => {
-                    if let Some(ref bin_info) = grid_data.histogram_bin_info {
-                        plot_histogram_without_unit_only_alarm_data(total_count, &mut data_map, pool, &alarm_sql, &alarm_params, bin_info).await?;
-                    }
-                },
-                _ => {},
-            },
-            _ => match graph_condition.graph_type.as_str() { //ユニット毎にデータをまとめる
-                "Histogram" => {
-                    if let Some(ref bin_info) = grid_data.histogram_bin_info {
-                        plot_histogram_with_unit_only_alarm_data(total_count, &mut data_map, pool, &alarm_sql, &alarm_params, bin_info).await?;
-                    }
-                },
-                _ => {},
-            },
-        };
     }
 
     Ok((data_map,grid_data))
+}
+
+//アラームのプロットを重ねる場合の処理
+//ヒストグラム・密度プロットは通常データと同じビン・グリッド(bins)で集計する
+async fn plot_alarm_data(pool:&PgPool,graph_condition:&GraphCondition,bins:&SharedBins)->Result<Option<AlarmCounts>,Box<dyn Error>>{
+    //アラームデータ取得用のSQL文を生成（パラメータ化）
+    let (alarm_sql, alarm_params) = create_alarm_sql(graph_condition)
+        .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+
+    //アラーム分のデータを集計する(ユニット分割の有無はSQLのunit_nameで区別される)
+    let alarm_counts = match (graph_condition.graph_type.as_str(), &bins.density_grid) {
+        ("Histogram", _) if !bins.bin_edges.is_empty() => query_histogram_only_alarm_data(pool, &alarm_sql, &alarm_params, &bins.bin_edges).await?,
+        ("DensityPlot", Some(grid_data)) => query_densityplot_only_alarm_data(pool, &alarm_sql, &alarm_params, grid_data, graph_condition).await?,
+        _ => return Ok(None),
+    };
 
+    Ok(Some(alarm_counts))
 }
@@ -0,0 +1,201 @@
+/* ヒストグラムのビン分割 */
+use crate::graph::variants::*;
+
+//ビン数の上限
+pub const MAX_BIN_NUMBER: usize = 1000;
+
+//範囲外の値の扱い
+//Exclude: 集計から除外してunderflow/overflowとして件数のみ返す
+//Clip: 両端のビンに含める
+const OUTLIER_MODES: &[&str] = &["Exclude", "Clip"];
+
+//ビン分割の設定をチェックする(データ取得前に呼ぶ)
+pub fn validate_bin_condition(graph_condition:&GraphCondition)->Result<(),String>{
+    if !OUTLIER_MODES.contains(&graph_condition.histogram_outliers.as_str()) {
+        return Err(format!("Invalid histogram_outliers: {}", graph_condition.histogram_outliers));
+    }
+
+    if !graph_condition.bin_edges.is_empty() {
+        let edges = &graph_condition.bin_edges;
+        if edges.len() < 2 || edges.len() > MAX_BIN_NUMBER + 1 {
+            return Err(format!("bin_edges must have 2 to {} values", MAX_BIN_NUMBER + 1));
+        }
+        if edges.iter().any(|e| !e.is_finite()) || edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("bin_edges must be finite and strictly increasing".to_string());
+        }
+        return Ok(());
+    }
+
+    if let Some([low, high]) = graph_condition.bin_range
+        && !(low.is_finite() && high.is_finite() && low < high) {
+        return Err(format!("Invalid bin_range: [{}, {}]", low, high));
+    }
+
+    match graph_condition.bin_rule.as_str() {
+        "Fixed" => {
+            if graph_condition.bin_number == 0 || graph_condition.bin_number as usize > MAX_BIN_NUMBER {
+                return Err(format!("bin_number must be 1 to {}: {}", MAX_BIN_NUMBER, graph_condition.bin_number));
+            }
+            Ok(())
+        },
+        "Sturges" | "Scott" | "FreedmanDiaconis" => Ok(()),
+        _ => Err(format!("Invalid bin_rule: {}", graph_condition.bin_rule)),
+    }
+}
+
+//ビン数の自動決定に使う値の要約(SQL側で集計する)
+//bin_range指定時は範囲内の値のみを集計する
+pub struct ValueSummary{
+    pub count:i64,
+    pub min:Option<f64>,
+    pub max:Option<f64>,
+    pub std:Option<f64>,    //標本標準偏差(Scottで使用)
+    pub q1:Option<f64>,     //第1四分位数(FreedmanDiaconisで使用)
+    pub q3:Option<f64>,     //第3四分位数(FreedmanDiaconisで使用)
+}
+
+//ビンの境界値を決める
+//bin_edges指定時はそのまま使用し、それ以外はbin_range(指定が無い場合はデータの最小値～最大値)をbin_ruleで決めた数で等分する
+pub fn create_bin_edges(summary:&ValueSummary,graph_condition:&GraphCondition)->Result<Vec<f64>,String>{
+    if !graph_condition.bin_edges.is_empty() {
+        return Ok(graph_condition.bin_edges.clone());
+    }
+
+    let (mut low, mut high) = match (graph_condition.bin_range, summary.min, summary.max) {
+        (Some([low, high]), _, _) => (low, high),
+        (None, Some(min), Some(max)) if summary.count > 0 => (min, max),
+        _ => return Err("No data for histogram".to_string()),
+    };
+    //全て同じ値の場合は値を中心に幅1のビンとする
+    if low == high {
+        low -= 0.5;
+        high += 0.5;
+    }
+
+    let bin_number = match graph_condition.bin_rule.as_str() {
+        "Fixed" => graph_condition.bin_number as usize,
+        rule => auto_bin_number(summary, high - low, rule),
+    }.clamp(1, MAX_BIN_NUMBER);
+
+    let bin_width = (high - low) / bin_number as f64;
+    let mut edges: Vec<f64> = (0..bin_number).map(|i| low + bin_width * i as f64).collect();
+    edges.push(high);
+    Ok(edges)
+}
+
+//ビン数を自動で決める
+//Sturges: log2(n)+1
+//Scott: 幅=3.49*σ*n^(-1/3)
+//FreedmanDiaconis: 幅=2*IQR*n^(-1/3)
+//幅が0になる場合(ばらつきが無い場合)はSturgesで決める
+fn auto_bin_number(summary:&ValueSummary,range:f64,rule:&str)->usize{
+    let n = summary.count;
+    if n < 2 {
+        return 1;
+    }
+    let sturges = (n as f64).log2().ceil() as usize + 1;
+    let width = match (rule, summary.std, summary.q1, summary.q3) {
+        ("Scott", Some(std), _, _) => 3.49 * std * (n as f64).powf(-1.0 / 3.0),
+        ("FreedmanDiaconis", _, Some(q1), Some(q3)) => 2.0 * (q3 - q1) * (n as f64).powf(-1.0 / 3.0),
+        _ => return sturges,
+    };
+    if width > 0.0 { (range / width).ceil() as usize } else { sturges }
+}
+
+//width_bucketのバケットごとの件数をビンごとの件数に変換する
+//bucket_counts[0]は下限未満、bucket_counts[n+1]は上限超過、bucket_counts[1..=n]が各ビン
+//戻り値: (ビンごとの件数, 下側の範囲外件数, 上側の範囲外件数)
+pub fn bucket_counts_to_bins(bucket_counts:&[i32],outlier_mode:&str)->(Vec<i32>,i32,i32){
+    if bucket_counts.len() < 3 {
+        return (vec![], 0, 0);
+    }
+    let bin_count = bucket_counts.len() - 2;
+    let underflow = bucket_counts[0];
+    let overflow = bucket_counts[bin_count + 1];
+    let mut bin_counts = bucket_counts[1..=bin_count].to_vec();
+    if outlier_mode == "Clip" {
+        bin_counts[0] += underflow;
+        bin_counts[bin_count - 1] += overflow;
+    }
+    (bin_counts, underflow, overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(extra: serde_json::Value) -> GraphCondition {
+        let mut value = serde_json::json!({
+            "graph_type": "Histogram", "graph_x_item": "DC1_STAGE_Z", "graph_y_item": "DC1_STAGE_Z",
+            "start_date": "2026-10-01 00:00:00", "end_date": "2026-10-02 00:00:00",
+            "bin_number": 10, "bins_x": 5, "bins_y": 5, "plot_unit": "None",
+            "alarm": {"unit": "DC1", "codes": []},
+        });
+        value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn summary(count: i64, min: f64, max: f64) -> ValueSummary {
+        ValueSummary{count, min: Some(min), max: Some(max), std: Some(1.0), q1: Some(min), q3: Some(max)}
+    }
+
+    #[test]
+    fn fixed_bins_split_range_evenly() {
+        let edges = create_bin_edges(&summary(100, 0.0, 10.0), &condition(serde_json::json!({"bin_number": 4}))).unwrap();
+        assert_eq!(edges, vec![0.0, 2.5, 5.0, 7.5, 10.0]);
+        //bin_rangeはデータの範囲より優先する
+        let edges = create_bin_edges(&summary(100, 0.0, 10.0), &condition(serde_json::json!({"bin_number": 2, "bin_range": [-4.0, 4.0]}))).unwrap();
+        assert_eq!(edges, vec![-4.0, 0.0, 4.0]);
+        //bin_edgesはそのまま使う
+        let edges = create_bin_edges(&summary(0, 0.0, 0.0), &condition(serde_json::json!({"bin_edges": [0.0, 1.0, 5.0]}))).unwrap();
+        assert_eq!(edges, vec![0.0, 1.0, 5.0]);
+    }
+
+    #[test]
+    fn constant_and_empty_data() {
+        let edges = create_bin_edges(&summary(5, 3.0, 3.0), &condition(serde_json::json!({"bin_number": 1}))).unwrap();
+        assert_eq!(edges, vec![2.5, 3.5]);
+        let empty = ValueSummary{count: 0, min: None, max: None, std: None, q1: None, q3: None};
+        assert!(create_bin_edges(&empty, &condition(serde_json::json!({}))).is_err());
+    }
+
+    #[test]
+    fn auto_bin_number_rules() {
+        let data = summary(1024, 0.0, 100.0);
+        assert_eq!(auto_bin_number(&data, 100.0, "Sturges"), 11);
+        //幅 = 3.49 * 1 * 1024^(-1/3) ≒ 0.3469
+        assert_eq!(auto_bin_number(&data, 100.0, "Scott"), 289);
+        //幅 = 2 * 100 * 1024^(-1/3) ≒ 19.84
+        assert_eq!(auto_bin_number(&data, 100.0, "FreedmanDiaconis"), 6);
+        //ばらつきが無い場合はSturges
+        let flat = ValueSummary{count: 1024, min: Some(1.0), max: Some(1.0), std: Some(0.0), q1: Some(1.0), q3: Some(1.0)};
+        assert_eq!(auto_bin_number(&flat, 1.0, "Scott"), 11);
+        assert_eq!(auto_bin_number(&summary(1, 0.0, 0.0), 1.0, "Sturges"), 1);
+        //ビン数は上限で打ち切る
+        let edges = create_bin_edges(&summary(1_000_000, 0.0, 1e6), &condition(serde_json::json!({"bin_rule": "Scott"}))).unwrap();
+        assert_eq!(edges.len(), MAX_BIN_NUMBER + 1);
+    }
+
+    #[test]
+    fn bucket_counts_exclude_and_clip() {
+        let counts = [3, 1, 2, 4, 5];
+        assert_eq!(bucket_counts_to_bins(&counts, "Exclude"), (vec![1, 2, 4], 3, 5));
+        assert_eq!(bucket_counts_to_bins(&counts, "Clip"), (vec![4, 2, 9], 3, 5));
+        assert_eq!(bucket_counts_to_bins(&[2, 7, 1], "Clip"), (vec![10], 2, 1));
+        assert_eq!(bucket_counts_to_bins(&[1, 2], "Clip"), (vec![], 0, 0));
+        assert_eq!(bucket_counts_to_bins(&[], "Exclude"), (vec![], 0, 0));
+    }
+
+    #[test]
+    fn validation_rejects_bad_conditions() {
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_number": 0}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_number": 1001}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_rule": "Rice"}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"histogram_outliers": "Drop"}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_range": [1.0, 1.0]}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_edges": [0.0]}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_edges": [0.0, 2.0, 1.0]}))).is_err());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_edges": [0.0, 1.0], "bin_number": 0}))).is_ok());
+        assert!(validate_bin_condition(&condition(serde_json::json!({"bin_rule": "Scott", "bin_number": 0}))).is_ok());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod graphdata;
 pub mod variants;
-mod sql;
+pub mod columns;
+pub mod correlation;
+pub mod sql;
 mod plotdata;
-mod alarm_plotdata;
\ No newline at end of file
+mod alarm_plotdata;
+mod control_chart;
+mod downsample;
+mod overlay;
+mod histogram;
+mod statistics;
+mod row_budget;
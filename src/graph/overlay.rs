@@ -0,0 +1,357 @@
+/* 折れ線グラフに重ねる計算系列(移動平均・移動中央値・EWMA・±kσ帯)を作成する */
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::graph::variants::*;
+
+//移動窓の最大点数
+const MAX_OVERLAY_WINDOW: usize = 1000;
+
+//計算系列(間引きの際に元の系列と同じ点を残すため、元の系列名を持つ)
+pub struct OverlaySeries{
+    pub base:String,            //元の系列名
+    pub name:String,            //計算系列名
+    pub rows:Vec<PlotData>,     //元の系列と同じ長さ・同じ順序
+}
+
+//各系列に対して計算系列を作成する
+//移動窓が埋まるまでの点はy_dataをNoneとする(NaNの点は窓に含めない)
+//計算系列名が元の系列名や他の計算系列名と重なる場合はエラーにする
+pub fn create_line_overlays(data_map:&HashMap<String,Vec<PlotData>>,overlays:&[LineOverlay])->Result<Vec<OverlaySeries>,String>{
+    for overlay in overlays {
+        validate_overlay(overlay)?;
+    }
+
+    let mut overlay_series = Vec::new();
+    let mut overlay_names: HashSet<String> = HashSet::new();
+    for (series_name, rows) in data_map {
+        let (xs, ys): (Vec<Option<chrono::NaiveDateTime>>, Vec<f64>) = rows.iter()
+            .filter_map(|data| match data {
+                PlotData::Line(line) => line.y_data.map(|y| (line.x_data, y)),
+                _ => None,
+            })
+            .unzip();
+        //Line以外やyがNoneの点を含む系列は元の系列と並びが揃わないため対象外
+        if xs.len() != rows.len() {
+            continue;
+        }
+
+        for overlay in overlays {
+            let series: Vec<(String, Vec<Option<f64>>)> = match overlay.kind.as_str() {
+                "RollingMean" => vec![(
+                    format!("{}_rolling_mean_{}", series_name, overlay.window),
+                    rolling_mean_std(&ys, overlay.window).into_iter().map(|v| v.map(|(mean, _)| mean)).collect(),
+                )],
+                "RollingMedian" => vec![(
+                    format!("{}_rolling_median_{}", series_name, overlay.window),
+                    rolling_median(&ys, overlay.window),
+                )],
+                "EWMA" => match overlay.alpha {
+                    //系列名はalphaを指定した場合はその値、それ以外は窓の点数(スパン)にする
+                    Some(alpha) => vec![(format!("{}_ewma_alpha_{}", series_name, alpha), ewma(&ys, alpha))],
+                    None => vec![(
+                        format!("{}_ewma_{}", series_name, overlay.window),
+                        ewma(&ys, 2.0 / (overlay.window as f64 + 1.0)),
+                    )],
+                },
+                "SigmaBand" => {
+                    let mean_std = rolling_mean_std(&ys, overlay.window);
+                    vec![
+                        (
+                            format!("{}_sigma_upper_{}", series_name, overlay.window),
+                            mean_std.iter().map(|v| v.map(|(mean, std)| mean + overlay.k * std)).collect(),
+                        ),
+                        (
+                            format!("{}_sigma_lower_{}", series_name, overlay.window),
+                            mean_std.iter().map(|v| v.map(|(mean, std)| mean - overlay.k * std)).collect(),
+                        ),
+                    ]
+                },
+                _ => return Err(format!("Invalid line overlay: {}", overlay.kind)),
+            };
+
+            for (name, values) in series {
+                if data_map.contains_key(&name) || !overlay_names.insert(name.clone()) {
+                    return Err(format!("Line overlay series name is duplicated: {}", name));
+                }
+                let rows = xs.iter().zip(values)
+                    .map(|(x, y)| PlotData::Line(LinePlotData{x_data:*x,y_data:y,is_alarm:false}))
+                    .collect();
+                overlay_series.push(OverlaySeries{base:series_name.clone(),name,rows});
+            }
+        }
+    }
+
+    Ok(overlay_series)
+}
+
+//計算系列の設定値をチェック
+fn validate_overlay(overlay:&LineOverlay)->Result<(),String>{
+    let min_window = if overlay.kind == "SigmaBand" { 2 } else { 1 };
+    if overlay.window < min_window || overlay.window > MAX_OVERLAY_WINDOW {
+        return Err(format!("Invalid window for {}: {} (must be {}..={})", overlay.kind, overlay.window, min_window, MAX_OVERLAY_WINDOW));
+    }
+    if let Some(alpha) = overlay.alpha && !(alpha > 0.0 && alpha <= 1.0) {
+        return Err(format!("Invalid alpha for EWMA: {} (must be 0 < alpha <= 1)", alpha));
+    }
+    if !(overlay.k.is_finite() && overlay.k > 0.0) {
+        return Err(format!("Invalid k for SigmaBand: {}", overlay.k));
+    }
+    Ok(())
+}
+
+//移動平均と移動標準偏差(標本)
+//窓への追加・削除ごとにWelford法で平均と偏差平方和を更新する(合計・二乗和の差は桁落ちするため使わない)
+fn rolling_mean_std(ys:&[f64],window:usize)->Vec<Option<(f64,f64)>>{
+    let mut count = 0.0;
+    let mut mean = 0.0;
+    let mut m2: f64 = 0.0;
+    ys.iter().enumerate().map(|(i, y)| {
+        if y.is_finite() {
+            count += 1.0;
+            let delta = y - mean;
+            mean += delta / count;
+            m2 += delta * (y - mean);
+        }
+        if i >= window && ys[i - window].is_finite() {
+            let old = ys[i - window];
+            count -= 1.0;
+            if count == 0.0 {
+                mean = 0.0;
+                m2 = 0.0;
+            } else {
+                let delta = old - mean;
+                mean -= delta / count;
+                m2 -= delta * (old - mean);
+            }
+        }
+        if i + 1 < window || count == 0.0 {
+            return None;
+        }
+        let std = if count > 1.0 { (m2.max(0.0) / (count - 1.0)).sqrt() } else { 0.0 };
+        Some((mean, std))
+    }).collect()
+}
+
+//f64を全順序で比較するためのラッパー
+#[derive(Clone,Copy,PartialEq)]
+struct OrderedValue(f64);
+
+impl Eq for OrderedValue {}
+
+impl PartialOrd for OrderedValue {
+    fn partial_cmp(&self,other:&Self)->Option<Ordering>{
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedValue {
+    fn cmp(&self,other:&Self)->Ordering{
+        self.0.total_cmp(&other.0)
+    }
+}
+
+//移動中央値を求めるための2つのヒープ
+//lowは中央値以下の値(最大ヒープ)、highは中央値より大きい値(最小ヒープ)
+//窓から外れた値はdelayedに記録し、ヒープの先頭に来た時点で取り除く
+struct SlidingMedian{
+    low:BinaryHeap<OrderedValue>,
+    high:BinaryHeap<Reverse<OrderedValue>>,
+    delayed:HashMap<u64,usize>,
+    low_size:usize,
+    high_size:usize,
+}
+
+impl SlidingMedian{
+    fn new()->Self{
+        SlidingMedian{low:BinaryHeap::new(), high:BinaryHeap::new(), delayed:HashMap::new(), low_size:0, high_size:0}
+    }
+
+    fn is_low(&self,value:f64)->bool{
+        self.low.peek().map(|top| OrderedValue(value) <= *top).unwrap_or(false)
+    }
+
+    fn insert(&mut self,value:f64){
+        if self.low_size == 0 || self.is_low(value) {
+            self.low.push(OrderedValue(value));
+            self.low_size += 1;
+        } else {
+            self.high.push(Reverse(OrderedValue(value)));
+            self.high_size += 1;
+        }
+        self.rebalance();
+    }
+
+    fn remove(&mut self,value:f64){
+        *self.delayed.entry(value.to_bits()).or_insert(0) += 1;
+        if self.is_low(value) {
+            self.low_size -= 1;
+        } else {
+            self.high_size -= 1;
+        }
+        self.prune();
+        self.rebalance();
+    }
+
+    //ヒープの先頭にある削除済みの値を取り除く
+    fn prune(&mut self){
+        while let Some(top) = self.low.peek() && self.take_delayed(top.0) {
+            self.low.pop();
+        }
+        while let Some(Reverse(top)) = self.high.peek() && self.take_delayed(top.0) {
+            self.high.pop();
+        }
+    }
+
+    fn take_delayed(&mut self,value:f64)->bool{
+        match self.delayed.get_mut(&value.to_bits()) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.delayed.remove(&value.to_bits());
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    //lowの件数がhighと同じか1件多くなるようにする
+    fn rebalance(&mut self){
+        if self.low_size > self.high_size + 1 {
+            if let Some(top) = self.low.pop() {
+                self.high.push(Reverse(top));
+            }
+            self.low_size -= 1;
+            self.high_size += 1;
+        } else if self.low_size < self.high_size {
+            if let Some(Reverse(top)) = self.high.pop() {
+                self.low.push(top);
+            }
+            self.low_size += 1;
+            self.high_size -= 1;
+        }
+        self.prune();
+    }
+
+    fn median(&self)->Option<f64>{
+        let low = self.low.peek()?.0;
+        if self.low_size > self.high_size {
+            Some(low)
+        } else {
+            self.high.peek().map(|Reverse(high)| (low + high.0) / 2.0)
+        }
+    }
+}
+
+//移動中央値(2つのヒープで1点あたりO(log window))
+fn rolling_median(ys:&[f64],window:usize)->Vec<Option<f64>>{
+    let mut median = SlidingMedian::new();
+    ys.iter().enumerate().map(|(i, y)| {
+        if y.is_finite() {
+            median.insert(*y);
+        }
+        if i >= window && ys[i - window].is_finite() {
+            median.remove(ys[i - window]);
+        }
+        if i + 1 < window {
+            return None;
+        }
+        median.median()
+    }).collect()
+}
+
+//指数加重移動平均(初期値は最初の点、NaNの点はNoneとして平均に含めない)
+fn ewma(ys:&[f64],alpha:f64)->Vec<Option<f64>>{
+    let mut current: Option<f64> = None;
+    ys.iter().map(|y| {
+        if !y.is_finite() {
+            return None;
+        }
+        let value = match current {
+            Some(prev) => alpha * y + (1.0 - alpha) * prev,
+            None => *y,
+        };
+        current = Some(value);
+        current
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //窓ごとに計算し直した中央値
+    fn naive_median(ys:&[f64],window:usize)->Vec<Option<f64>>{
+        (0..ys.len()).map(|i| {
+            if i + 1 < window {
+                return None;
+            }
+            let mut values: Vec<f64> = ys[i + 1 - window..=i].iter().cloned().filter(|v| v.is_finite()).collect();
+            if values.is_empty() {
+                return None;
+            }
+            values.sort_by(|a, b| a.total_cmp(b));
+            let mid = values.len() / 2;
+            Some(if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] })
+        }).collect()
+    }
+
+    #[test]
+    fn rolling_median_matches_naive() {
+        let ys: Vec<f64> = (0..500).map(|i| ((i * 7919) % 97) as f64 - 40.0).collect();
+        for window in [1, 2, 3, 10, 51, 500, 600] {
+            assert_eq!(rolling_median(&ys, window), naive_median(&ys, window), "window {}", window);
+        }
+        assert!(rolling_median(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn rolling_median_with_duplicates_and_nan() {
+        let ys = vec![1.0, 1.0, f64::NAN, 1.0, 2.0, 2.0, f64::NAN, f64::NAN, f64::NAN, 0.0, -0.0, 5.0];
+        for window in [2, 3, 4] {
+            assert_eq!(rolling_median(&ys, window), naive_median(&ys, window), "window {}", window);
+        }
+    }
+
+    #[test]
+    fn rolling_mean_std_is_stable_with_large_offset() {
+        let ys: Vec<f64> = (0..100_000).map(|i| 1e9 + (i % 3) as f64).collect();
+        let result = rolling_mean_std(&ys, 3);
+        assert_eq!(result[0], None);
+        for (mean, std) in result.into_iter().skip(2).map(Option::unwrap) {
+            assert!((mean - (1e9 + 1.0)).abs() < 1e-6);
+            assert!((std - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn rolling_mean_std_skips_nan() {
+        let ys = vec![1.0, f64::NAN, 3.0, 5.0];
+        let result = rolling_mean_std(&ys, 2);
+        assert_eq!(result, vec![None, Some((1.0, 0.0)), Some((3.0, 0.0)), Some((4.0, 2f64.sqrt()))]);
+        assert_eq!(rolling_mean_std(&[f64::NAN, f64::NAN], 2), vec![None, None]);
+    }
+
+    #[test]
+    fn ewma_values() {
+        assert!(ewma(&[], 0.5).is_empty());
+        assert_eq!(ewma(&[2.0, 4.0, f64::NAN, 8.0], 0.5), vec![Some(2.0), Some(3.0), None, Some(5.5)]);
+        assert_eq!(ewma(&[2.0, 4.0], 1.0), vec![Some(2.0), Some(4.0)]);
+    }
+
+    #[test]
+    fn overlay_names_use_span_and_reject_collisions() {
+        let rows = |n: usize| (0..n).map(|i| PlotData::Line(LinePlotData{x_data:None, y_data:Some(i as f64), is_alarm:false})).collect::<Vec<_>>();
+        let ewma_overlay = |alpha: Option<f64>| LineOverlay{kind:"EWMA".to_string(), window:20, alpha, k:3.0};
+
+        let data_map = HashMap::from([("data".to_string(), rows(5))]);
+        let series = create_line_overlays(&data_map, &[ewma_overlay(None), ewma_overlay(Some(0.2))]).unwrap();
+        let names: HashSet<String> = series.into_iter().map(|s| s.name).collect();
+        assert_eq!(names, HashSet::from(["data_ewma_20".to_string(), "data_ewma_alpha_0.2".to_string()]));
+
+        assert!(create_line_overlays(&data_map, &[ewma_overlay(None), ewma_overlay(None)]).is_err());
+        let data_map = HashMap::from([("data".to_string(), rows(5)), ("data_ewma_20".to_string(), rows(5))]);
+        assert!(create_line_overlays(&data_map, &[ewma_overlay(None)]).is_err());
+    }
+}
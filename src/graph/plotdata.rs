@@ -1,141 +1,166 @@
+use futures_util::TryStreamExt;
 use sqlx::{PgPool, Row};
+use sqlx::postgres::PgRow;
 use std::error::Error;
 use std::collections::HashMap;
 
 use crate::graph::variants::*;
+use crate::graph::columns::is_timestamp_column;
+use crate::graph::histogram::{bucket_counts_to_bins, create_bin_edges, ValueSummary};
+use crate::graph::row_budget::stream_rows;
+
+//先頭カラムのユニット名を取得する
+//unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
+pub fn get_unit_name(row:&PgRow)->Option<String>{
+    if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
+        Some(s)
+    } else {
+        get_number(row, 0).map(|n| n.to_string())
+    }
+}
+
+//数値カラムの値をf64で取得する
+//INTEGER/BIGINT/SMALLINT/REAL/DOUBLE PRECISIONに対応(NUMERICはSQL側でdouble precisionにキャスト済)
+pub fn get_number(row:&PgRow, index:usize)->Option<f64>{
+    if let Ok(v) = row.try_get::<Option<i32>, _>(index) {
+        v.map(|n| n as f64)
+    } else if let Ok(v) = row.try_get::<Option<f64>, _>(index) {
+        v
+    } else if let Ok(v) = row.try_get::<Option<i64>, _>(index) {
+        v.map(|n| n as f64)
+    } else if let Ok(v) = row.try_get::<Option<f32>, _>(index) {
+        v.map(|n| n as f64)
+    } else if let Ok(v) = row.try_get::<Option<i16>, _>(index) {
+        v.map(|n| n as f64)
+    } else {
+        None
+    }
+}
+
+//散布図の系列名(ユニットなしはdata、ユニットありはユニット名、対象のアラームのチップは先頭にalarm_を付ける)
+pub(crate) fn scatter_series_name(unit_name:Option<&str>,is_alarm:bool)->String{
+    let name = unit_name.unwrap_or("data");
+    if is_alarm { format!("alarm_{}", name) } else { name.to_string() }
+}
 
 //プロット分割しない散布図のデータを取得
-pub async fn plot_scatterplot_without_unit(total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
+pub async fn plot_scatterplot_without_unit(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
     data_map.entry("data".to_string()).or_insert(vec![]);
 
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
-    }
-    let rows_data = query.fetch_all(pool).await?;
-
-    println!("query_rows collected: {} rows", rows_data.len());
+    let mut rows_data = stream_rows(pool, sql, params);
 
     let rows = data_map.get_mut("data").unwrap();
     if graph_condition.alarm.codes.is_empty(){ //アラーム情報を取得しない場合
-        for row in rows_data {
-            let y_opt: Option<i32> = row.try_get(1).ok().flatten();
+        while let Some(row) = rows_data.try_next().await? {
+            let y_opt: Option<f64> = get_number(&row, 1);
 
-            let x_is_valid = if graph_condition.graph_x_item.contains("DATE"){
+            let x_is_valid = if is_timestamp_column(&graph_condition.graph_x_item){
                 row.try_get::<Option<chrono::NaiveDateTime>, _>(0).ok().flatten().is_some()
             }else{
-                row.try_get::<Option<i32>, _>(0).ok().flatten().is_some()
+                get_number(&row, 0).is_some()
             };
 
             // XとYの両方がSomeの場合のみプッシュ
             if x_is_valid && y_opt.is_some() {
-                let x_value: XdimData = if graph_condition.graph_x_item.contains("DATE"){
+                let x_value: XdimData = if is_timestamp_column(&graph_condition.graph_x_item){
                     XdimData::DateData(row.try_get(0).ok())
                 }else{
-                    XdimData::NumberData(row.try_get(0).ok())
+                    XdimData::NumberData(get_number(&row, 0))
                 };
                 rows.push(PlotData::Scatter(ScatterPlotData{x_data:x_value,y_data:y_opt,is_alarm:false}));
             }
         }
     }else{ //アラームをふくめる場合
+        //対象のアラームコードのチップは通常データから分けてalarm_dataに格納する(同じ点を二重に描画しない)
         let target_alarm_code:Vec<i32>=graph_condition.alarm.codes.clone(); //集計対象のアラームコードリスト
-        for row in rows_data {
-            let y_opt: Option<i32> = row.try_get(1).ok().flatten();
+        let mut alarm_rows:Vec<PlotData>=vec![];
+        while let Some(row) = rows_data.try_next().await? {
+            let y_opt: Option<f64> = get_number(&row, 1);
 
-            let x_is_valid = if graph_condition.graph_x_item.contains("DATE"){
+            let x_is_valid = if is_timestamp_column(&graph_condition.graph_x_item){
                 row.try_get::<Option<chrono::NaiveDateTime>, _>(0).ok().flatten().is_some()
             }else{
-                row.try_get::<Option<i32>, _>(0).ok().flatten().is_some()
+                get_number(&row, 0).is_some()
             };
 
             // XとYの両方がSomeの場合のみプッシュ
             if x_is_valid && y_opt.is_some() {
                 let alarm_value: Option<i32> = row.try_get(2).ok().flatten();
                 let is_alarm = alarm_value.map(|v| target_alarm_code.contains(&v)).unwrap_or(false);
-                if is_alarm{println!("OK");}
-                let x_value: XdimData = if graph_condition.graph_x_item.contains("DATE"){
+                let x_value: XdimData = if is_timestamp_column(&graph_condition.graph_x_item){
                     XdimData::DateData(row.try_get(0).ok())
                 }else{
-                    XdimData::NumberData(row.try_get(0).ok())
+                    XdimData::NumberData(get_number(&row, 0))
                 };
-                rows.push(PlotData::Scatter(ScatterPlotData{x_data:x_value,y_data:y_opt,is_alarm}));
+                let point = PlotData::Scatter(ScatterPlotData{x_data:x_value,y_data:y_opt,is_alarm});
+                if is_alarm { alarm_rows.push(point) } else { rows.push(point) }
             }
         }
+        data_map.insert(scatter_series_name(None, true), alarm_rows);
     }
 
     Ok(())
 }
 
 //プロット分割する散布図のデータを取得
-pub async fn plot_scatterplot_with_unit(total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
+pub async fn plot_scatterplot_with_unit(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
     //DBからデータを取得
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
-    }
-    let rows_data = query.fetch_all(pool).await?;
+    let mut rows_data = stream_rows(pool, sql, params);
 
     if graph_condition.alarm.codes.is_empty(){ //アラーム情報を取得しない場合
-        for row in rows_data {
-            // unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
-            let unit_name: String = if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
-                s
-            } else if let Ok(Some(i)) = row.try_get::<Option<i32>, _>(0) {
-                i.to_string()
-            } else {
-                continue; // unit_nameが取得できない場合はスキップ
+        while let Some(row) = rows_data.try_next().await? {
+            let unit_name = match get_unit_name(&row) {
+                Some(s) => s,
+                None => continue, // unit_nameが取得できない場合はスキップ
             };
 
-            let y_opt: Option<i32> = row.try_get(2).ok().flatten();
+            let y_opt: Option<f64> = get_number(&row, 2);
 
-            let x_is_valid = if graph_condition.graph_x_item.contains("DATE"){
+            let x_is_valid = if is_timestamp_column(&graph_condition.graph_x_item){
                 row.try_get::<Option<chrono::NaiveDateTime>, _>(1).ok().flatten().is_some()
             }else{
-                row.try_get::<Option<i32>, _>(1).ok().flatten().is_some()
+                get_number(&row, 1).is_some()
             };
 
             // XとYの両方がSomeの場合のみプッシュ
             if x_is_valid && y_opt.is_some() {
-                let x_value: XdimData = if graph_condition.graph_x_item.contains("DATE"){
+                let x_value: XdimData = if is_timestamp_column(&graph_condition.graph_x_item){
                     XdimData::DateData(row.try_get(1).ok())
                 }else{
-                    XdimData::NumberData(row.try_get(1).ok())
+                    XdimData::NumberData(get_number(&row, 1))
                 };
                 data_map.entry(unit_name).or_insert(vec![]).push(
                     PlotData::Scatter(ScatterPlotData{x_data:x_value, y_data:y_opt,is_alarm:false})
                 );
             }
         }
-    }else{
+    }else{ //アラームをふくめる場合
+        //対象のアラームコードのチップは "alarm_" + ユニット名 の系列に分けて格納する
         let target_alarm_code:Vec<i32>=graph_condition.alarm.codes.clone(); //集計対象のアラームコードリスト
-        for row in rows_data {
-            // unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
-            let unit_name: String = if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
-                s
-            } else if let Ok(Some(i)) = row.try_get::<Option<i32>, _>(0) {
-                i.to_string()
-            } else {
-                continue; // unit_nameが取得できない場合はスキップ
+        while let Some(row) = rows_data.try_next().await? {
+            let unit_name = match get_unit_name(&row) {
+                Some(s) => s,
+                None => continue, // unit_nameが取得できない場合はスキップ
             };
 
-            let y_opt: Option<i32> = row.try_get(2).ok().flatten();
+            let y_opt: Option<f64> = get_number(&row, 2);
 
-            let x_is_valid = if graph_condition.graph_x_item.contains("DATE"){
+            let x_is_valid = if is_timestamp_column(&graph_condition.graph_x_item){
                 row.try_get::<Option<chrono::NaiveDateTime>, _>(1).ok().flatten().is_some()
             }else{
-                row.try_get::<Option<i32>, _>(1).ok().flatten().is_some()
+                get_number(&row, 1).is_some()
             };
 
             // XとYの両方がSomeの場合のみプッシュ
             if x_is_valid && y_opt.is_some() {
                 let alarm_value: Option<i32> = row.try_get(3).ok().flatten();
                 let is_alarm = alarm_value.map(|v| target_alarm_code.contains(&v)).unwrap_or(false);
-                let x_value: XdimData = if graph_condition.graph_x_item.contains("DATE"){
+                let x_value: XdimData = if is_timestamp_column(&graph_condition.graph_x_item){
                     XdimData::DateData(row.try_get(1).ok())
                 }else{
-                    XdimData::NumberData(row.try_get(1).ok())
+                    XdimData::NumberData(get_number(&row, 1))
                 };
-                data_map.entry(unit_name).or_insert(vec![]).push(
+                data_map.entry(scatter_series_name(Some(&unit_name), is_alarm)).or_insert(vec![]).push(
                     PlotData::Scatter(ScatterPlotData{x_data:x_value, y_data:y_opt,is_alarm})
                 );
             }
@@ -146,36 +171,32 @@ pub async fn plot_scatterplot_with_unit(total_count:i64,data_map:&mut HashMap<St
 
 //プロット分割しない折れ線グラフ(時系列プロット)のデータを取得
 //LD_PICKUP_DATEでORDERされた状態でデータ取得済
-pub async fn plot_lineplot_without_unit(total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
+pub async fn plot_lineplot_without_unit(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
     data_map.entry("data".to_string()).or_insert(vec![]);
 
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
-    }
-    let rows_data = query.fetch_all(pool).await?;
-
-    println!("query_rows collected: {} rows", rows_data.len());
+    let mut rows_data = stream_rows(pool, sql, params);
 
     let rows = data_map.get_mut("data").unwrap();
 
     if graph_condition.alarm.codes.is_empty(){ //アラーム情報を取得しない場合
-        for row in rows_data {
-            let y_value: Option<i32> = row.try_get(1).ok().flatten();
+        while let Some(row) = rows_data.try_next().await? {
+            let x_value: Option<chrono::NaiveDateTime> = row.try_get(0).ok().flatten();
+            let y_value: Option<f64> = get_number(&row, 1);
             // Yがnullでない場合のみプッシュ
             if y_value.is_some() {
-                rows.push(PlotData::Line(LinePlotData{y_data:y_value,is_alarm:false}));
+                rows.push(PlotData::Line(LinePlotData{x_data:x_value,y_data:y_value,is_alarm:false}));
             }
         }
     }else{
         let target_alarm_code:Vec<i32>=graph_condition.alarm.codes.clone(); //集計対象のアラームコードリスト
-        for row in rows_data {
-            let y_value: Option<i32> = row.try_get(1).ok().flatten();
+        while let Some(row) = rows_data.try_next().await? {
+            let x_value: Option<chrono::NaiveDateTime> = row.try_get(0).ok().flatten();
+            let y_value: Option<f64> = get_number(&row, 1);
             // Yがnullでない場合のみプッシュ
             if y_value.is_some() {
                 let alarm_value: Option<i32> = row.try_get(2).ok().flatten();
                 let is_alarm = alarm_value.map(|v| target_alarm_code.contains(&v)).unwrap_or(false);
-                rows.push(PlotData::Line(LinePlotData{y_data:y_value,is_alarm}));
+                rows.push(PlotData::Line(LinePlotData{x_data:x_value,y_data:y_value,is_alarm}));
             }
         }
     }
@@ -186,51 +207,41 @@ pub async fn plot_lineplot_without_unit(total_count:i64,data_map:&mut HashMap<St
 }
 
 //プロット分割する折れ線グラフのデータを取得
-pub async fn plot_lineplot_with_unit(total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
-    }
-    let rows_data = query.fetch_all(pool).await?;
+pub async fn plot_lineplot_with_unit(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<(),Box<dyn Error>>{
+    let mut rows_data = stream_rows(pool, sql, params);
 
     if graph_condition.alarm.codes.is_empty(){ //アラーム情報を取得しない場合
-        for row in rows_data {
-            // unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
-            let unit: String = if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
-                s
-            } else if let Ok(Some(i)) = row.try_get::<Option<i32>, _>(0) {
-                i.to_string()
-            } else {
-                continue; // unit_nameが取得できない場合はスキップ
+        while let Some(row) = rows_data.try_next().await? {
+            let unit = match get_unit_name(&row) {
+                Some(s) => s,
+                None => continue, // unit_nameが取得できない場合はスキップ
             };
 
-            let y_value: Option<i32> = row.try_get(2).ok().flatten();
+            let x_value: Option<chrono::NaiveDateTime> = row.try_get(1).ok().flatten();
+            let y_value: Option<f64> = get_number(&row, 2);
             // Yがnullでない場合のみプッシュ
             if y_value.is_some() {
                 data_map.entry(unit).or_insert(vec![]).push(
-                    PlotData::Line(LinePlotData{y_data:y_value,is_alarm:false})
+                    PlotData::Line(LinePlotData{x_data:x_value,y_data:y_value,is_alarm:false})
                 );
             }
         }
     }else{
         let target_alarm_code:Vec<i32>=graph_condition.alarm.codes.clone(); //集計対象のアラームコードリスト
-        for row in rows_data {
-            // unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
-            let unit: String = if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
-                s
-            } else if let Ok(Some(i)) = row.try_get::<Option<i32>, _>(0) {
-                i.to_string()
-            } else {
-                continue; // unit_nameが取得できない場合はスキップ
+        while let Some(row) = rows_data.try_next().await? {
+            let unit = match get_unit_name(&row) {
+                Some(s) => s,
+                None => continue, // unit_nameが取得できない場合はスキップ
             };
 
-            let y_value: Option<i32> = row.try_get(2).ok().flatten();
+            let x_value: Option<chrono::NaiveDateTime> = row.try_get(1).ok().flatten();
+            let y_value: Option<f64> = get_number(&row, 2);
             // Yがnullでない場合のみプッシュ
             if y_value.is_some() {
                 let alarm_value: Option<i32> = row.try_get(3).ok().flatten();
                 let is_alarm = alarm_value.map(|v| target_alarm_code.contains(&v)).unwrap_or(false);
                 data_map.entry(unit).or_insert(vec![]).push(
-                    PlotData::Line(LinePlotData{y_data:y_value,is_alarm})
+                    PlotData::Line(LinePlotData{x_data:x_value,y_data:y_value,is_alarm})
                 );
             }
         }
@@ -238,203 +249,306 @@ pub async fn plot_lineplot_with_unit(total_count:i64,data_map:&mut HashMap<Strin
     Ok(())
 }
 
-/* Heatmap(Histogram) */
-//プロット分割しないヒストグラムのデータを取得
-pub async fn plot_histogram_without_unit(_total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<HistogramBinInfo,Box<dyn Error>>{
-    //dataキーに値を入れる
-    data_map.entry("data".to_string()).or_insert(vec![]);
-
+//時間単位で集計した折れ線グラフのデータを取得(ユニット分割の有無どちらにも対応)
+//平均・最小・最大・標準偏差・件数はSQL側で集計済
+pub async fn plot_lineplot_aggregate(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String])->Result<(),Box<dyn Error>>{
     let mut query = sqlx::query(sql);
     for param in params {
         query = query.bind(param);
     }
-    let rows_data = query.fetch_all(pool).await?;
-
-    let mut query_rows: Vec<i32> = Vec::new();
-    for row in rows_data {
-        if let Ok(Some(x_value)) = row.try_get::<Option<i32>, _>(0) {
-            query_rows.push(x_value);
-        }
-    }
+    let mut rows_data = query.fetch(pool);
 
-    if query_rows.is_empty(){
-        return Ok(HistogramBinInfo{
-            bin_edges: vec![],
-            bin_width: 0.0,
-        });
+    while let Some(row) = rows_data.try_next().await? {
+        let unit = match get_unit_name(&row) {
+            Some(s) => s,
+            None => continue, // unit_nameが取得できない場合はスキップ
+        };
+        let x_value: Option<chrono::NaiveDateTime> = row.try_get(1).ok().flatten();
+        let count: i64 = row.try_get(6).unwrap_or(0);
+        data_map.entry(unit).or_insert(vec![]).push(
+            PlotData::LineAggregate(LineAggregateData{
+                x_data: x_value,
+                mean: get_number(&row, 2),
+                min: get_number(&row, 3),
+                max: get_number(&row, 4),
+                std: get_number(&row, 5),
+                count,
+            })
+        );
     }
+    Ok(())
+}
 
-    // データの最小値と最大値を取得
-    let x_min = *query_rows.iter().min().unwrap();
-    let x_max = *query_rows.iter().max().unwrap();
-
-    // ビン幅を計算
-    let bin_width = (x_max - x_min) as f64 / graph_condition.bin_number as f64;
-
-    // ビンの境界値を計算
-    let mut bin_edges = Vec::new();
-    for i in 0..=graph_condition.bin_number {
-        bin_edges.push(x_min + (bin_width * i as f64) as i32);
-    }
+/* Heatmap(Histogram) */
+//ヒストグラムのビンの境界値を決める(通常データ・アラームで共通のビンを使用する)
+//bin_edges指定時、またはbin_rangeとFixedのビン数の指定時は値の要約を取得せずにそのまま決める
+pub async fn histogram_bin_edges(pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<Vec<f64>,Box<dyn Error>>{
+    let needs_summary = graph_condition.bin_edges.is_empty()
+        && !(graph_condition.bin_range.is_some() && graph_condition.bin_rule == "Fixed");
+    let summary = if needs_summary {
+        query_value_summary(pool, sql, params, graph_condition).await?
+    } else {
+        ValueSummary{count:0, min:None, max:None, std:None, q1:None, q3:None}
+    };
+    Ok(create_bin_edges(&summary, graph_condition)?)
+}
 
-    // ビンごとの個数を集計
-    let mut bin_counts = vec![0; graph_condition.bin_number as usize];
-    for value in query_rows {
-        let bin_index = if bin_width == 0.0 {
-            0
-        } else {
-            (((value - x_min) as f64 / bin_width) as usize).min(graph_condition.bin_number as usize - 1)
-        };
-        bin_counts[bin_index] += 1;
-    }
+//ヒストグラムのデータを取得(ユニット分割の有無どちらにも対応)
+//histogram_bin_edgesで決めたビンごとに、SQL側でwidth_bucketを使って集計する
+//全ユニットで同じビンを使用する
+pub async fn plot_histogram(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition,bin_edges:&[f64])->Result<HistogramBinInfo,Box<dyn Error>>{
+    // 各ユニットのデータをビン化
+    let mut underflow_count = 0;
+    let mut overflow_count = 0;
+    for (unit_name, bucket_counts) in query_histogram_buckets(pool, sql, params, bin_edges).await? {
+        let (bin_counts, underflow, overflow) = bucket_counts_to_bins(&bucket_counts, &graph_condition.histogram_outliers);
+        underflow_count += underflow;
+        overflow_count += overflow;
 
-    // BinnedHistogramDataとして格納
-    let rows = data_map.get_mut("data").unwrap();
-    for (bin_index, count) in bin_counts.into_iter().enumerate() {
-        rows.push(PlotData::BinnedHistogram(BinnedHistogramData {
-            bin_index,
-            count,
-        }));
+        // BinnedHistogramDataとして格納
+        let rows = data_map.entry(unit_name).or_insert(vec![]);
+        for (bin_index, count) in bin_counts.into_iter().enumerate() {
+            rows.push(PlotData::BinnedHistogram(BinnedHistogramData {
+                bin_index,
+                count,
+            }));
+        }
     }
 
     Ok(HistogramBinInfo {
-        bin_edges,
-        bin_width,
+        bin_width: (bin_edges[bin_edges.len() - 1] - bin_edges[0]) / (bin_edges.len() - 1) as f64,
+        bin_edges: bin_edges.to_vec(),
+        outliers: graph_condition.histogram_outliers.clone(),
+        underflow_count,
+        overflow_count,
     })
 }
 
-//プロット分割するヒストグラムのデータを取得
-pub async fn plot_histogram_with_unit(_total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<HistogramBinInfo,Box<dyn Error>>{
-    let mut query = sqlx::query(sql);
-    for param in params {
-        query = query.bind(param);
+//ビン数の決定に使う値の要約を取得する
+//四分位数はFreedmanDiaconisの場合のみ計算する(ソートが必要なため)
+async fn query_value_summary(pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<ValueSummary,Box<dyn Error>>{
+    let mut params = params.to_vec();
+    let quartiles = if graph_condition.bin_edges.is_empty() && graph_condition.bin_rule == "FreedmanDiaconis" {
+        "percentile_cont(0.25) WITHIN GROUP (ORDER BY x_value), percentile_cont(0.75) WITHIN GROUP (ORDER BY x_value)"
+    } else {
+        "NULL::double precision, NULL::double precision"
+    };
+    let mut summary_sql = format!(
+        "WITH base AS ({}) SELECT COUNT(x_value), MIN(x_value), MAX(x_value), STDDEV_SAMP(x_value), {} FROM base WHERE x_value IS NOT NULL",
+        sql, quartiles
+    );
+    if let Some([low, high]) = graph_condition.bin_range {
+        summary_sql += &format!(" AND x_value BETWEEN ${}::double precision AND ${}::double precision", params.len() + 1, params.len() + 2);
+        params.push(low.to_string());
+        params.push(high.to_string());
     }
-    let rows_data = query.fetch_all(pool).await?;
-
-    let mut query_rows: Vec<(String,i32)> = Vec::new();
-    for row in rows_data {
-        // unit_nameはINTEGERまたはVARCHAR型の可能性があるので、両方試す
-        let unit_name: String = if let Ok(Some(s)) = row.try_get::<Option<String>, _>(0) {
-            s
-        } else if let Ok(Some(i)) = row.try_get::<Option<i32>, _>(0) {
-            i.to_string()
-        } else {
-            continue; // unit_nameが取得できない場合はスキップ
-        };
 
-        if let Ok(Some(x_value)) = row.try_get::<Option<i32>, _>(1) {
-            query_rows.push((unit_name, x_value));
-        }
+    let mut query = sqlx::query(&summary_sql);
+    for param in &params {
+        query = query.bind(param);
     }
+    let row = query.fetch_one(pool).await?;
+
+    Ok(ValueSummary{
+        count: row.try_get(0).unwrap_or(0),
+        min: get_number(&row, 1),
+        max: get_number(&row, 2),
+        std: get_number(&row, 3),
+        q1: get_number(&row, 4),
+        q3: get_number(&row, 5),
+    })
+}
 
-    if query_rows.is_empty(){
-        return Ok(HistogramBinInfo{
-            bin_edges: vec![],
-            bin_width: 0.0,
-        });
+//ユニットごとにwidth_bucketのバケットごとの件数を取得する
+//戻り値のバケットは0(下限未満), 1..=n(各ビン), n+1(上限超過)
+//最後のビンは上限の値を含む
+pub async fn query_histogram_buckets(pool:&PgPool,sql:&str,params:&[String],bin_edges:&[f64])->Result<HashMap<String,Vec<i32>>,Box<dyn Error>>{
+    let mut params = params.to_vec();
+    let bin_count = bin_edges.len() - 1;
+    let edges_index = params.len() + 1;
+    let bucket_sql = format!(
+        "WITH base AS ({sql}) SELECT unit_name,
+            CASE WHEN x_value = ${last}::double precision THEN {bin_count} ELSE width_bucket(x_value, ${edges}::double precision[]) END AS bucket,
+            COUNT(*)
+        FROM base WHERE x_value IS NOT NULL GROUP BY 1, 2",
+        sql = sql, last = edges_index + 1, edges = edges_index, bin_count = bin_count
+    );
+    params.push(format!("{{{}}}", bin_edges.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(",")));
+    params.push(bin_edges[bin_count].to_string());
+
+    let mut query = sqlx::query(&bucket_sql);
+    for param in &params {
+        query = query.bind(param);
     }
+    let mut rows_data = query.fetch(pool);
 
-    // 全データの最小値と最大値を取得(全ユニットで同じビンを使用)
-    let x_min = *query_rows.iter().map(|(_, v)| v).min().unwrap();
-    let x_max = *query_rows.iter().map(|(_, v)| v).max().unwrap();
-
-    // ビン幅を計算
-    let bin_width = (x_max - x_min) as f64 / graph_condition.bin_number as f64;
-
-    // ビンの境界値を計算
-    let mut bin_edges = Vec::new();
-    for i in 0..=graph_condition.bin_number {
-        bin_edges.push(x_min + (bin_width * i as f64) as i32);
+    let mut unit_buckets: HashMap<String, Vec<i32>> = HashMap::new();
+    while let Some(row) = rows_data.try_next().await? {
+        let unit_name = match get_unit_name(&row) {
+            Some(s) => s,
+            None => continue, // unit_nameが取得できない場合はスキップ
+        };
+        let bucket: i32 = row.try_get(1).unwrap_or(0);
+        let count: i64 = row.try_get(2).unwrap_or(0);
+        let buckets = unit_buckets.entry(unit_name).or_insert_with(|| vec![0; bin_count + 2]);
+        buckets[(bucket.max(0) as usize).min(bin_count + 1)] += count as i32;
     }
 
-    // ユニットごとにデータを分けてビン化
-    let mut unit_data: HashMap<String, Vec<i32>> = HashMap::new();
-    for (unit_name, value) in query_rows {
-        unit_data.entry(unit_name).or_insert(vec![]).push(value);
+    Ok(unit_buckets)
+}
+
+/* Heatmap(DensityPlot) */
+//密度プロットのグリッドを決める(通常データ・アラームで共通のグリッドを使用する)
+//x,yの最小・最大でグリッド幅を決め、データが無い場合はNoneを返す
+pub async fn densityplot_grid(pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<Option<GridData>,Box<dyn Error>>{
+    //格子幅を決めるためにx,yのmax,minを出す
+    let range_sql = format!(
+        "WITH base AS ({}) SELECT COUNT(*), MIN(x_value), MAX(x_value), MIN(y_value), MAX(y_value) FROM base WHERE x_value IS NOT NULL AND y_value IS NOT NULL",
+        sql
+    );
+    let mut query = sqlx::query(&range_sql);
+    for param in params {
+        query = query.bind(param);
     }
+    let row = query.fetch_one(pool).await?;
+    let count: i64 = row.try_get(0).unwrap_or(0);
+    let (x_min, x_max, y_min, y_max) = match (get_number(&row, 1), get_number(&row, 2), get_number(&row, 3), get_number(&row, 4)) {
+        (Some(x_min), Some(x_max), Some(y_min), Some(y_max)) if count > 0 => (x_min, x_max, y_min, y_max),
+        _ => return Ok(None),
+    };
 
-    // 各ユニットのデータをビン化
-    for (unit_name, values) in unit_data {
-        let mut bin_counts = vec![0; graph_condition.bin_number as usize];
-        for value in values {
-            let bin_index = if bin_width == 0.0 {
-                0
-            } else {
-                (((value - x_min) as f64 / bin_width) as usize).min(graph_condition.bin_number as usize - 1)
-            };
-            bin_counts[bin_index] += 1;
-        }
+    //グリッド幅を計算
+    Ok(Some(GridData {
+        grid_x: (x_max - x_min) / graph_condition.bins_x as f64,
+        grid_y: (y_max - y_min) / graph_condition.bins_y as f64,
+        x_min, y_min,
+        ..Default::default()
+    }))
+}
 
-        // BinnedHistogramDataとして格納
-        for (bin_index, count) in bin_counts.into_iter().enumerate() {
-            data_map.entry(unit_name.clone()).or_insert(vec![]).push(
-                PlotData::BinnedHistogram(BinnedHistogramData {
-                    bin_index,
-                    count,
-                })
-            );
+//密度プロットのデータを取得(ユニット分割の有無どちらにも対応)
+//densityplot_gridで決めたグリッドごとにSQL側で集計する
+//全ユニットで同じ軸(グリッド)を使用して比較できるようにする
+pub async fn plot_densityplot(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition,grid_data:Option<&GridData>)->Result<GridData,Box<dyn Error>>{
+    if graph_condition.plot_unit == "None" {
+        data_map.entry("data".to_string()).or_insert(vec![]);
+    }
+    let grid_data = match grid_data {
+        Some(grid_data) => grid_data,
+        None => return Ok(GridData::default()),
+    };
+
+    //HashmapにユニットごとにVec<PlotData>でまとめる
+    for (unit_name, arr) in query_density_grid(pool, sql, params, grid_data, graph_condition).await? {
+        let rows = data_map.entry(unit_name).or_insert(vec![]);
+        for y in 0..graph_condition.bins_y{
+            for x in 0..graph_condition.bins_x{
+                rows.push(PlotData::Heatmap(HeatmapData{x_data:x,y_data:y,z_data:Some(arr[x as usize][y as usize])}));
+            }
         }
     }
 
-    Ok(HistogramBinInfo {
-        bin_edges,
-        bin_width,
-    })
+    Ok(GridData{grid_x:grid_data.grid_x, grid_y:grid_data.grid_y, x_min:grid_data.x_min, y_min:grid_data.y_min, ..Default::default()})
 }
 
-/* Heatmap(DensityPlot) */
-//プロット分割しない密度プロットのデータを取得
-pub async fn plot_densityplot_without_unit(total_count:i64,data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String],graph_condition:&GraphCondition)->Result<GridData,Box<dyn Error>>{
-    data_map.entry("data".to_string()).or_insert(vec![]);
-
-    let mut query = sqlx::query(sql);
-    for param in params {
+//ユニットごとにグリッドごとの件数を取得する(arr[x][y])
+//グリッド番号はfloor((値-最小値)/グリッド幅)で、範囲外の値は端のグリッドに含める
+pub async fn query_density_grid(pool:&PgPool,sql:&str,params:&[String],grid_data:&GridData,graph_condition:&GraphCondition)->Result<HashMap<String,Vec<Vec<i32>>>,Box<dyn Error>>{
+    let mut params = params.to_vec();
+    let index = params.len() + 1;
+    let grid_expr = |value: &str, min: usize, len: usize, bins: u32| format!(
+        "CASE WHEN ${len}::double precision = 0 THEN 0 ELSE LEAST(GREATEST(floor(({value} - ${min}::double precision) / ${len}::double precision), 0), {max})::integer END",
+        value = value, min = min, len = len, max = bins - 1
+    );
+    let grid_sql = format!(
+        "WITH base AS ({sql}) SELECT unit_name, {gx} AS grid_x, {gy} AS grid_y, COUNT(*)
+        FROM base WHERE x_value IS NOT NULL AND y_value IS NOT NULL GROUP BY 1, 2, 3",
+        sql = sql,
+        gx = grid_expr("x_value", index, index + 1, graph_condition.bins_x),
+        gy = grid_expr("y_value", index + 2, index + 3, graph_condition.bins_y),
+    );
+    params.push(grid_data.x_min.to_string());
+    params.push(grid_data.grid_x.to_string());
+    params.push(grid_data.y_min.to_string());
+    params.push(grid_data.grid_y.to_string());
+
+    let mut query = sqlx::query(&grid_sql);
+    for param in &params {
         query = query.bind(param);
     }
-    let rows_data = query.fetch_all(pool).await?;
+    let mut rows_data = query.fetch(pool);
 
-    //格子幅を決めるためにx,yのmax,minを出す
-    let mut x_min=i32::MAX;
-    let mut x_max=i32::MIN;
-    let mut y_min=i32::MAX;
-    let mut y_max=i32::MIN;
-
-    let mut query_rows: Vec<(i32, i32)> = Vec::new();
-    for row in rows_data {
-        let x_value_opt: Option<i32> = row.try_get(0).ok().flatten();
-        let y_value_opt: Option<i32> = row.try_get(1).ok().flatten();
-        if let (Some(x_value), Some(y_value)) = (x_value_opt, y_value_opt) {
-            if x_value < x_min { x_min = x_value; }
-            if x_value > x_max { x_max = x_value; }
-            if y_value < y_min { y_min = y_value; }
-            if y_value > y_max { y_max = y_value; }
-            query_rows.push((x_value, y_value));
-        }
+    let mut unit_arr: HashMap<String, Vec<Vec<i32>>> = HashMap::new();
+    while let Some(row) = rows_data.try_next().await? {
+        let unit_name = match get_unit_name(&row) {
+            Some(s) => s,
+            None => continue, // unit_nameが取得できない場合はスキップ
+        };
+        let grid_num_x: i32 = row.try_get(1).unwrap_or(0);
+        let grid_num_y: i32 = row.try_get(2).unwrap_or(0);
+        let count: i64 = row.try_get(3).unwrap_or(0);
+        let arr = unit_arr.entry(unit_name)
+            .or_insert_with(|| vec![vec![0; graph_condition.bins_y as usize]; graph_condition.bins_x as usize]);
+        arr[grid_num_x as usize][grid_num_y as usize] += count as i32;
     }
 
-    //グリッド幅を計算
-    let grid_len_x=(x_max as f64-x_min as f64)/graph_condition.bins_x as f64;
-    let grid_len_y=(y_max as f64-y_min as f64)/graph_condition.bins_y as f64;
-
-    //グリッド毎の数量を初期化
-    let mut arr = vec![vec![0; graph_condition.bins_y as usize]; graph_condition.bins_x as usize];
-
-    for (x_val, y_val) in query_rows.iter() {
-        let grid_num_x = (((x_val - x_min) as f64 / grid_len_x) as usize)
-            .min(graph_condition.bins_x as usize - 1);
-        let grid_num_y = (((y_val - y_min) as f64 / grid_len_y) as usize)
-            .min(graph_condition.bins_y as usize - 1);
+    Ok(unit_arr)
+}
 
-        arr[grid_num_x][grid_num_y] += 1;
+/* BoxPlot */
+//ユニットごとに返す外れ値の最大数
+const MAX_BOXPLOT_OUTLIERS: usize = 1000;
+
+//箱ひげ図のデータを取得(ユニット分割の有無どちらにも対応)
+//四分位数はSQL側でpercentile_contを使って計算し、生データは取得しない
+pub async fn plot_boxplot(data_map:&mut HashMap<String,Vec<PlotData>>,pool:&PgPool,sql:&str,params:&[String])->Result<(),Box<dyn Error>>{
+    let box_sql = format!(
+        "WITH base AS ({sql}),
+        stats AS (
+            SELECT unit_name, COUNT(*) AS cnt, MIN(value) AS min_value, MAX(value) AS max_value, AVG(value) AS mean_value,
+                percentile_cont(0.25) WITHIN GROUP (ORDER BY value) AS q1,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY value) AS median,
+                percentile_cont(0.75) WITHIN GROUP (ORDER BY value) AS q3
+            FROM base GROUP BY unit_name
+        )
+        SELECT s.unit_name, s.cnt, s.min_value, s.q1, s.median, s.q3, s.max_value, s.mean_value,
+            MIN(b.value) FILTER (WHERE b.value >= s.q1 - 1.5 * (s.q3 - s.q1)) AS whisker_low,
+            MAX(b.value) FILTER (WHERE b.value <= s.q3 + 1.5 * (s.q3 - s.q1)) AS whisker_high,
+            COUNT(*) FILTER (WHERE b.value < s.q1 - 1.5 * (s.q3 - s.q1) OR b.value > s.q3 + 1.5 * (s.q3 - s.q1)) AS outlier_count,
+            (array_agg(b.value ORDER BY b.value) FILTER (WHERE b.value < s.q1 - 1.5 * (s.q3 - s.q1) OR b.value > s.q3 + 1.5 * (s.q3 - s.q1)))[1:{max_outliers}] AS outliers
+        FROM base b JOIN stats s ON b.unit_name = s.unit_name
+        GROUP BY s.unit_name, s.cnt, s.min_value, s.q1, s.median, s.q3, s.max_value, s.mean_value
+        ORDER BY s.unit_name",
+        sql = sql, max_outliers = MAX_BOXPLOT_OUTLIERS
+    );
+
+    let mut query = sqlx::query(&box_sql);
+    for param in params {
+        query = query.bind(param);
     }
+    let mut rows_data = query.fetch(pool);
 
-    //HashmapにVec<PlotData>でまとめる
-    let rows= data_map.get_mut("data").unwrap();
-    for y in 0..graph_condition.bins_y{
-        for x in 0..graph_condition.bins_x{
-            rows.push(PlotData::Heatmap(HeatmapData{x_data:x,y_data:y,z_data:Some(arr[x as usize][y as usize])}));
-        }
+    while let Some(row) = rows_data.try_next().await? {
+        let unit_name = match get_unit_name(&row) {
+            Some(s) => s,
+            None => continue, // unit_nameが取得できない場合はスキップ
+        };
+        let number = |index:usize| get_number(&row, index).unwrap_or(0.0);
+        let box_data = BoxPlotData{
+            count: row.try_get(1).unwrap_or(0),
+            min: number(2),
+            q1: number(3),
+            median: number(4),
+            q3: number(5),
+            max: number(6),
+            mean: number(7),
+            whisker_low: number(8),
+            whisker_high: number(9),
+            outlier_count: row.try_get(10).unwrap_or(0),
+            outliers: row.try_get::<Option<Vec<f64>>, _>(11).ok().flatten().unwrap_or_default(),
+        };
+        data_map.entry(unit_name).or_insert(vec![]).push(PlotData::BoxPlot(box_data));
     }
 
-    Ok(GridData { grid_x: grid_len_x, grid_y: grid_len_y, x_min: x_min, y_min: y_min, histogram_bin_info: None })
+    Ok(())
 }
@@ -0,0 +1,74 @@
+/* 生データを取得するグラフの行数の上限(row budget)を管理する */
+use futures_util::stream::{BoxStream, StreamExt};
+use sqlx::PgPool;
+use sqlx::postgres::PgRow;
+use std::env;
+use std::error::Error;
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+
+//グラフ1つあたりで取得する生データの最大行数
+static MAX_GRAPH_ROWS: Lazy<usize> = Lazy::new(|| {
+    env::var("MAX_GRAPH_ROWS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(2_000_000)
+});
+
+//取得前の行数チェック方法
+//Limit: 上限+1行までしか取得せず、超えた場合はエラー(既定)
+//Explain: 取得前にEXPLAINの推定行数でもチェックし、明らかに超える場合はスキャンせずにエラー
+static ROW_BUDGET_CHECK: Lazy<String> = Lazy::new(|| {
+    env::var("ROW_BUDGET_CHECK").unwrap_or("Limit".to_string())
+});
+
+//上限を超えた場合のエラー
+fn row_budget_error(rows:&str)->Box<dyn Error>{
+    format!(
+        "Too many rows for this graph ({} rows, limit {}). Narrow your filters or date range, or use aggregation (LinePlot line_bucket, Histogram, DensityPlot, BoxPlot)",
+        rows, *MAX_GRAPH_ROWS
+    ).into()
+}
+
+//上限+1行で打ち切るLIMIT句を付ける
+pub fn limit_sql(sql:&str)->String{
+    format!("{} LIMIT {}", sql, *MAX_GRAPH_ROWS + 1)
+}
+
+//EXPLAINの推定行数が上限を超えていないかチェックする(ROW_BUDGET_CHECK=Explainの場合のみ)
+pub async fn check_row_estimate(pool:&PgPool,sql:&str,params:&[String])->Result<(),Box<dyn Error>>{
+    if *ROW_BUDGET_CHECK != "Explain" {
+        return Ok(());
+    }
+
+    let explain_sql = format!("EXPLAIN (FORMAT JSON) {}", sql);
+    let mut query = sqlx::query_scalar::<_, serde_json::Value>(&explain_sql);
+    for param in params {
+        query = query.bind(param);
+    }
+    let plan = query.fetch_one(pool).await?;
+    let estimated_rows = plan[0]["Plan"]["Plan Rows"].as_f64().unwrap_or(0.0);
+    debug!("Estimated rows: {}", estimated_rows);
+
+    if estimated_rows > *MAX_GRAPH_ROWS as f64 {
+        warn!("Rejected query by row estimate: {} rows", estimated_rows);
+        return Err(row_budget_error(&format!("about {}", estimated_rows as u64)));
+    }
+    Ok(())
+}
+
+//行を1行ずつ取得するストリームを返す(全行をメモリに載せない)
+//上限を超える行が返ってきた時点でエラーを返す(生データを取得する場合はSQLにlimit_sqlでLIMIT句を付けておくこと)
+pub fn stream_rows<'a>(pool:&'a PgPool,sql:&'a str,params:&'a [String])->BoxStream<'a,Result<PgRow,Box<dyn Error>>>{
+    let mut query = sqlx::query(sql);
+    for param in params {
+        query = query.bind(param);
+    }
+    let max_rows = *MAX_GRAPH_ROWS;
+    query.fetch(pool)
+        .enumerate()
+        .map(move |(index, row)| {
+            if index >= max_rows {
+                return Err(row_budget_error(&format!("more than {}", max_rows)));
+            }
+            row.map_err(|e| e.into())
+        })
+        .boxed()
+}
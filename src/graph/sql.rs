@@ -1,112 +1,97 @@
-use crate::graph::{self, variants::*};
+use crate::graph::variants::*;
+use crate::graph::columns::{column_type,select_expr,ColumnType};
+use crate::graph::histogram::{validate_bin_condition, MAX_BIN_NUMBER};
+use chrono::{NaiveDate, NaiveDateTime};
+use once_cell::sync::Lazy;
+use std::env;
 use tracing::debug;
 
-// 許可されたカラム名のリスト（ホワイトリスト）
-// セキュリティのため、このリストにあるカラム名のみがSQL文で使用可能です
-const ALLOWED_COLUMNS: &[&str] = &[
-    // 基本カラム
-    "ID", "MACHINE_ID", "TYPE_NAME", "LOT_NAME", "SERIAL", "WANO", "WAX", "WAY",
-
-    // LD (Loader) 関連
-    "LD_PICKUP_DATE", "LD_TRAYID", "LD_TRAY_ARM", "LD_TRAY_POCKET_X", "LD_TRAY_POCKET_Y",
-    "LD_TRAY_ALIGN_X", "LD_TRAY_ALIGN_Y", "LD_ARM1_COLLET", "LD_ALARM",
-
-    // DC1 (Die Checker 1) 関連
-    "DC1_PRE_ALIGN_X", "DC1_PRE_ALIGN_Y", "DC1_PRE_ALIGN_T", "DC1_ARM1_COLLET",
-    "DC1_STAGE_SERIAL", "DC1_STAGE_COUNT", "DC1_PROBE_SERIAL", "DC1_PROBE_COUNT",
-    "DC1_PROBE_X1", "DC1_PROBE_Y1", "DC1_PROBE_X2", "DC1_PROBE_Y2",
-    "DC1_STAGE_Z", "DC1_PIN_Z", "DC1_CHIP_ALIGN_X", "DC1_CHIP_ALIGN_Y", "DC1_CHIP_ALIGN_T",
-    "DC1_TEST_BIN", "DC1_ARM2_COLLET", "DC1_ALARM",
-
-    // AC1 (AC Test 1) 関連
-    "AC1_ARM1_COLLET", "AC1_STAGE_SERIAL", "AC1_STAGE_COUNT", "AC1_PROBE_SERIAL", "AC1_PROBE_COUNT",
-    "AC1_PROBE_X1", "AC1_PROBE_Y1", "AC1_PROBE_X2", "AC1_PROBE_Y2",
-    "AC1_STAGE_Z", "AC1_PIN_Z", "AC1_CHIP_ALIGN_X", "AC1_CHIP_ALIGN_Y", "AC1_CHIP_ALIGN_T",
-    "AC1_TEST_BIN", "AC1_ARM2_COLLET", "AC1_ALARM",
-
-    // AC2 (AC Test 2) 関連
-    "AC2_ARM1_COLLET", "AC2_STAGE_SERIAL", "AC2_STAGE_COUNT", "AC2_PROBE_SERIAL", "AC2_PROBE_COUNT",
-    "AC2_PROBE_X1", "AC2_PROBE_Y1", "AC2_PROBE_X2", "AC2_PROBE_Y2",
-    "AC2_STAGE_Z", "AC2_PIN_Z", "AC2_CHIP_ALIGN_X", "AC2_CHIP_ALIGN_Y", "AC2_CHIP_ALIGN_T",
-    "AC2_TEST_BIN", "AC2_ARM2_COLLET", "AC2_ALARM",
-
-    // DC2 (Die Checker 2) 関連
-    "DC2_ARM1_COLLET", "DC2_STAGE_SERIAL", "DC2_STAGE_COUNT", "DC2_PROBE_SERIAL", "DC2_PROBE_COUNT",
-    "DC2_PROBE_X1", "DC2_PROBE_Y1", "DC2_PROBE_X2", "DC2_PROBE_Y2",
-    "DC2_STAGE_Z", "DC2_PIN_Z", "DC2_CHIP_ALIGN_X", "DC2_CHIP_ALIGN_Y", "DC2_CHIP_ALIGN_T",
-    "DC2_TEST_BIN", "DC2_ARM2_COLLET", "DC2_ALARM",
-
-    // IP (Inspection) 関連
-    "IP_ARM1_COLLET", "IP_STAGE_COUNT", "IP_SURF_BIN", "IP_ARM2_COLLET", "IP_BACK_BIN", "IP_ALARM",
-
-    // ULD (Unloader) 関連
-    "ULD_PRE_ALIGN_X", "ULD_PRE_ALIGN_Y", "ULD_PRE_ALIGN_T", "ULD_TRAYID",
-    "ULD_POCKET_X", "ULD_POCKET_Y", "ULD_POCKET_ALIGN_X", "ULD_POCKET_ALIGN_Y",
-    "ULD_ARM1_COLLET", "ULD_PUT_DATE", "ULD_CHIP_ALIGN_X", "ULD_CHIP_ALIGN_Y",
-    "ULD_CHIP_ALIGN_NUM", "ULD_ALARM",
-];
-
 // 許可された比較演算子のリスト
-const ALLOWED_COMPARISONS: &[&str] = &["=", ">", "<", ">=", "<=", "!=", "<>", "LIKE"];
-
-// INTEGER型のカラムのリスト
-const INTEGER_COLUMNS: &[&str] = &[
-    "MACHINE_ID", "SERIAL", "WANO", "WAX", "WAY",
-    "LD_TRAY_POCKET_X", "LD_TRAY_POCKET_Y", "LD_TRAY_ALIGN_X", "LD_TRAY_ALIGN_Y",
-    "LD_ARM1_COLLET", "LD_ALARM",
-    "DC1_PRE_ALIGN_X", "DC1_PRE_ALIGN_Y", "DC1_PRE_ALIGN_T", "DC1_ARM1_COLLET",
-    "DC1_STAGE_COUNT", "DC1_PROBE_COUNT", "DC1_PROBE_X1", "DC1_PROBE_Y1",
-    "DC1_PROBE_X2", "DC1_PROBE_Y2", "DC1_STAGE_Z", "DC1_PIN_Z",
-    "DC1_CHIP_ALIGN_X", "DC1_CHIP_ALIGN_Y", "DC1_CHIP_ALIGN_T",
-    "DC1_TEST_BIN", "DC1_ARM2_COLLET", "DC1_ALARM",
-    "AC1_ARM1_COLLET", "AC1_STAGE_COUNT", "AC1_PROBE_COUNT",
-    "AC1_PROBE_X1", "AC1_PROBE_Y1", "AC1_PROBE_X2", "AC1_PROBE_Y2",
-    "AC1_STAGE_Z", "AC1_PIN_Z", "AC1_CHIP_ALIGN_X", "AC1_CHIP_ALIGN_Y",
-    "AC1_CHIP_ALIGN_T", "AC1_TEST_BIN", "AC1_ARM2_COLLET", "AC1_ALARM",
-    "AC2_ARM1_COLLET", "AC2_STAGE_COUNT", "AC2_PROBE_COUNT",
-    "AC2_PROBE_X1", "AC2_PROBE_Y1", "AC2_PROBE_X2", "AC2_PROBE_Y2",
-    "AC2_STAGE_Z", "AC2_PIN_Z", "AC2_CHIP_ALIGN_X", "AC2_CHIP_ALIGN_Y",
-    "AC2_CHIP_ALIGN_T", "AC2_TEST_BIN", "AC2_ARM2_COLLET", "AC2_ALARM",
-    "DC2_ARM1_COLLET", "DC2_STAGE_COUNT", "DC2_PROBE_COUNT",
-    "DC2_PROBE_X1", "DC2_PROBE_Y1", "DC2_PROBE_X2", "DC2_PROBE_Y2",
-    "DC2_STAGE_Z", "DC2_PIN_Z", "DC2_CHIP_ALIGN_X", "DC2_CHIP_ALIGN_Y",
-    "DC2_CHIP_ALIGN_T", "DC2_TEST_BIN", "DC2_ARM2_COLLET", "DC2_ALARM",
-    "IP_ARM1_COLLET", "IP_STAGE_COUNT", "IP_SURF_BIN", "IP_ARM2_COLLET",
-    "IP_BACK_BIN", "IP_ALARM",
-    "ULD_PRE_ALIGN_X", "ULD_PRE_ALIGN_Y", "ULD_PRE_ALIGN_T",
-    "ULD_POCKET_X", "ULD_POCKET_Y", "ULD_POCKET_ALIGN_X", "ULD_POCKET_ALIGN_Y",
-    "ULD_ARM1_COLLET", "ULD_CHIP_ALIGN_X", "ULD_CHIP_ALIGN_Y",
-    "ULD_CHIP_ALIGN_NUM", "ULD_ALARM",
+// PREFIXは前方一致(LIKE '値%')
+const ALLOWED_COMPARISONS: &[&str] = &[
+    "=", ">", "<", ">=", "<=", "!=", "<>",
+    "LIKE", "NOT LIKE", "PREFIX",
+    "IN", "NOT IN", "BETWEEN", "NOT BETWEEN",
+    "IS NULL", "IS NOT NULL",
 ];
 
-// TIMESTAMP型のカラムのリスト
-const TIMESTAMP_COLUMNS: &[&str] = &["LD_PICKUP_DATE", "ULD_PUT_DATE"];
+// IN/NOT INで指定できる値の最大数
+const MAX_FILTER_VALUES: usize = 1000;
+
+// 相関行列に指定できるカラムの最大数
+const MAX_CORRELATION_COLUMNS: usize = 64;
+
+// エクスポートで指定できるカラムの最大数
+const MAX_EXPORT_COLUMNS: usize = 512;
+
+// Spearmanで指定できるカラムの最大数(ペアごとに順位を付け直すため少なくする)
+const MAX_SPEARMAN_COLUMNS: usize = 16;
 
 // カラム名が安全かどうかチェック
 fn validate_column_name(column: &str) -> Result<String, String> {
-    let upper_column = column.to_uppercase();
-    if ALLOWED_COLUMNS.contains(&upper_column.as_str()) {
-        Ok(upper_column)
+    if column_type(column).is_some() {
+        Ok(column.to_uppercase())
     } else {
         Err(format!("Invalid column name: {}", column))
     }
 }
 
+// グラフの軸に使うカラムが数値型(allow_timestampの場合は日付型も可)かどうかチェック
+fn validate_plot_column(column: &str, allow_timestamp: bool) -> Result<String, String> {
+    let upper_column = validate_column_name(column)?;
+    match column_type(&upper_column) {
+        Some(t) if t.is_numeric() => Ok(upper_column),
+        Some(ColumnType::Timestamp) if allow_timestamp => Ok(upper_column),
+        _ => Err(format!("Column is not plottable as a number: {}", column)),
+    }
+}
+
 // カラムの型に応じたキャストを取得
 fn get_column_cast(column: &str) -> &str {
-    let upper_column = column.to_uppercase();
-    if INTEGER_COLUMNS.contains(&upper_column.as_str()) {
-        "::integer"
-    } else if TIMESTAMP_COLUMNS.contains(&upper_column.as_str()) {
-        "::timestamp"
-    } else {
-        "" // VARCHAR型などはキャスト不要
+    column_type(column).map(|t| t.cast()).unwrap_or("")
+}
+
+// シフトの開始時刻(時)とシフトの長さ(時間)
+// シフトの長さは24の約数のみ有効(それ以外は既定値を使用)
+static SHIFT_START_HOUR: Lazy<i64> = Lazy::new(|| {
+    env::var("SHIFT_START_HOUR").ok().and_then(|v| v.parse().ok()).filter(|h| (0..24).contains(h)).unwrap_or(8)
+});
+static SHIFT_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("SHIFT_HOURS").ok().and_then(|v| v.parse().ok()).filter(|h| *h > 0 && 24 % *h == 0).unwrap_or(12)
+});
+
+// 指定した日時が含まれるシフトの開始日時(time_bucket_exprのShiftと同じ区切り)
+pub fn shift_start(at: &NaiveDateTime) -> NaiveDateTime {
+    let start = *SHIFT_START_HOUR * 3600;
+    let length = *SHIFT_HOURS * 3600;
+    let seconds = at.and_utc().timestamp();
+    let shift_seconds = (seconds - start).div_euclid(length) * length + start;
+    chrono::DateTime::from_timestamp(shift_seconds, 0).map(|d| d.naive_utc()).unwrap_or(*at)
+}
+
+// LD_PICKUP_DATEを時間単位(Minute/Hour/Shift/Day/Week)で切り捨てるSQL式を返す
+pub fn time_bucket_expr(bucket: &str) -> Result<String, String> {
+    match bucket {
+        "Minute" => Ok("date_trunc('minute', LD_PICKUP_DATE)".to_string()),
+        "Hour" => Ok("date_trunc('hour', LD_PICKUP_DATE)".to_string()),
+        "Day" => Ok("date_trunc('day', LD_PICKUP_DATE)".to_string()),
+        "Week" => Ok("date_trunc('week', LD_PICKUP_DATE)".to_string()), // 月曜始まり
+        "Shift" => {
+            // シフト開始時刻を基準にシフトの長さで切り捨てる
+            let start = *SHIFT_START_HOUR * 3600;
+            let length = *SHIFT_HOURS * 3600;
+            Ok(format!(
+                "(timestamp 'epoch' + (floor((extract(epoch FROM LD_PICKUP_DATE) - {start}) / {length}) * {length} + {start}) * interval '1 second')",
+                start = start, length = length
+            ))
+        },
+        _ => Err(format!("Invalid time bucket: {}", bucket)),
     }
 }
 
 // 比較演算子が安全かどうかチェック
 fn validate_comparison(comparison: &str) -> Result<String, String> {
-    let upper_comparison = comparison.to_uppercase();
+    let upper_comparison = comparison.split_whitespace().collect::<Vec<_>>().join(" ").to_uppercase();
     if ALLOWED_COMPARISONS.contains(&upper_comparison.as_str()) {
         Ok(upper_comparison)
     } else {
@@ -114,6 +99,44 @@ fn validate_comparison(comparison: &str) -> Result<String, String> {
     }
 }
 
+// ヒストグラム・密度プロット用のSELECT句(FROM句まで)
+// SQL側で集計するため列名をunit_name, x_value, y_valueに統一する(ユニット分割しない場合のunit_nameは'data')
+fn binned_columns_sql(graph_condition: &GraphCondition, with_y: bool) -> Result<String, String> {
+    let unit_name = if graph_condition.plot_unit != "None" {
+        format!("{}::text", validate_column_name(&graph_condition.plot_unit)?)
+    } else {
+        "'data'".to_string()
+    };
+    let mut sql = format!(
+        "{} AS unit_name, {}::double precision AS x_value",
+        unit_name, validate_plot_column(&graph_condition.graph_x_item, false)?
+    );
+    if with_y {
+        sql += &format!(", {}::double precision AS y_value", validate_plot_column(&graph_condition.graph_y_item, false)?);
+    }
+    sql += " FROM chipdata";
+    Ok(sql)
+}
+
+// 密度プロットのグリッドの分割数をチェック
+fn validate_grid_condition(graph_condition: &GraphCondition) -> Result<(), String> {
+    for (name, bins) in [("bins_x", graph_condition.bins_x), ("bins_y", graph_condition.bins_y)] {
+        if bins == 0 || bins as usize > MAX_BIN_NUMBER {
+            return Err(format!("{} must be 1 to {}: {}", name, MAX_BIN_NUMBER, bins));
+        }
+    }
+    Ok(())
+}
+
+// アラームの表示方法のバリデーション(Count or Ratio)
+fn validate_alarm_overlay(overlay: &str) -> Result<(), String> {
+    if overlay.eq_ignore_ascii_case("Count") || overlay.eq_ignore_ascii_case("Ratio") {
+        Ok(())
+    } else {
+        Err(format!("Invalid alarm overlay: {} (Count or Ratio)", overlay))
+    }
+}
+
 // グラフ条件から適切なSQL文を作成（パラメータ化バージョン）
 // 戻り値: (SQL文, バインドするパラメータのベクタ)
 pub fn create_sql(graph_condition: &GraphCondition) -> Result<(String, Vec<String>), String> {
@@ -121,17 +144,28 @@ pub fn create_sql(graph_condition: &GraphCondition) -> Result<(String, Vec<Strin
     let mut params: Vec<String> = Vec::new();
 
     // カラム名のバリデーション
-    let x_item = validate_column_name(&graph_condition.graph_x_item)?;
-    let y_item = validate_column_name(&graph_condition.graph_y_item)?;
+    // 散布図のx軸のみ日付型を許可する(LinePlot,ControlChartのx軸はLD_PICKUP_DATE固定のため使用しない)
+    let x_item = match graph_condition.graph_type.as_str() {
+        "ScatterPlot" => select_expr(&validate_plot_column(&graph_condition.graph_x_item, true)?),
+        "DensityPlot" | "Histogram" => select_expr(&validate_plot_column(&graph_condition.graph_x_item, false)?),
+        _ => validate_column_name(&graph_condition.graph_x_item)?,
+    };
+    let y_item = if graph_condition.graph_type == "Histogram" {
+        validate_column_name(&graph_condition.graph_y_item)? // ヒストグラムではy軸は使用しない
+    } else {
+        select_expr(&validate_plot_column(&graph_condition.graph_y_item, false)?)
+    };
     let plot_unit = if graph_condition.plot_unit != "None" {
-        Some(validate_column_name(&graph_condition.plot_unit)?)
+        Some(select_expr(&validate_column_name(&graph_condition.plot_unit)?))
     } else {
         None
     };
+    if !graph_condition.alarm.codes.is_empty() {
+        validate_alarm_overlay(&graph_condition.alarm.overlay)?;
+    }
 
     // X, Yデータ取得
     if graph_condition.graph_type=="ScatterPlot"{
-        println!("{:?}",graph_condition.alarm.codes);
         //プロット単位をまとめるかどうかで決める
         if graph_condition.alarm.codes.is_empty(){ //アラームプロットを重ねない場合
             if let Some(ref unit) = plot_unit {
@@ -147,19 +181,24 @@ pub fn create_sql(graph_condition: &GraphCondition) -> Result<(String, Vec<Strin
                 sql += &format!("{}, {}, {} FROM chipdata", x_item, y_item, alarm_column);
             }
         }
-    }else if  graph_condition.graph_type=="DensityPlot"{
-        if let Some(ref unit) = plot_unit {
-            sql += &format!("{}, {}, {} FROM chipdata", unit, x_item, y_item);
-        } else {
-            sql += &format!("{}, {} FROM chipdata", x_item, y_item);
-        }
-    }else if graph_condition.graph_type=="Histogram"{
-        //プロット単位をまとめるかどうかで決める
-        if let Some(ref unit) = plot_unit {
-            sql += &format!("{}, {} FROM chipdata", unit, x_item);
+    }else if  graph_condition.graph_type=="DensityPlot"{ //密度プロットはSQL側でグリッドごとに集計する
+        validate_grid_condition(graph_condition)?;
+        sql += &binned_columns_sql(graph_condition, true)?;
+    }else if graph_condition.graph_type=="Histogram"{ //ヒストグラムはSQL側でビンごとに集計する
+        validate_bin_condition(graph_condition)?;
+        sql += &binned_columns_sql(graph_condition, false)?;
+    }else if graph_condition.graph_type=="LinePlot" && graph_condition.line_bucket != "None"{ //集計する時系列プロットの場合は時間単位(とユニット)ごとに統計量を取る
+        let bucket_expr = time_bucket_expr(&graph_condition.line_bucket)?;
+        let y_column = validate_column_name(&graph_condition.graph_y_item)?;
+        let unit_name = if plot_unit.is_some() {
+            format!("{}::text", validate_column_name(&graph_condition.plot_unit)?)
         } else {
-            sql += &format!("{} FROM chipdata", x_item);
-        }
+            "'data'".to_string()
+        };
+        sql += &format!(
+            "{u} AS unit_name, {b} AS bucket_start, AVG({y})::double precision, MIN({y})::double precision, MAX({y})::double precision, STDDEV_SAMP({y})::double precision, COUNT({y}) FROM chipdata",
+            u = unit_name, b = bucket_expr, y = y_column
+        );
     }else if graph_condition.graph_type=="LinePlot"{ //時系列プロットの場合はx軸は必ずLD_PICKUP_DATEをとり、アラームが設定されていればそれも取る
         if graph_condition.alarm.codes.is_empty(){ //アラームプロットを重ねない場合
             if let Some(ref unit) = plot_unit {
@@ -175,44 +214,66 @@ pub fn create_sql(graph_condition: &GraphCondition) -> Result<(String, Vec<Strin
                 sql += &format!("LD_PICKUP_DATE, {}, {} FROM chipdata", y_item, alarm_column);
             }
         }
+    }else if graph_condition.graph_type=="ControlChart"{ //管理図の場合は群ごとに集計する(個々のチップ単位の場合は時系列順に取得する)
+        if plot_unit.is_some() {
+            return Err("ControlChart does not support plot_unit".to_string());
+        }
+        let y_column = validate_column_name(&graph_condition.graph_y_item)?;
+        match graph_condition.control_subgroup.as_str() {
+            "Chip" => {
+                sql += &format!("LD_PICKUP_DATE, {} FROM chipdata", y_item);
+            },
+            "Lot" => {
+                sql += &format!("LOT_NAME, MIN(LD_PICKUP_DATE), AVG({y})::double precision, (MAX({y}) - MIN({y}))::double precision, COUNT({y}), STDDEV_SAMP({y})::double precision FROM chipdata", y = y_column);
+            },
+            bucket => {
+                let bucket_expr = time_bucket_expr(bucket)?;
+                sql += &format!("{b}::text, MIN(LD_PICKUP_DATE), AVG({y})::double precision, (MAX({y}) - MIN({y}))::double precision, COUNT({y}), STDDEV_SAMP({y})::double precision FROM chipdata", b = bucket_expr, y = y_column);
+            },
+        }
+    }else if graph_condition.graph_type=="BoxPlot"{ //箱ひげ図の場合はユニット名と値を統一した型で取得する(集計はplot側でSQLを包んで行う)
+        let y_column = validate_column_name(&graph_condition.graph_y_item)?;
+        if plot_unit.is_some() {
+            let unit_column = validate_column_name(&graph_condition.plot_unit)?;
+            sql += &format!("{}::text AS unit_name, {}::double precision AS value FROM chipdata", unit_column, y_column);
+        } else {
+            sql += &format!("'data' AS unit_name, {}::double precision AS value FROM chipdata", y_column);
+        }
+    }else{
+        return Err(format!("Unsupported graph type: {}", graph_condition.graph_type));
     }
 
     // フィルター情報追加
-    let mut param_index = 1;
-    if !graph_condition.filters.is_empty() {
-        sql += " WHERE ";
-        for (index, filter) in graph_condition.filters.iter().enumerate() {
-            let item = validate_column_name(&filter.item)?;
-            let comparison = validate_comparison(&filter.comparison)?;
-            let cast = get_column_cast(&item);
-
-            if comparison == "LIKE" {
-                sql += &format!("{} LIKE ${}", item, param_index);
-                params.push(format!("%{}%", filter.value));
-            } else {
-                sql += &format!("{} {} ${}{}", item, comparison, param_index, cast);
-                params.push(filter.value.clone());
-            }
-            param_index += 1;
-
-            if index + 1 < graph_condition.filters.len() {
-                // filter_conjunction も検証
-                let conjunction = graph_condition.filter_conjunction.to_uppercase();
-                if conjunction != "AND" && conjunction != "OR" {
-                    return Err("Invalid filter conjunction".to_string());
-                }
-                sql += &format!(" {} ", conjunction);
-            }
+    // フィルター式全体を括弧でくくり、日付範囲は常に式全体にかかるようにする
+    sql += " WHERE ";
+    if let Some(filter_sql) = create_filter_sql(graph_condition, &mut params)? {
+        sql += &format!("({}) AND ", filter_sql);
+    }
+
+    //パーティション情報追加
+    sql += &format!("ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", params.len() + 1, params.len() + 2);
+    params.push(graph_condition.start_date.clone());
+    params.push(graph_condition.end_date.clone());
+
+    //管理図は群ごとに集計し、時系列順に並べる
+    if graph_condition.graph_type == "ControlChart" {
+        let y_column = validate_column_name(&graph_condition.graph_y_item)?;
+        if graph_condition.control_subgroup == "Chip" {
+            sql += &format!(" AND {} IS NOT NULL ORDER BY LD_PICKUP_DATE ASC", y_column);
+        } else {
+            sql += &format!(" AND {} IS NOT NULL GROUP BY 1 ORDER BY 2 ASC", y_column);
         }
-        //パーティション情報追加
-        sql += &format!(" AND ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", param_index, param_index + 1);
-        params.push(graph_condition.start_date.clone());
-        params.push(graph_condition.end_date.clone());
-    } else {
-        //パーティション情報追加
-        sql += &format!(" WHERE ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", param_index, param_index + 1);
-        params.push(graph_condition.start_date.clone());
-        params.push(graph_condition.end_date.clone());
+    }
+
+    //集計する時系列プロットはユニットと時間単位ごとにまとめ、時系列順に並べる
+    if graph_condition.graph_type == "LinePlot" && graph_condition.line_bucket != "None" {
+        let y_column = validate_column_name(&graph_condition.graph_y_item)?;
+        sql += &format!(" AND {} IS NOT NULL GROUP BY 1, 2 ORDER BY 2 ASC", y_column);
+    }
+
+    //箱ひげ図はNULLを除外する
+    if graph_condition.graph_type == "BoxPlot" {
+        sql += &format!(" AND {} IS NOT NULL", validate_column_name(&graph_condition.graph_y_item)?);
     }
 
     debug!("Generated SQL: {}", sql);
@@ -227,94 +288,36 @@ pub fn create_alarm_sql(graph_condition: &GraphCondition) -> Result<(String, Vec
     let mut params: Vec<String> = Vec::new();
 
     // カラム名のバリデーション
-    let x_item = validate_column_name(&graph_condition.graph_x_item)?;
-    let y_item = validate_column_name(&graph_condition.graph_y_item)?;
-    let plot_unit = if graph_condition.plot_unit != "None" {
-        Some(validate_column_name(&graph_condition.plot_unit)?)
-    } else {
-        None
-    };
-
-    // プロットデータ取得用のSQLを定義
-    if graph_condition.graph_type == "LinePlot" {
-        //LinePlotの場合はx軸は必ずLD_PICKUP_DATE
-        if let Some(ref unit) = plot_unit {
-            sql += &format!("{}, LD_PICKUP_DATE, {} FROM chipdata", unit, y_item);
-        } else {
-            sql += &format!("LD_PICKUP_DATE, {} FROM chipdata", y_item);
-        }
-    }else if graph_condition.graph_type=="ScatterPlot" || graph_condition.graph_type=="DensityPlot" {
-        //プロット単位をまとめるかどうかで決める
-        if let Some(ref unit) = plot_unit {
-            sql += &format!("{}, {}, {} FROM chipdata", unit, x_item, y_item);
-        } else {
-            sql += &format!("{}, {} FROM chipdata", x_item, y_item);
-        }
+    // プロットデータ取得用のSQLを定義(アラームだけを別に集計するのはヒストグラム・密度プロットのみ)
+    if graph_condition.graph_type=="DensityPlot"{
+        sql += &binned_columns_sql(graph_condition, true)?;
     }else if graph_condition.graph_type=="Histogram"{
-        //プロット単位をまとめるかどうかで決める
-        if let Some(ref unit) = plot_unit {
-            sql += &format!("{}, {} FROM chipdata", unit, x_item);
-        } else {
-            sql += &format!("{} FROM chipdata", x_item);
-        }
+        sql += &binned_columns_sql(graph_condition, false)?;
+    }else{
+        return Err(format!("Unsupported graph type: {}", graph_condition.graph_type));
     }
 
     //アラームフィルター追加
     sql += " WHERE ";
     let alarm_column = validate_column_name(&format!("{}_ALARM", graph_condition.alarm.unit))?;
 
-    let mut param_index = 1;
     // 複数のアラームコードがある場合はOR条件で結合
     if !graph_condition.alarm.codes.is_empty() {
-        if graph_condition.alarm.codes.len() == 1 {
-            sql += &format!("{} = ${}::integer", alarm_column, param_index);
-            params.push(graph_condition.alarm.codes[0].to_string());
-            param_index += 1;
-        } else {
-            sql += "(";
-            for (idx, alarm_code) in graph_condition.alarm.codes.iter().enumerate() {
-                sql += &format!("{} = ${}::integer", alarm_column, param_index);
-                params.push(alarm_code.to_string());
-                param_index += 1;
-
-                if idx + 1 < graph_condition.alarm.codes.len() {
-                    sql += " OR ";
-                }
-            }
-            sql += ")";
+        let mut alarm_conditions = Vec::new();
+        for alarm_code in graph_condition.alarm.codes.iter() {
+            alarm_conditions.push(format!("{} = ${}::integer", alarm_column, params.len() + 1));
+            params.push(alarm_code.to_string());
         }
+        sql += &format!("({}) AND ", alarm_conditions.join(" OR "));
     }
 
     // フィルター情報追加
-    if !graph_condition.filters.is_empty() {
-        sql += " AND ";
-        for (index, filter) in graph_condition.filters.iter().enumerate() {
-            let item = validate_column_name(&filter.item)?;
-            let comparison = validate_comparison(&filter.comparison)?;
-            let cast = get_column_cast(&item);
-
-            if comparison == "LIKE" {
-                sql += &format!("{} LIKE ${}", item, param_index);
-                params.push(format!("%{}%", filter.value));
-            } else {
-                sql += &format!("{} {} ${}{}", item, comparison, param_index, cast);
-                params.push(filter.value.clone());
-            }
-            param_index += 1;
-
-            if index + 1 < graph_condition.filters.len() {
-                // filter_conjunction も検証
-                let conjunction = graph_condition.filter_conjunction.to_uppercase();
-                if conjunction != "AND" && conjunction != "OR" {
-                    return Err("Invalid filter conjunction".to_string());
-                }
-                sql += &format!(" {} ", conjunction);
-            }
-        }
+    if let Some(filter_sql) = create_filter_sql(graph_condition, &mut params)? {
+        sql += &format!("({}) AND ", filter_sql);
     }
 
     //パーティション情報追加
-    sql += &format!(" AND ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", param_index, param_index + 1);
+    sql += &format!("ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", params.len() + 1, params.len() + 2);
     params.push(graph_condition.start_date.clone());
     params.push(graph_condition.end_date.clone());
 
@@ -323,3 +326,581 @@ pub fn create_alarm_sql(graph_condition: &GraphCondition) -> Result<(String, Vec
 
     Ok((sql, params))
 }
+
+// フィルター式の最大ネスト数
+const MAX_FILTER_DEPTH: usize = 16;
+
+// 系列ごとの記述統計量を取得するSQL文を作成（パラメータ化バージョン）
+// 対象の値はヒストグラムではx軸、それ以外はy軸
+// 散布図・密度プロットでx軸が数値の場合は相関係数と回帰直線も計算する(スピアマンは平均順位のピアソン相関)
+pub fn create_statistics_sql(graph_condition: &GraphCondition) -> Result<(String, Vec<String>), String> {
+    let mut params: Vec<String> = Vec::new();
+
+    let value_column = if graph_condition.graph_type == "Histogram" {
+        validate_column_name(&graph_condition.graph_x_item)?
+    } else {
+        validate_column_name(&graph_condition.graph_y_item)?
+    };
+    let x_column = if matches!(graph_condition.graph_type.as_str(), "ScatterPlot" | "DensityPlot")
+        && column_type(&graph_condition.graph_x_item).map(|t| t.is_numeric()).unwrap_or(false) {
+        Some(validate_column_name(&graph_condition.graph_x_item)?)
+    } else {
+        None
+    };
+    let unit_name = if graph_condition.plot_unit != "None" {
+        format!("{}::text", validate_column_name(&graph_condition.plot_unit)?)
+    } else {
+        "'data'".to_string()
+    };
+
+    let mut base_sql = format!("SELECT {} AS unit_name, {}::double precision AS value", unit_name, value_column);
+    if let Some(ref x) = x_column {
+        base_sql += &format!(", {}::double precision AS x_value", x);
+    }
+    base_sql += " FROM chipdata WHERE ";
+
+    // フィルター情報追加
+    if let Some(filter_sql) = create_filter_sql(graph_condition, &mut params)? {
+        base_sql += &format!("({}) AND ", filter_sql);
+    }
+
+    //パーティション情報追加
+    base_sql += &format!("ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", params.len() + 1, params.len() + 2);
+    params.push(graph_condition.start_date.clone());
+    params.push(graph_condition.end_date.clone());
+
+    let stats_columns = "s.unit_name, s.cnt, s.null_cnt, s.mean_value, s.std_value, s.min_value, s.max_value, s.percentiles";
+    let stats_sql = "SELECT unit_name, COUNT(value) AS cnt, COUNT(*) - COUNT(value) AS null_cnt,
+            AVG(value) AS mean_value, STDDEV_SAMP(value) AS std_value, MIN(value) AS min_value, MAX(value) AS max_value,
+            percentile_cont(ARRAY[0.01, 0.05, 0.25, 0.5, 0.75, 0.95, 0.99]) WITHIN GROUP (ORDER BY value) AS percentiles";
+    let sql = if x_column.is_some() {
+        format!(
+            "WITH base AS ({base}),
+            stats AS ({stats}, regr_count(value, x_value) AS pair_cnt, corr(x_value, value) AS pearson,
+                regr_slope(value, x_value) AS slope, regr_intercept(value, x_value) AS intercept, regr_r2(value, x_value) AS r2
+                FROM base GROUP BY unit_name),
+            ranked AS (
+                SELECT unit_name,
+                    RANK() OVER (PARTITION BY unit_name ORDER BY x_value) + (COUNT(*) OVER (PARTITION BY unit_name, x_value) - 1) / 2.0 AS x_rank,
+                    RANK() OVER (PARTITION BY unit_name ORDER BY value) + (COUNT(*) OVER (PARTITION BY unit_name, value) - 1) / 2.0 AS y_rank
+                FROM base WHERE value IS NOT NULL AND x_value IS NOT NULL
+            ),
+            spearman AS (SELECT unit_name, corr(x_rank, y_rank) AS spearman FROM ranked GROUP BY unit_name)
+            SELECT {columns}, s.pair_cnt, s.pearson, sp.spearman, s.slope, s.intercept, s.r2
+            FROM stats s LEFT JOIN spearman sp ON sp.unit_name IS NOT DISTINCT FROM s.unit_name
+            ORDER BY s.unit_name",
+            base = base_sql, stats = stats_sql, columns = stats_columns
+        )
+    } else {
+        format!(
+            "WITH base AS ({base}),
+            stats AS ({stats} FROM base GROUP BY unit_name)
+            SELECT {columns} FROM stats s ORDER BY s.unit_name",
+            base = base_sql, stats = stats_sql, columns = stats_columns
+        )
+    };
+
+    debug!("Generated Statistics SQL: {}", sql);
+    debug!("Statistics SQL Params: {:?}", params);
+
+    Ok((sql, params))
+}
+
+// ロールアップテーブルで集計しているディメンション(ユニット分割・フィルターに使えるカラム)
+const ROLLUP_DIMENSIONS: &[&str] = &["MACHINE_ID", "TYPE_NAME", "LOT_NAME"];
+
+// ロールアップテーブルの集計値を、元のカラム名(LD_PICKUP_DATE等)で参照できるようにするFROM句
+// 集計済みの時間の範囲[start_hour, end_hour)はロールアップから、期間の端の1時間に満たない部分は生データ(1チップ1行)から取得する
+// LD_PICKUP_DATEは時間の開始になるため、1時間以上の時間単位でのみ集計できる
+fn rollup_values_from(graph_condition: &GraphCondition, start_hour: &NaiveDateTime, end_hour: &NaiveDateTime, params: &mut Vec<String>) -> Result<String, String> {
+    let column = validate_plot_column(&graph_condition.graph_y_item, false)?;
+    let index = params.len() + 1;
+    let sql = format!(
+        "(SELECT hour_start AS LD_PICKUP_DATE, MACHINE_ID, TYPE_NAME, LOT_NAME, chip_count, value_count, value_sum, value_sum_sq, value_min, value_max
+            FROM chipdata_hourly_values WHERE column_name = ${name} AND hour_start >= ${start_hour}::timestamp AND hour_start < ${end_hour}::timestamp
+        UNION ALL
+        SELECT LD_PICKUP_DATE, MACHINE_ID, TYPE_NAME, LOT_NAME, 1::bigint, ({c} IS NOT NULL)::integer::bigint, {c}::numeric, {c}::numeric * {c}::numeric, {c}::double precision, {c}::double precision
+            FROM chipdata WHERE ld_pickup_date BETWEEN ${start}::timestamp AND ${end}::timestamp
+            AND NOT (ld_pickup_date >= ${start_hour}::timestamp AND ld_pickup_date < ${end_hour}::timestamp)) AS chipdata",
+        c = column, name = index, start_hour = index + 1, end_hour = index + 2, start = index + 3, end = index + 4
+    );
+    params.push(column);
+    params.push(start_hour.to_string());
+    params.push(end_hour.to_string());
+    params.push(graph_condition.start_date.clone());
+    params.push(graph_condition.end_date.clone());
+    Ok(sql)
+}
+
+// ロールアップの合計値から平均・標準偏差(標本)を計算するSQL式
+const ROLLUP_MEAN_EXPR: &str = "(SUM(value_sum) / NULLIF(SUM(value_count), 0))::double precision";
+const ROLLUP_STD_EXPR: &str = "(CASE WHEN SUM(value_count) > 1 THEN sqrt(GREATEST((SUM(value_sum_sq) - SUM(value_sum) * SUM(value_sum) / SUM(value_count)) / (SUM(value_count) - 1), 0)) END)::double precision";
+
+// 集計する折れ線グラフの条件がロールアップテーブルで答えられるかどうか(期間が集計済みかどうかは別にチェックする)
+// 時間単位が1時間以上で、ユニット分割・フィルターがロールアップのディメンションのみの場合
+pub fn is_rollup_line_condition(graph_condition: &GraphCondition) -> bool {
+    let is_dimension = |column: &str| ROLLUP_DIMENSIONS.contains(&column.to_uppercase().as_str());
+    let mut filter_items = Vec::new();
+    match graph_condition.filter_expr.as_ref() {
+        Some(filter_expr) => collect_filter_items(filter_expr, &mut filter_items),
+        None => filter_items.extend(graph_condition.filters.iter().map(|f| f.item.as_str())),
+    }
+
+    graph_condition.graph_type == "LinePlot"
+        && matches!(graph_condition.line_bucket.as_str(), "Hour" | "Shift" | "Day" | "Week")
+        && (graph_condition.plot_unit == "None" || is_dimension(&graph_condition.plot_unit))
+        && filter_items.iter().all(|item| is_dimension(item))
+}
+
+// フィルター式で使っているカラム名を集める
+fn collect_filter_items<'a>(node: &'a FilterNode, items: &mut Vec<&'a str>) {
+    match node {
+        FilterNode::Condition(filter) => items.push(&filter.item),
+        FilterNode::Group { children, .. } => children.iter().for_each(|child| collect_filter_items(child, items)),
+        FilterNode::Not { child } => collect_filter_items(child, items),
+    }
+}
+
+// ロールアップテーブルから集計する折れ線グラフのSQL文を作成する(列の並びはcreate_sqlの集計する折れ線グラフと同じ)
+// start_hour, end_hour: 集計済みの時間の範囲[start_hour, end_hour)(期間の残りは生データから集計する)
+pub fn create_rollup_line_sql(graph_condition: &GraphCondition, start_hour: &NaiveDateTime, end_hour: &NaiveDateTime) -> Result<(String, Vec<String>), String> {
+    let mut params = Vec::new();
+    let bucket_expr = time_bucket_expr(&graph_condition.line_bucket)?;
+    let mut sql = format!(
+        "SELECT {u} AS unit_name, {b} AS bucket_start, {mean}, MIN(value_min)::double precision, MAX(value_max)::double precision, {std}, SUM(value_count)::bigint FROM {from} WHERE value_count > 0",
+        u = rollup_unit_name(graph_condition)?, b = bucket_expr, mean = ROLLUP_MEAN_EXPR, std = ROLLUP_STD_EXPR,
+        from = rollup_values_from(graph_condition, start_hour, end_hour, &mut params)?
+    );
+    if let Some(filter_sql) = create_filter_sql(graph_condition, &mut params)? {
+        sql += &format!(" AND ({})", filter_sql);
+    }
+    sql += " GROUP BY 1, 2 ORDER BY 2 ASC";
+
+    debug!("Generated Rollup SQL: {}", sql);
+    debug!("Rollup SQL Params: {:?}", params);
+
+    Ok((sql, params))
+}
+
+// ロールアップテーブルから記述統計量を取得するSQL文を作成する(列の並びはcreate_statistics_sqlと同じ、パーセンタイルはNULL)
+pub fn create_rollup_statistics_sql(graph_condition: &GraphCondition, start_hour: &NaiveDateTime, end_hour: &NaiveDateTime) -> Result<(String, Vec<String>), String> {
+    let mut params = Vec::new();
+    let mut sql = format!(
+        "SELECT {u} AS unit_name, SUM(value_count)::bigint, SUM(chip_count - value_count)::bigint, {mean}, {std}, MIN(value_min)::double precision, MAX(value_max)::double precision, NULL::double precision[] FROM {from}",
+        u = rollup_unit_name(graph_condition)?, mean = ROLLUP_MEAN_EXPR, std = ROLLUP_STD_EXPR,
+        from = rollup_values_from(graph_condition, start_hour, end_hour, &mut params)?
+    );
+    if let Some(filter_sql) = create_filter_sql(graph_condition, &mut params)? {
+        sql += &format!(" WHERE {}", filter_sql);
+    }
+    sql += " GROUP BY 1";
+
+    debug!("Generated Rollup Statistics SQL: {}", sql);
+    debug!("Rollup Statistics SQL Params: {:?}", params);
+
+    Ok((sql, params))
+}
+
+// ロールアップテーブルのunit_nameのSQL式(ユニット分割しない場合は'data')
+fn rollup_unit_name(graph_condition: &GraphCondition) -> Result<String, String> {
+    if graph_condition.plot_unit != "None" {
+        Ok(format!("{}::text", validate_column_name(&graph_condition.plot_unit)?))
+    } else {
+        Ok("'data'".to_string())
+    }
+}
+
+// 相関行列を取得するSQL文を作成（パラメータ化バージョン）
+// 1回のスキャンで各カラムの件数と全ペアの相関係数・件数を集計する
+// 列の並び: COUNT(c0)..COUNT(cn), ばらつきの有無(MAX > MIN)c0..cn, 以降はi<jのペアごとに (相関係数, 件数)
+// Spearmanはペアごとに両方の値がある行だけで平均順位を付け、その順位のピアソン相関を取る
+pub fn create_correlation_sql(condition: &CorrelationCondition) -> Result<(String, Vec<String>), String> {
+    let mut params: Vec<String> = Vec::new();
+
+    if condition.method != "Pearson" && condition.method != "Spearman" {
+        return Err(format!("Invalid correlation method: {}", condition.method));
+    }
+    let max_columns = if condition.method == "Spearman" { MAX_SPEARMAN_COLUMNS } else { MAX_CORRELATION_COLUMNS };
+    if condition.columns.len() < 2 || condition.columns.len() > max_columns {
+        return Err(format!("columns must have 2 to {} items for {}", max_columns, condition.method));
+    }
+    let mut columns: Vec<String> = Vec::new();
+    for column in condition.columns.iter() {
+        let column = validate_plot_column(column, false)?;
+        if columns.contains(&column) {
+            return Err(format!("Duplicate column: {}", column));
+        }
+        columns.push(column);
+    }
+
+    let mut base_sql = format!(
+        "SELECT {} FROM chipdata WHERE ",
+        columns.iter().enumerate().map(|(i, c)| format!("{}::double precision AS v{}", c, i)).collect::<Vec<_>>().join(", ")
+    );
+
+    // フィルター情報追加
+    if let Some(filter_sql) = compile_filters(condition.filter_expr.as_ref(), &condition.filters, &condition.filter_conjunction, &mut params)? {
+        base_sql += &format!("({}) AND ", filter_sql);
+    }
+
+    //パーティション情報追加
+    base_sql += &format!("ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp", params.len() + 1, params.len() + 2);
+    params.push(condition.start_date.clone());
+    params.push(condition.end_date.clone());
+
+    let mut select_columns: Vec<String> = (0..columns.len()).map(|i| format!("COUNT(v{})", i)).collect();
+    select_columns.extend((0..columns.len()).map(|i| format!("MAX(v{i}) > MIN(v{i})", i = i)));
+    let sql = if condition.method == "Spearman" {
+        //ペアごとに完全なケース(両方NULLでない行)で順位を付けるCTEを作り、相関係数と件数を参照する
+        //同順位は平均順位(RANK + (同じ値の件数 - 1) / 2)にする
+        let rank_expr = |v: &str| format!("RANK() OVER (ORDER BY {v}) + (COUNT(*) OVER (PARTITION BY {v}) - 1) / 2.0", v = v);
+        let mut pair_ctes = Vec::new();
+        for i in 0..columns.len() {
+            for j in (i + 1)..columns.len() {
+                let (vi, vj) = (format!("v{}", i), format!("v{}", j));
+                pair_ctes.push(format!(
+                    "p{i}_{j} AS (SELECT corr(x_rank::double precision, y_rank::double precision) AS r, COUNT(*) AS n FROM (SELECT {x} AS x_rank, {y} AS y_rank FROM base WHERE {vi} IS NOT NULL AND {vj} IS NOT NULL) ranked)",
+                    i = i, j = j, x = rank_expr(&vi), y = rank_expr(&vj), vi = vi, vj = vj
+                ));
+                select_columns.push(format!("(SELECT r FROM p{i}_{j}), (SELECT n FROM p{i}_{j})", i = i, j = j));
+            }
+        }
+        format!(
+            "WITH base AS MATERIALIZED ({}), {} SELECT {} FROM base",
+            base_sql, pair_ctes.join(", "), select_columns.join(", ")
+        )
+    } else {
+        for i in 0..columns.len() {
+            for j in (i + 1)..columns.len() {
+                select_columns.push(format!("corr(v{i}, v{j}), regr_count(v{i}, v{j})", i = i, j = j));
+            }
+        }
+        format!("WITH base AS ({}) SELECT {} FROM base", base_sql, select_columns.join(", "))
+    };
+
+    debug!("Generated Correlation SQL: {}", sql);
+    debug!("Correlation SQL Params: {:?}", params);
+
+    Ok((sql, params))
+}
+
+// エクスポートのSQL文を作成（パラメータ化バージョン）
+// 型によらずCSVに書けるように全てのカラムをtextで取得し、LD_PICKUP_DATE順に並べる
+pub fn create_export_sql(condition: &ExportCondition) -> Result<(String, Vec<String>), String> {
+    let mut params: Vec<String> = Vec::new();
+
+    if condition.columns.is_empty() || condition.columns.len() > MAX_EXPORT_COLUMNS {
+        return Err(format!("columns must have 1 to {} items", MAX_EXPORT_COLUMNS));
+    }
+    let mut columns: Vec<String> = Vec::new();
+    for column in condition.columns.iter() {
+        let column = validate_column_name(column)?;
+        if columns.contains(&column) {
+            return Err(format!("Duplicate column: {}", column));
+        }
+        columns.push(column);
+    }
+
+    let mut sql = format!(
+        "SELECT {} FROM chipdata WHERE ",
+        columns.iter().map(|c| format!("{}::text", c)).collect::<Vec<_>>().join(", ")
+    );
+
+    // フィルター情報追加
+    if let Some(filter_sql) = compile_filters(condition.filter_expr.as_ref(), &condition.filters, &condition.filter_conjunction, &mut params)? {
+        sql += &format!("({}) AND ", filter_sql);
+    }
+
+    //パーティション情報追加
+    sql += &format!("ld_pickup_date BETWEEN ${}::timestamp AND ${}::timestamp ORDER BY ld_pickup_date", params.len() + 1, params.len() + 2);
+    params.push(condition.start_date.clone());
+    params.push(condition.end_date.clone());
+
+    debug!("Generated Export SQL: {}", sql);
+    debug!("Export SQL Params: {:?}", params);
+
+    Ok((sql, params))
+}
+
+// グラフ条件のフィルターからWHERE句の条件式を作成する
+// filter_exprがあればそれを使い、なければ従来のfilters/filter_conjunctionを1つのグループとして扱う
+// フィルターが無い場合はNoneを返す
+pub fn create_filter_sql(graph_condition: &GraphCondition, params: &mut Vec<String>) -> Result<Option<String>, String> {
+    compile_filters(graph_condition.filter_expr.as_ref(), &graph_condition.filters, &graph_condition.filter_conjunction, params)
+}
+
+// フィルター式(またはfilters/filter_conjunction)からWHERE句の条件式を作成する
+fn compile_filters(filter_expr: Option<&FilterNode>, filters: &[Filter], filter_conjunction: &str, params: &mut Vec<String>) -> Result<Option<String>, String> {
+    if let Some(filter_expr) = filter_expr {
+        return compile_filter_node(filter_expr, params, 0).map(Some);
+    }
+
+    if filters.is_empty() {
+        return Ok(None);
+    }
+
+    let mut conditions = Vec::new();
+    for filter in filters.iter() {
+        conditions.push(compile_filter_condition(filter, params)?);
+    }
+    let conjunction = validate_conjunction(filter_conjunction)?;
+    Ok(Some(conditions.join(&format!(" {} ", conjunction))))
+}
+
+// フィルター式のノードをSQLの条件式に変換する(グループとNOTは括弧でくくる)
+fn compile_filter_node(node: &FilterNode, params: &mut Vec<String>, depth: usize) -> Result<String, String> {
+    if depth > MAX_FILTER_DEPTH {
+        return Err(format!("Filter expression is nested too deeply (max {})", MAX_FILTER_DEPTH));
+    }
+
+    match node {
+        FilterNode::Condition(filter) => compile_filter_condition(filter, params),
+        FilterNode::Group { conjunction, children } => {
+            let conjunction = validate_conjunction(conjunction)?;
+            if children.is_empty() {
+                // 空のグループは条件なしとして扱う
+                return Ok("TRUE".to_string());
+            }
+            let mut conditions = Vec::new();
+            for child in children {
+                conditions.push(compile_filter_node(child, params, depth + 1)?);
+            }
+            Ok(format!("({})", conditions.join(&format!(" {} ", conjunction))))
+        },
+        FilterNode::Not { child } => {
+            Ok(format!("NOT ({})", compile_filter_node(child, params, depth + 1)?))
+        },
+    }
+}
+
+// 単一のフィルター条件をSQLの条件式に変換する
+fn compile_filter_condition(filter: &Filter, params: &mut Vec<String>) -> Result<String, String> {
+    let item = validate_column_name(&filter.item)?;
+    let comparison = validate_comparison(&filter.comparison)?;
+    let item_type = column_type(&item).ok_or(format!("Invalid column name: {}", filter.item))?;
+    let cast = get_column_cast(&item);
+
+    let condition = match comparison.as_str() {
+        "IS NULL" | "IS NOT NULL" => format!("{} {}", item, comparison),
+        "LIKE" | "NOT LIKE" => {
+            // 部分一致(数値カラムも文字列として比較する)
+            params.push(format!("%{}%", filter.value));
+            format!("{}::text {} ${}", item, comparison, params.len())
+        },
+        "PREFIX" => {
+            // 前方一致(値に含まれるワイルドカード文字はエスケープする)
+            params.push(format!("{}%", escape_like(&filter.value)));
+            format!("{}::text LIKE ${}", item, params.len())
+        },
+        "IN" | "NOT IN" => {
+            let values = filter_values(filter, item_type)?;
+            if values.is_empty() || values.len() > MAX_FILTER_VALUES {
+                return Err(format!("{} requires 1 to {} values: {}", comparison, MAX_FILTER_VALUES, filter.item));
+            }
+            let mut placeholders = Vec::new();
+            for value in values {
+                params.push(value);
+                placeholders.push(format!("${}{}", params.len(), cast));
+            }
+            format!("{} {} ({})", item, comparison, placeholders.join(", "))
+        },
+        "BETWEEN" | "NOT BETWEEN" => {
+            let values = filter_values(filter, item_type)?;
+            if values.len() != 2 {
+                return Err(format!("{} requires exactly 2 values: {}", comparison, filter.item));
+            }
+            let mut values = values.into_iter();
+            params.push(values.next().unwrap());
+            let lower = format!("${}{}", params.len(), cast);
+            params.push(values.next().unwrap());
+            let upper = format!("${}{}", params.len(), cast);
+            format!("{} {} {} AND {}", item, comparison, lower, upper)
+        },
+        _ => {
+            params.push(validate_filter_value(&filter.value, item_type, &filter.item)?);
+            format!("{} {} ${}{}", item, comparison, params.len(), cast)
+        },
+    };
+
+    Ok(condition)
+}
+
+// 複数値を取るフィルターの値一覧を取得し、カラムの型に合うか検証する
+// valuesが空の場合はvalueをカンマ区切りで分割して使用する
+fn filter_values(filter: &Filter, item_type: ColumnType) -> Result<Vec<String>, String> {
+    let raw_values: Vec<String> = if filter.values.is_empty() {
+        filter.value.split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    } else {
+        filter.values.iter()
+            .map(|v| match v {
+                FilterValue::Number(n) => n.to_string(),
+                FilterValue::Text(s) => s.clone(),
+            })
+            .collect()
+    };
+
+    raw_values.iter()
+        .map(|v| validate_filter_value(v, item_type, &filter.item))
+        .collect()
+}
+
+// フィルターの値がカラムの型に合うか検証し、バインド用の文字列を返す
+fn validate_filter_value(value: &str, item_type: ColumnType, item: &str) -> Result<String, String> {
+    let value = value.trim();
+    let is_valid = match item_type {
+        ColumnType::Integer => value.parse::<i64>().is_ok(),
+        ColumnType::Real | ColumnType::Numeric => value.parse::<f64>().map(|v| v.is_finite()).unwrap_or(false),
+        ColumnType::Timestamp => NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").is_ok()
+            || NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+        ColumnType::Text => return Ok(value.to_string()),
+    };
+
+    if is_valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("Invalid value for {} ({:?}): {}", item, item_type, value))
+    }
+}
+
+// LIKEのワイルドカード文字をエスケープする
+fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// フィルターの接続方法(AND/OR)が安全かどうかチェック
+fn validate_conjunction(conjunction: &str) -> Result<String, String> {
+    let upper_conjunction = conjunction.to_uppercase();
+    if upper_conjunction == "AND" || upper_conjunction == "OR" {
+        Ok(upper_conjunction)
+    } else {
+        Err("Invalid filter conjunction".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::columns::load_test_columns;
+    use crate::graph::plotdata::scatter_series_name;
+
+    fn condition(item: &str, comparison: &str, value: &str) -> FilterNode {
+        FilterNode::Condition(Filter{
+            item: item.to_string(),
+            value: value.to_string(),
+            comparison: comparison.to_string(),
+            values: vec![],
+        })
+    }
+
+    fn group(conjunction: &str, children: Vec<FilterNode>) -> FilterNode {
+        FilterNode::Group{conjunction: conjunction.to_string(), children}
+    }
+
+    fn compile(node: &FilterNode) -> Result<(String, Vec<String>), String> {
+        load_test_columns();
+        let mut params = Vec::new();
+        compile_filter_node(node, &mut params, 0).map(|sql| (sql, params))
+    }
+
+    #[test]
+    fn nested_groups_keep_precedence() {
+        let node = group("AND", vec![
+            condition("MACHINE_ID", "=", "3"),
+            group("or", vec![
+                condition("LOT_NAME", "=", "A"),
+                FilterNode::Not{child: Box::new(condition("DC1_STAGE_Z", ">", "1.5"))},
+            ]),
+        ]);
+        let (sql, params) = compile(&node).unwrap();
+        assert_eq!(sql, "(MACHINE_ID = $1::integer AND (LOT_NAME = $2 OR NOT (DC1_STAGE_Z > $3::double precision)))");
+        assert_eq!(params, vec!["3", "A", "1.5"]);
+    }
+
+    #[test]
+    fn empty_group_is_true() {
+        assert_eq!(compile(&group("AND", vec![])).unwrap().0, "TRUE");
+    }
+
+    #[test]
+    fn depth_limit() {
+        let mut node = condition("MACHINE_ID", "=", "1");
+        for _ in 0..MAX_FILTER_DEPTH {
+            node = FilterNode::Not{child: Box::new(node)};
+        }
+        assert!(compile(&node).is_ok());
+        let node = FilterNode::Not{child: Box::new(node)};
+        assert!(compile(&node).unwrap_err().contains("nested too deeply"));
+    }
+
+    #[test]
+    fn between_requires_two_typed_values() {
+        let (sql, params) = compile(&condition("DC1_STAGE_Z", "not  between", "1, 2.5")).unwrap();
+        assert_eq!(sql, "DC1_STAGE_Z NOT BETWEEN $1::double precision AND $2::double precision");
+        assert_eq!(params, vec!["1", "2.5"]);
+        assert!(compile(&condition("DC1_STAGE_Z", "BETWEEN", "1")).is_err());
+        assert!(compile(&condition("DC1_STAGE_Z", "BETWEEN", "1,2,3")).is_err());
+        assert!(compile(&condition("MACHINE_ID", "BETWEEN", "1,x")).is_err());
+        assert!(compile(&condition("DC1_STAGE_Z", "BETWEEN", "1,NaN")).is_err());
+    }
+
+    #[test]
+    fn prefix_escapes_wildcards() {
+        let (sql, params) = compile(&condition("LOT_NAME", "PREFIX", r"A_1%\")).unwrap();
+        assert_eq!(sql, "LOT_NAME::text LIKE $1");
+        assert_eq!(params, vec![r"A\_1\%\\%"]);
+    }
+
+    #[test]
+    fn density_grid_requires_bins() {
+        let mut graph_condition: GraphCondition = serde_json::from_value(serde_json::json!({
+            "graph_type": "DensityPlot", "graph_x_item": "DC1_STAGE_Z", "graph_y_item": "DC1_OFFSET",
+            "start_date": "2026-10-01 00:00:00", "end_date": "2026-10-02 00:00:00",
+            "bin_number": 10, "bins_x": 0, "bins_y": 5, "plot_unit": "None",
+            "alarm": {"unit": "DC1", "codes": []},
+        })).unwrap();
+        assert!(validate_grid_condition(&graph_condition).is_err());
+        graph_condition.bins_x = MAX_BIN_NUMBER as u32 + 1;
+        assert!(validate_grid_condition(&graph_condition).is_err());
+        graph_condition.bins_x = 5;
+        assert!(validate_grid_condition(&graph_condition).is_ok());
+    }
+
+    #[test]
+    fn scatter_alarm_column_and_series_names() {
+        load_test_columns();
+        let mut graph_condition: GraphCondition = serde_json::from_value(serde_json::json!({
+            "graph_type": "ScatterPlot", "graph_x_item": "LD_PICKUP_DATE", "graph_y_item": "DC1_STAGE_Z",
+            "start_date": "2026-10-01 00:00:00", "end_date": "2026-10-02 00:00:00",
+            "bin_number": 10, "bins_x": 5, "bins_y": 5, "plot_unit": "MACHINE_ID",
+            "alarm": {"unit": "DC1", "codes": [401]},
+        })).unwrap();
+        //ユニットあり: ユニット, x, y, アラームカラムの順(plot_scatterplot_with_unitが読む列)
+        let (sql, _) = create_sql(&graph_condition).unwrap();
+        assert!(sql.starts_with("SELECT MACHINE_ID"), "{}", sql);
+        assert!(sql.contains(", DC1_ALARM FROM chipdata"), "{}", sql);
+        assert_eq!(scatter_series_name(Some("3"), false), "3");
+        assert_eq!(scatter_series_name(Some("3"), true), "alarm_3");
+
+        //ユニットなし: x, y, アラームカラムの順
+        graph_condition.plot_unit = "None".to_string();
+        let (sql, _) = create_sql(&graph_condition).unwrap();
+        assert!(sql.contains(", DC1_ALARM FROM chipdata"), "{}", sql);
+        assert_eq!(scatter_series_name(None, false), "data");
+        assert_eq!(scatter_series_name(None, true), "alarm_data");
+
+        //アラームを重ねない場合はアラームカラムを取得しない
+        graph_condition.alarm.codes.clear();
+        let (sql, _) = create_sql(&graph_condition).unwrap();
+        assert!(!sql.contains("DC1_ALARM"), "{}", sql);
+    }
+
+    #[test]
+    fn rejects_unknown_column_operator_and_conjunction() {
+        assert!(compile(&condition("MACHINE_ID; DROP TABLE chipdata", "=", "1")).is_err());
+        assert!(compile(&condition("MACHINE_ID", "= 1 OR 1 =", "1")).is_err());
+        assert!(compile(&group("XOR", vec![condition("MACHINE_ID", "=", "1")])).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+/* グラフと一緒に返す記述統計量を取得する */
+use futures_util::TryStreamExt;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::graph::variants::*;
+use crate::graph::sql::create_statistics_sql;
+
+//系列名(ユニット名 or data)→記述統計量
+//集計はSQL側で行い、生データは取得しない
+pub async fn get_statistics(pool:&PgPool,graph_condition:&GraphCondition)->Result<HashMap<String,SeriesStatistics>,Box<dyn Error>>{
+    let (sql, params) = create_statistics_sql(graph_condition)
+        .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+    query_statistics(pool, &sql, &params).await
+}
+
+//記述統計量のSQL(create_statistics_sqlと同じ列の並び)を実行して系列ごとにまとめる
+//数値の列は全てdouble precision(件数はbigint)で、型が合わない場合はエラーにする
+pub async fn query_statistics(pool:&PgPool,sql:&str,params:&[String])->Result<HashMap<String,SeriesStatistics>,Box<dyn Error>>{
+    let mut query = sqlx::query(sql);
+    for param in params {
+        query = query.bind(param);
+    }
+    let mut rows_data = query.fetch(pool);
+
+    let mut statistics = HashMap::new();
+    while let Some(row) = rows_data.try_next().await? {
+        let unit_name: String = match row.try_get::<Option<String>, _>(0)? {
+            Some(s) => s,
+            None => continue, // ユニット名がNULLの行はグラフにも出ないのでスキップ
+        };
+        let percentiles: Vec<f64> = row.try_get::<Option<Vec<f64>>, _>(7)?.unwrap_or_default();
+        let percentile = |i: usize| percentiles.get(i).copied();
+
+        //相関の列は散布図・密度プロットでx軸が数値の場合のみある
+        let correlation = if row.len() > 8 {
+            Some(CorrelationStatistics{
+                pair_count: row.try_get(8)?,
+                pearson: row.try_get(9)?,
+                spearman: row.try_get(10)?,
+                slope: row.try_get(11)?,
+                intercept: row.try_get(12)?,
+                r2: row.try_get(13)?,
+            })
+        } else {
+            None
+        };
+
+        statistics.insert(unit_name, SeriesStatistics{
+            count: row.try_get(1)?,
+            null_count: row.try_get(2)?,
+            mean: row.try_get(3)?,
+            std: row.try_get(4)?,
+            min: row.try_get(5)?,
+            max: row.try_get(6)?,
+            p1: percentile(0),
+            p5: percentile(1),
+            p25: percentile(2),
+            p50: percentile(3),
+            p75: percentile(4),
+            p95: percentile(5),
+            p99: percentile(6),
+            correlation,
+        });
+    }
+
+    Ok(statistics)
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize,Serialize};
+use std::collections::HashMap;
 
 /*グラフ作成条件*/
-#[derive(Debug,Deserialize)]
+#[derive(Debug,Serialize,Deserialize)]
 pub struct GraphCondition{ //グラフ描画に必要な情報を全て入れる構造体
     pub graph_type:String,          //グラフ種類
     pub graph_x_item:String,        //x軸の項目
@@ -13,47 +14,192 @@ pub struct GraphCondition{ //グラフ描画に必要な情報を全て入れる
     pub bins_y:u32,              //密度プロットのY軸軸分割数
     pub plot_unit:String,           //plotの分割設定
     pub alarm:AlarmInfo,            //alarm関係の情報
-    pub filters:Vec<Filter>,        //filter一覧
-    pub filter_conjunction:String   //filterの接続方法AND or OR
+    #[serde(default)]
+    pub filters:Vec<Filter>,        //filter一覧(filter_exprが無い場合に使用)
+    #[serde(default="default_filter_conjunction")]
+    pub filter_conjunction:String,  //filterの接続方法AND or OR
+    #[serde(default)]
+    pub filter_expr:Option<FilterNode>, //入れ子にできるフィルター式(指定時はfiltersより優先)
+    #[serde(default="default_control_subgroup")]
+    pub control_subgroup:String,    //管理図の群 Chip(I-MR) or Lot/Hour/Shift/Day/Week(Xbar-R or Xbar-S)
+    #[serde(default)]
+    pub max_points:Option<usize>,   //折れ線グラフの系列ごとの最大点数(指定時は間引く)
+    #[serde(default="default_downsample_method")]
+    pub downsample_method:String,   //間引き方法 LTTB or MinMax
+    #[serde(default="default_line_bucket")]
+    pub line_bucket:String,         //折れ線グラフの集計単位 None(生データ) or Minute/Hour/Shift/Day/Week
+    #[serde(default)]
+    pub line_overlays:Vec<LineOverlay>, //折れ線グラフに重ねる計算系列
+    #[serde(default="default_bin_rule")]
+    pub bin_rule:String,            //ヒストグラムのビン数の決め方 Fixed(bin_number) or Sturges/Scott/FreedmanDiaconis
+    #[serde(default)]
+    pub bin_edges:Vec<f64>,         //ヒストグラムのビンの境界値(指定時はbin_rule,bin_rangeより優先)
+    #[serde(default)]
+    pub bin_range:Option<[f64;2]>,  //ヒストグラムの範囲[下限,上限](期間が違うヒストグラムのビンを揃える場合に使用)
+    #[serde(default="default_histogram_outliers")]
+    pub histogram_outliers:String,  //範囲外の値の扱い Exclude or Clip
+    #[serde(default)]
+    pub with_statistics:bool,       //系列ごとの記述統計量を返すかどうか(期間全体を集計するクエリが増えるため指定時のみ)
 }
 
-#[derive(Debug,Deserialize)]
+fn default_bin_rule()->String{
+    "Fixed".to_string()
+}
+
+fn default_histogram_outliers()->String{
+    "Exclude".to_string()
+}
+
+fn default_line_bucket()->String{
+    "None".to_string()
+}
+
+fn default_downsample_method()->String{
+    "LTTB".to_string()
+}
+
+fn default_control_subgroup()->String{
+    "Chip".to_string()
+}
+
+fn default_filter_conjunction()->String{
+    "AND".to_string()
+}
+
+//フィルター式のノード
+//例: {"type":"Group","conjunction":"OR","children":[{"type":"Condition","item":"DC1_TEST_BIN","value":"1","comparison":"="},{"type":"Not","child":{...}}]}
+#[derive(Debug,Serialize,Deserialize)]
+#[serde(tag="type")]
+pub enum FilterNode{
+    Condition(Filter),                                      //単一のフィルター条件
+    Group{conjunction:String, children:Vec<FilterNode>},    //AND/ORで結合するグループ
+    Not{child:Box<FilterNode>},                             //否定
+}
+
+#[derive(Debug,Serialize,Deserialize)]
 pub struct Filter{ //各フィルターの内容を入れる構造体
     pub item:String,
+    #[serde(default)]
     pub value:String,
-    pub comparison:String
+    pub comparison:String,
+    #[serde(default)]
+    pub values:Vec<FilterValue>,    //IN/NOT IN/BETWEEN用の複数値(空の場合はvalueをカンマ区切りで使用)
 }
 
+#[derive(Debug,Serialize,Deserialize)]
+#[serde(untagged)]
+pub enum FilterValue{ //フィルターの値(数値または文字列)
+    Number(f64),
+    Text(String),
+}
+
+/*エクスポート(非同期ジョブ)の条件*/
+#[derive(Debug,Deserialize)]
+pub struct ExportCondition{
+    pub columns:Vec<String>,        //出力するカラム一覧(LD_PICKUP_DATE順に出力する)
+    pub start_date:String,          //データ取得開始日
+    pub end_date:String,            //データ取得終了日
+    #[serde(default)]
+    pub filters:Vec<Filter>,        //filter一覧(filter_exprが無い場合に使用)
+    #[serde(default="default_filter_conjunction")]
+    pub filter_conjunction:String,  //filterの接続方法AND or OR
+    #[serde(default)]
+    pub filter_expr:Option<FilterNode>, //入れ子にできるフィルター式(指定時はfiltersより優先)
+}
+
+/*相関行列の作成条件*/
 #[derive(Debug,Deserialize)]
+pub struct CorrelationCondition{
+    pub columns:Vec<String>,        //相関を取るカラム一覧(数値型のみ)
+    #[serde(default="default_correlation_method")]
+    pub method:String,              //Pearson or Spearman
+    pub start_date:String,          //データ取得開始日
+    pub end_date:String,            //データ取得終了日
+    #[serde(default)]
+    pub filters:Vec<Filter>,        //filter一覧(filter_exprが無い場合に使用)
+    #[serde(default="default_filter_conjunction")]
+    pub filter_conjunction:String,  //filterの接続方法AND or OR
+    #[serde(default)]
+    pub filter_expr:Option<FilterNode>, //入れ子にできるフィルター式(指定時はfiltersより優先)
+}
+
+fn default_correlation_method()->String{
+    "Pearson".to_string()
+}
+
+//相関行列のヒートマップの1セル
+#[derive(Debug,Serialize)]
+pub struct CorrelationCell{
+    pub x_data:usize,               //columnsのインデックス
+    pub y_data:usize,               //columnsのインデックス
+    pub x_item:String,
+    pub y_item:String,
+    pub z_data:Option<f64>,         //相関係数(件数が2未満 or ばらつきが無い場合はNone)
+    pub count:i64,                  //両方に値がある件数
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+pub struct LineOverlay{ //折れ線グラフに重ねる計算系列の設定
+    pub kind:String,                //RollingMean, RollingMedian, EWMA, SigmaBand
+    #[serde(default="default_overlay_window")]
+    pub window:usize,               //移動窓の点数(EWMAでalphaが無い場合は2/(window+1)をalphaとする)
+    #[serde(default)]
+    pub alpha:Option<f64>,          //EWMAの平滑化係数(0<alpha<=1)
+    #[serde(default="default_overlay_k")]
+    pub k:f64,                      //SigmaBandの幅(移動平均±k*移動標準偏差)
+}
+
+fn default_overlay_window()->usize{
+    20
+}
+
+fn default_overlay_k()->f64{
+    3.0
+}
+
+#[derive(Debug,Serialize,Deserialize)]
 pub struct AlarmInfo{ //アラームプロットを重ねる場合：アラームの内容を入れる構造体
     pub unit:String,
     pub codes:Vec<i32>,
+    #[serde(default="default_alarm_overlay")]
+    pub overlay:String,             //密度プロットのアラーム表示方法 Count(個数) or Ratio(比率)、それ以外はエラー
+}
+
+fn default_alarm_overlay()->String{
+    "Count".to_string()
 }
 /* ------------------------------------------- */
 
 /*プロットデータ型の定義 */
 #[derive(Debug,Serialize)]
 pub enum XdimData{
-    NumberData(Option<i32>),
+    NumberData(Option<f64>),
     DateData(Option<chrono::NaiveDateTime>)
 }
 
 #[derive(Debug,Serialize)]
 pub struct ScatterPlotData{
     pub x_data:XdimData, //日付等の文字列と通常数値両方取る可能性がある
-    pub y_data:Option<i32>,
+    pub y_data:Option<f64>,
     pub is_alarm:bool,
 }
 
 #[derive(Debug,Serialize)]
 pub struct LinePlotData{
-    pub y_data:Option<i32>,
+    pub x_data:Option<chrono::NaiveDateTime>,   //LD_PICKUP_DATE
+    pub y_data:Option<f64>,
     pub is_alarm:bool,
 }
 
+//時間単位で集計した折れ線グラフの1点
 #[derive(Debug,Serialize)]
-pub struct HistogramData{
-    pub x_data:Option<i32>,
+pub struct LineAggregateData{
+    pub x_data:Option<chrono::NaiveDateTime>,   //集計単位の開始時刻
+    pub mean:Option<f64>,
+    pub min:Option<f64>,
+    pub max:Option<f64>,
+    pub std:Option<f64>,        //標本標準偏差(1件のみの場合はNone)
+    pub count:i64,
 }
 
 #[derive(Debug,Serialize)]
@@ -64,8 +210,11 @@ pub struct BinnedHistogramData{
 
 #[derive(Debug,Serialize)]
 pub struct HistogramBinInfo{
-    pub bin_edges: Vec<i32>,  // ビンの境界値 [min, edge1, edge2, ..., max]
-    pub bin_width: f64,       // ビン幅
+    pub bin_edges: Vec<f64>,  // ビンの境界値 [min, edge1, edge2, ..., max]
+    pub bin_width: f64,       // ビン幅(bin_edges指定で不等幅の場合は平均の幅)
+    pub outliers: String,     // 範囲外の値の扱い Exclude or Clip
+    pub underflow_count: i32, // 下限未満の件数(全系列の合計)
+    pub overflow_count: i32,  // 上限超過の件数(全系列の合計)
 }
 
 #[derive(Debug,Serialize)]
@@ -75,28 +224,125 @@ pub struct HeatmapData{
     pub z_data:Option<i32>,
 }
 
+#[derive(Debug,Serialize)]
+pub struct HeatmapRatioData{
+    pub x_data:u32,
+    pub y_data:u32,
+    pub z_data:Option<f64>,   // グリッド内の全チップに対するアラームチップの比率
+}
+
+#[derive(Debug,Serialize)]
+pub struct ControlChartData{
+    pub label:String,                           // 群の名前(ロット名 or 時間帯の開始時刻 or チップの取得時刻)
+    pub x_data:Option<chrono::NaiveDateTime>,   // 群の最初のLD_PICKUP_DATE
+    pub value:f64,                              // 個々の値(I) or 群の平均値(Xbar)
+    pub range:Option<f64>,                      // 移動範囲(MR) or 群の範囲(R)。最初の点のMRはNone
+    pub std:Option<f64>,                        // 群の標準偏差(S)。I-MR or 群の大きさが1の場合はNone
+    pub count:i64,                              // 群の大きさ
+    pub out_of_control:bool,                    // 値が管理限界外かどうか
+    pub range_out_of_control:bool,              // 範囲が管理限界外かどうか
+}
+
+#[derive(Debug,Serialize)]
+pub struct BoxPlotData{
+    pub count:i64,
+    pub min:f64,
+    pub q1:f64,
+    pub median:f64,
+    pub q3:f64,
+    pub max:f64,
+    pub mean:f64,
+    pub whisker_low:f64,        // Q1-1.5IQR以上の最小値
+    pub whisker_high:f64,       // Q3+1.5IQR以下の最大値
+    pub outlier_count:i64,      // 外れ値の総数
+    pub outliers:Vec<f64>,      // 外れ値(昇順、最大MAX_BOXPLOT_OUTLIERS件)
+}
+
 #[derive(Debug,Serialize)]
 pub enum PlotData{
     Scatter(ScatterPlotData),
     Line(LinePlotData),
-    Histogram(HistogramData),
+    LineAggregate(LineAggregateData),
     BinnedHistogram(BinnedHistogramData),
     Heatmap(HeatmapData),
+    HeatmapRatio(HeatmapRatioData),
+    ControlChart(ControlChartData),
+    BoxPlot(BoxPlotData),
 }
 
 //ヒートマップ描画でフロントエンド側に返すべき情報
-#[derive(Debug,Serialize)]
+#[derive(Debug,Serialize,Default)]
 pub struct GridData{
     pub grid_x:f64,
     pub grid_y:f64,
-    pub x_min:i32,
-    pub y_min:i32,
+    pub x_min:f64,
+    pub y_min:f64,
     pub histogram_bin_info: Option<HistogramBinInfo>,  // ヒストグラムのビン情報
+    pub control_chart_info: Option<ControlChartInfo>,  // 管理図の管理限界・工程能力
+    pub downsample_info: Option<HashMap<String,DownsampleInfo>>,  // 折れ線グラフの系列ごとの間引き前後の点数
+    pub from_rollup: bool,  // ロールアップから取得した場合はtrue(記述統計量のパーセンタイルp1〜p99はnullになる)
 }
 
+//系列ごとの記述統計量(ヒストグラムはx軸、それ以外はy軸の値)
 #[derive(Debug,Serialize)]
-pub enum UnitType{
-    String(String),
-    Number(i32)
+pub struct SeriesStatistics{
+    pub count:i64,                  // 値がある件数
+    pub null_count:i64,             // 値がNULLで除外した件数
+    pub mean:Option<f64>,
+    pub std:Option<f64>,            // 標本標準偏差
+    pub min:Option<f64>,
+    pub max:Option<f64>,
+    pub p1:Option<f64>,
+    pub p5:Option<f64>,
+    pub p25:Option<f64>,
+    pub p50:Option<f64>,
+    pub p75:Option<f64>,
+    pub p95:Option<f64>,
+    pub p99:Option<f64>,
+    pub correlation:Option<CorrelationStatistics>,  // 散布図・密度プロット(x軸が数値)のみ
 }
 
+//x軸とy軸の相関と回帰直線(y = slope * x + intercept)
+#[derive(Debug,Serialize)]
+pub struct CorrelationStatistics{
+    pub pair_count:i64,             // x,yの両方に値がある件数
+    pub pearson:Option<f64>,
+    pub spearman:Option<f64>,
+    pub slope:Option<f64>,
+    pub intercept:Option<f64>,
+    pub r2:Option<f64>,
+}
+
+//間引き前後の点数
+#[derive(Debug,Serialize)]
+pub struct DownsampleInfo{
+    pub original_count:usize,       // 間引き前の点数
+    pub returned_count:usize,       // 返却する点数
+}
+
+//管理図の管理限界線と工程能力指数
+#[derive(Debug,Serialize)]
+pub struct ControlChartInfo{
+    pub chart_type:String,          // I-MR, Xbar-R or Xbar-S
+    pub subgroup_size:f64,          // 群の大きさ(Xbar-R, Xbar-Sは平均値)
+    pub center_line:f64,            // 中心線(X or Xbarの平均)
+    pub ucl:f64,                    // 上方管理限界
+    pub lcl:f64,                    // 下方管理限界
+    pub range_center_line:f64,      // MR, R or Sの中心線
+    pub range_ucl:f64,              // MR, R or Sの上方管理限界
+    pub range_lcl:f64,              // MR, R or Sの下方管理限界
+    pub sigma:f64,                  // 群内標準偏差の推定値(I-MR, Xbar-RはRbar/d2、Xbar-Sはプールした標準偏差/c4)
+    pub lsl:Option<f64>,            // 規格下限
+    pub usl:Option<f64>,            // 規格上限
+    pub target:Option<f64>,         // 規格中心
+    pub cp:Option<f64>,
+    pub cpk:Option<f64>,
+}
+
+//規格値(assets/spec_limits.json の各カラムの値)
+#[derive(Debug,Deserialize)]
+pub struct SpecLimit{
+    pub lsl:Option<f64>,
+    pub usl:Option<f64>,
+    pub target:Option<f64>,
+}
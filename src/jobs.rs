@@ -0,0 +1,487 @@
+/* 時間のかかる処理を非同期ジョブとして実行する */
+//ジョブを登録するとjob_idを返し、処理はワーカー数を上限にバックグラウンドで実行する
+//状態(段階・処理した行数)の確認・結果の取得・キャンセルはjob_idで行い、終了したジョブの結果はJOB_RESULT_TTL_SECSの間保持する
+//終了したジョブはJOB_MAX_FINISHED件・結果の合計JOB_MAX_RESULT_BYTESまでとし、超えた場合は古いものから削除する
+//エクスポートはCSVファイルをEXPORT_DIRに書き出し、結果にはファイルの情報のみを返す(ファイルは/jobs/downloadで取得する)
+//Parquetは書き出し用のクレートが使えないため未対応で、CSVのみ出力する
+use chrono::NaiveDateTime;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use futures_util::TryStreamExt;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+use tracing::{error, info, warn};
+
+use crate::alarmdata::get_alarmdata;
+use crate::graph::correlation::get_correlation_from_db;
+use crate::graph::graphdata::get_graphdata_from_db;
+use crate::graph::sql::create_export_sql;
+use crate::graph::variants::{CorrelationCondition, ExportCondition, GraphCondition};
+use crate::lotdata::get_lotdata;
+use crate::query_limits::{error_code, run_with_limits, JOB_LIMITS};
+use crate::variants::{LotData, MachineData};
+
+//同時に実行するジョブ数
+static JOB_WORKERS: Lazy<usize> = Lazy::new(|| {
+    env::var("JOB_WORKERS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(2)
+});
+
+//待機中・実行中のジョブ数の上限(超えた場合は登録しない)
+static JOB_MAX_PENDING: Lazy<usize> = Lazy::new(|| {
+    env::var("JOB_MAX_PENDING").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(100)
+});
+
+//終了したジョブ(結果・エラー)を保持する時間
+static JOB_RESULT_TTL_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("JOB_RESULT_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60 * 60)
+});
+
+//終了したジョブを保持する最大件数
+static JOB_MAX_FINISHED: Lazy<usize> = Lazy::new(|| {
+    env::var("JOB_MAX_FINISHED").ok().and_then(|v| v.parse().ok()).unwrap_or(100)
+});
+
+//終了したジョブの結果(json)の合計サイズの上限。1件でこれを超える結果は保持せずにエラーにする
+static JOB_MAX_RESULT_BYTES: Lazy<usize> = Lazy::new(|| {
+    env::var("JOB_MAX_RESULT_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(256 * 1024 * 1024)
+});
+
+//エクスポートのファイルを書き出すディレクトリ
+static EXPORT_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    env::var("EXPORT_DIR").map(PathBuf::from).unwrap_or_else(|_| env::temp_dir().join("server_backend_exports"))
+});
+
+//エクスポートの進捗(行数)を更新する間隔
+const EXPORT_PROGRESS_ROWS: u64 = 10_000;
+
+//結果が大きすぎる場合のエラーコード
+const RESULT_TOO_LARGE_ERROR_CODE: &str = "RESULT_TOO_LARGE";
+
+static JOB_SEMAPHORE: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(*JOB_WORKERS)));
+static JOBS: Lazy<Mutex<HashMap<String,Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_JOB_SEQ: AtomicU64 = AtomicU64::new(1);
+
+//ジョブとして実行する処理と条件(同期のエンドポイントと同じ条件を指定する)
+//例: {"kind":"Graph","request":{...GraphCondition...}}, {"kind":"Export","request":{...ExportCondition...}}
+#[derive(Debug,Deserialize)]
+#[serde(tag="kind", content="request")]
+pub enum JobRequest{
+    Graph(Box<GraphCondition>),
+    Correlation(CorrelationCondition),
+    Lot(LotData),
+    Alarm(MachineData),
+    Export(ExportCondition),
+}
+
+impl JobRequest{
+    fn kind(&self)->&'static str{
+        match self {
+            JobRequest::Graph(_) => "Graph",
+            JobRequest::Correlation(_) => "Correlation",
+            JobRequest::Lot(_) => "Lot",
+            JobRequest::Alarm(_) => "Alarm",
+            JobRequest::Export(_) => "Export",
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum JobState{
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState{
+    fn is_finished(&self)->bool{
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+//ジョブの処理の段階
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum JobStage{
+    Queued,     //実行待ち
+    Querying,   //DBから取得中
+    Writing,    //取得した結果を作成中(エクスポートはファイルに書き出し中)
+    Finished,   //終了(成功・失敗・キャンセル)
+}
+
+struct Job{
+    seq:u64,
+    kind:&'static str,
+    state:JobState,
+    stage:JobStage,
+    rows:Option<u64>,
+    result_bytes:usize,
+    submitted_at:NaiveDateTime,
+    started_at:Option<NaiveDateTime>,
+    finished_at:Option<NaiveDateTime>,
+    started:Option<Instant>,
+    finished:Option<Instant>,
+    message:Option<String>,
+    error_code:Option<&'static str>,
+    result:Option<Arc<serde_json::Value>>,
+    abort_handle:Option<AbortHandle>,
+}
+
+//ジョブの状態(結果は含まない)
+#[derive(Debug,Serialize)]
+pub struct JobStatus{
+    pub job_id:String,
+    pub kind:&'static str,
+    pub state:JobState,
+    pub stage:JobStage,
+    pub rows:Option<u64>,               //取得した行数(グラフは点数、エクスポートは書き出し済みの行数で処理中も更新する)
+    pub queue_position:Option<usize>,   //待機中の場合、先に待機しているジョブ数
+    pub elapsed_ms:Option<u64>,         //実行開始からの経過時間(終了した場合は実行時間)
+    pub submitted_at:NaiveDateTime,
+    pub started_at:Option<NaiveDateTime>,
+    pub finished_at:Option<NaiveDateTime>,
+    pub message:Option<String>,
+    pub error_code:Option<&'static str>,
+}
+
+fn now()->NaiveDateTime{
+    chrono::Local::now().naive_local()
+}
+
+//エクスポートのファイルのパス
+fn export_path(job_id:&str)->PathBuf{
+    EXPORT_DIR.join(format!("{}.csv", job_id))
+}
+
+//ジョブを削除する(エクスポートのファイルも削除する)
+fn remove_job(jobs:&mut HashMap<String,Job>,job_id:&str){
+    if let Some(job) = jobs.remove(job_id) && job.kind == "Export" {
+        remove_export_file(job_id);
+    }
+}
+
+fn remove_export_file(job_id:&str){
+    let path = export_path(job_id);
+    if let Err(e) = std::fs::remove_file(&path) && e.kind() != io::ErrorKind::NotFound {
+        warn!("Failed to remove export file {:?}: {}", path, e);
+    }
+}
+
+//保持時間を過ぎた終了済みのジョブを削除し、件数・結果の合計サイズの上限を超える場合は古いものから削除する
+fn purge_expired(jobs:&mut HashMap<String,Job>){
+    let ttl = Duration::from_secs(*JOB_RESULT_TTL_SECS);
+    let mut finished: Vec<(Instant, String, usize)> = jobs.iter()
+        .filter_map(|(job_id, job)| job.finished.map(|f| (f, job_id.clone(), job.result_bytes)))
+        .collect();
+    finished.sort();
+
+    let mut total_bytes: usize = finished.iter().map(|(_, _, bytes)| bytes).sum();
+    let mut count = finished.len();
+    for (finished_at, job_id, bytes) in finished {
+        if finished_at.elapsed() < ttl && count <= *JOB_MAX_FINISHED && total_bytes <= *JOB_MAX_RESULT_BYTES {
+            break;
+        }
+        remove_job(jobs, &job_id);
+        total_bytes -= bytes;
+        count -= 1;
+    }
+}
+
+//jsonにしたときのバイト数を数える
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter{
+    fn write(&mut self,buf:&[u8])->io::Result<usize>{
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self)->io::Result<()>{
+        Ok(())
+    }
+}
+
+fn json_bytes(value:&serde_json::Value)->usize{
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value).map(|_| counter.0).unwrap_or(0)
+}
+
+fn job_status(jobs:&HashMap<String,Job>,job_id:&str,job:&Job)->JobStatus{
+    let queue_position = (job.state == JobState::Queued).then(|| {
+        jobs.values().filter(|other| other.state == JobState::Queued && other.seq < job.seq).count()
+    });
+    let elapsed_ms = job.started.map(|s| job.finished.unwrap_or_else(Instant::now).duration_since(s).as_millis() as u64);
+    JobStatus{
+        job_id: job_id.to_string(),
+        kind: job.kind,
+        state: job.state,
+        stage: job.stage,
+        rows: job.rows,
+        queue_position,
+        elapsed_ms,
+        submitted_at: job.submitted_at,
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        message: job.message.clone(),
+        error_code: job.error_code,
+    }
+}
+
+//ジョブを登録して実行を予約し、job_idを返す
+pub fn submit_job(pool:&PgPool,request:JobRequest,alarm_json_path:&str)->Result<String,String>{
+    let mut jobs = JOBS.lock().unwrap();
+    purge_expired(&mut jobs);
+    let pending = jobs.values().filter(|job| !job.state.is_finished()).count();
+    if pending >= *JOB_MAX_PENDING {
+        return Err(format!("Too many pending jobs ({}). Try again later", pending));
+    }
+
+    let seq = NEXT_JOB_SEQ.fetch_add(1, Ordering::Relaxed);
+    let submitted_at = now();
+    let job_id = format!("{}-{}", submitted_at.format("%Y%m%d%H%M%S"), seq);
+    let kind = request.kind();
+
+    let pool = pool.clone();
+    let alarm_json_path = alarm_json_path.to_string();
+    let id = job_id.clone();
+    //spawnしたタスクは登録を終えてロックを離すまで実行されない(同じスレッドで実行する)
+    let handle = actix_web::rt::spawn(async move {
+        let _permit = JOB_SEMAPHORE.clone().acquire_owned().await;
+        if !set_running(&id) {
+            return;
+        }
+        let result = run_job(&pool, &id, request, &alarm_json_path).await;
+        set_finished(&id, result);
+    });
+
+    jobs.insert(job_id.clone(), Job{
+        seq, kind,
+        state: JobState::Queued,
+        stage: JobStage::Queued,
+        rows: None,
+        result_bytes: 0,
+        submitted_at,
+        started_at: None, finished_at: None,
+        started: None, finished: None,
+        message: None, error_code: None, result: None,
+        abort_handle: Some(handle.abort_handle()),
+    });
+    info!("Submitted {} job: {}", kind, job_id);
+    Ok(job_id)
+}
+
+//実行開始を記録する(キャンセル済みの場合はfalse)
+fn set_running(job_id:&str)->bool{
+    let mut jobs = JOBS.lock().unwrap();
+    match jobs.get_mut(job_id) {
+        Some(job) if job.state == JobState::Queued => {
+            job.state = JobState::Running;
+            job.stage = JobStage::Querying;
+            job.started_at = Some(now());
+            job.started = Some(Instant::now());
+            true
+        },
+        _ => false,
+    }
+}
+
+//処理の段階と行数を記録する(実行中のみ)
+fn set_progress(job_id:&str,stage:JobStage,rows:Option<u64>){
+    let mut jobs = JOBS.lock().unwrap();
+    if let Some(job) = jobs.get_mut(job_id) && job.state == JobState::Running {
+        job.stage = stage;
+        job.rows = rows;
+    }
+}
+
+//結果を記録する(実行中にキャンセルされた場合は何もしない)
+//結果が保持できる上限より大きい場合は失敗にする
+fn set_finished(job_id:&str,result:Result<(serde_json::Value,u64),Box<dyn Error>>){
+    let mut jobs = JOBS.lock().unwrap();
+    let Some(job) = jobs.get_mut(job_id) else {
+        //キャンセル後に削除された場合、書き出したファイルが残らないようにする
+        remove_export_file(job_id);
+        return;
+    };
+    if job.state != JobState::Running {
+        if job.kind == "Export" {
+            remove_export_file(job_id);
+        }
+        return;
+    }
+    job.finished_at = Some(now());
+    job.finished = Some(Instant::now());
+    job.stage = JobStage::Finished;
+    job.abort_handle = None;
+    match result {
+        Ok((value, rows)) => {
+            let bytes = json_bytes(&value);
+            job.rows = Some(rows);
+            if bytes > *JOB_MAX_RESULT_BYTES {
+                job.state = JobState::Failed;
+                job.message = Some(format!("Job result is too large to keep ({} bytes, limit {} bytes). Narrow the conditions or use an Export job", bytes, *JOB_MAX_RESULT_BYTES));
+                job.error_code = Some(RESULT_TOO_LARGE_ERROR_CODE);
+                error!("{} job result is too large: {}, {} bytes", job.kind, job_id, bytes);
+            } else {
+                job.state = JobState::Succeeded;
+                job.message = Some("success".to_string());
+                job.result = Some(Arc::new(value));
+                job.result_bytes = bytes;
+                info!("{} job succeeded: {}, {} rows, {} bytes", job.kind, job_id, rows, bytes);
+            }
+        },
+        Err(e) => {
+            job.state = JobState::Failed;
+            job.message = Some(format!("{}", e));
+            job.error_code = Some(error_code(&*e));
+            if job.kind == "Export" {
+                remove_export_file(job_id);
+            }
+            error!("{} job failed: {}, error: {}", job.kind, job_id, e);
+        },
+    }
+    purge_expired(&mut jobs);
+}
+
+//ジョブの処理を実行し、同期のエンドポイントと同じ形のjsonと行数を返す
+//制限時間はジョブ用(JOB_LIMITS)を使い、キャンセルされた場合は実行中のクエリもキャンセルされる
+async fn run_job(pool:&PgPool,job_id:&str,request:JobRequest,alarm_json_path:&str)->Result<(serde_json::Value,u64),Box<dyn Error>>{
+    let result = match request {
+        JobRequest::Graph(graph_condition) => {
+            let (graph_data, grid_data, statistics) = run_with_limits(pool, &JOB_LIMITS, get_graphdata_from_db(pool, &graph_condition)).await?;
+            let rows = graph_data.values().map(|rows| rows.len() as u64).sum();
+            set_progress(job_id, JobStage::Writing, Some(rows));
+            (serde_json::json!({"graph_data":graph_data, "grid_data":grid_data, "statistics":statistics}), rows)
+        },
+        JobRequest::Correlation(condition) => {
+            let (columns, correlation_data) = run_with_limits(pool, &JOB_LIMITS, get_correlation_from_db(pool, &condition)).await?;
+            let rows = correlation_data.len() as u64;
+            set_progress(job_id, JobStage::Writing, Some(rows));
+            (serde_json::json!({"method":condition.method, "columns":columns, "correlation_data":correlation_data}), rows)
+        },
+        JobRequest::Lot(data) => {
+            let lot_data = run_with_limits(pool, &JOB_LIMITS, get_lotdata(pool, &data.lot_name)).await?;
+            let rows = lot_data.len() as u64;
+            set_progress(job_id, JobStage::Writing, Some(rows));
+            (serde_json::json!({"lot_data":lot_data}), rows)
+        },
+        JobRequest::Alarm(data) => {
+            let alarm_data = run_with_limits(pool, &JOB_LIMITS, get_alarmdata(pool, alarm_json_path, data.machine_id, &data.start_date, &data.end_date)).await?;
+            let rows = alarm_data.len() as u64;
+            set_progress(job_id, JobStage::Writing, Some(rows));
+            (serde_json::json!({"alarm_data":alarm_data}), rows)
+        },
+        JobRequest::Export(condition) => {
+            let (rows, bytes) = run_with_limits(pool, &JOB_LIMITS, export_csv(pool, job_id, &condition)).await?;
+            (serde_json::json!({"file_name":format!("{}.csv", job_id), "columns":condition.columns, "rows":rows, "bytes":bytes}), rows)
+        },
+    };
+    Ok(result)
+}
+
+//CSVの1項目(区切り文字・引用符・改行を含む場合は引用符で囲む、NULLは空欄)
+fn csv_field(value:Option<&str>)->String{
+    match value {
+        Some(v) if v.contains([',', '"', '\n', '\r']) => format!("\"{}\"", v.replace('"', "\"\"")),
+        Some(v) => v.to_string(),
+        None => String::new(),
+    }
+}
+
+//エクスポートの条件に合う行をCSVファイルに書き出す(行は取得しながら書き出し、メモリに溜めない)
+//戻り値: (行数, ファイルのバイト数)
+async fn export_csv(pool:&PgPool,job_id:&str,condition:&ExportCondition)->Result<(u64,u64),Box<dyn Error>>{
+    let (sql, params) = create_export_sql(condition)
+        .map_err(|e| Box::new(io::Error::new(io::ErrorKind::InvalidInput, e)) as Box<dyn Error>)?;
+
+    tokio::fs::create_dir_all(&*EXPORT_DIR).await?;
+    let mut writer = tokio::io::BufWriter::new(tokio::fs::File::create(export_path(job_id)).await?);
+    let header = condition.columns.iter().map(|c| csv_field(Some(&c.to_uppercase()))).collect::<Vec<_>>().join(",");
+    writer.write_all(format!("{}\n", header).as_bytes()).await?;
+
+    let mut query = sqlx::query(&sql);
+    for param in params.iter() {
+        query = query.bind(param);
+    }
+    let mut rows_data = query.fetch(pool);
+    let mut rows: u64 = 0;
+    while let Some(row) = rows_data.try_next().await? {
+        let mut line = (0..row.len())
+            .map(|i| row.try_get::<Option<String>, _>(i).map(|v| csv_field(v.as_deref())))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(",");
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        rows += 1;
+        if rows.is_multiple_of(EXPORT_PROGRESS_ROWS) {
+            set_progress(job_id, JobStage::Writing, Some(rows));
+        }
+    }
+    writer.flush().await?;
+    let bytes = tokio::fs::metadata(export_path(job_id)).await?.len();
+    Ok((rows, bytes))
+}
+
+//成功したエクスポートのジョブのファイルのパスを返す
+pub fn get_export_file(job_id:&str)->Result<PathBuf,String>{
+    let mut jobs = JOBS.lock().unwrap();
+    purge_expired(&mut jobs);
+    let job = jobs.get(job_id).ok_or(format!("Job not found: {}", job_id))?;
+    if job.kind != "Export" {
+        return Err(format!("Job is not an Export job: {}", job_id));
+    }
+    if job.state != JobState::Succeeded {
+        return Err(job.message.clone().unwrap_or(format!("Job is not finished ({:?})", job.state)));
+    }
+    Ok(export_path(job_id))
+}
+
+//ジョブの状態を返す
+pub fn get_job_status(job_id:&str)->Result<JobStatus,String>{
+    let mut jobs = JOBS.lock().unwrap();
+    purge_expired(&mut jobs);
+    let job = jobs.get(job_id).ok_or(format!("Job not found: {}", job_id))?;
+    Ok(job_status(&jobs, job_id, job))
+}
+
+//ジョブの状態と結果を返す(成功していない場合、結果はNone)
+pub fn get_job_result(job_id:&str)->Result<(JobStatus,Option<Arc<serde_json::Value>>),String>{
+    let mut jobs = JOBS.lock().unwrap();
+    purge_expired(&mut jobs);
+    let job = jobs.get(job_id).ok_or(format!("Job not found: {}", job_id))?;
+    Ok((job_status(&jobs, job_id, job), job.result.clone()))
+}
+
+//待機中・実行中のジョブをキャンセルする(終了済みの場合は状態を返すのみ)
+pub fn cancel_job(job_id:&str)->Result<JobStatus,String>{
+    let mut jobs = JOBS.lock().unwrap();
+    purge_expired(&mut jobs);
+    let job = jobs.get_mut(job_id).ok_or(format!("Job not found: {}", job_id))?;
+    if !job.state.is_finished() {
+        if let Some(handle) = job.abort_handle.take() {
+            handle.abort();
+        }
+        job.state = JobState::Cancelled;
+        job.stage = JobStage::Finished;
+        job.message = Some("cancelled".to_string());
+        job.finished_at = Some(now());
+        job.finished = Some(Instant::now());
+        info!("Cancelled {} job: {}", job.kind, job_id);
+        if job.kind == "Export" {
+            remove_export_file(job_id);
+        }
+    }
+    let job = &jobs[job_id];
+    Ok(job_status(&jobs, job_id, job))
+}
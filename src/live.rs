@@ -0,0 +1,461 @@
+/* 号機ごとの稼働状況をServer-Sent Eventsで配信する */
+//CHIPDATAを(LD_PICKUP_DATE, 号機, ロット, シリアル)の透かし(watermark)より後でポーリングし、新しいチップを号機ごとにまとめて配信する
+//  snapshot: 接続時の号機ごとの状態
+//  chips: 新しいチップ(号機の状態と、チップごとのステーション別アラームコード)
+//  alarm: 新しいアラーム(アラームコードで絞り込み可能)
+//透かしより前のチップ(LD_PICKUP_DATEが同じで号機・ロット・シリアルが小さいものを含む)が後から登録された場合は配信しない
+use actix_web::web::Bytes;
+use chrono::NaiveDateTime;
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+
+use crate::alarmdata::load_alarm_detail;
+use crate::variants::{AlarmDetail, ALARM_STATIONS};
+
+//ライブ配信を使うかどうか
+static LIVE_ENABLED: Lazy<bool> = Lazy::new(|| {
+    env::var("LIVE_ENABLED").map(|v| !matches!(v.to_lowercase().as_str(), "false" | "0" | "off")).unwrap_or(true)
+});
+
+//CHIPDATAをポーリングする間隔
+static LIVE_POLL_INTERVAL_MS: Lazy<u64> = Lazy::new(|| {
+    env::var("LIVE_POLL_INTERVAL_MS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(2000)
+});
+
+//1回のポーリングで取得する最大行数(残りは次回取得する)
+static LIVE_MAX_ROWS_PER_POLL: Lazy<i64> = Lazy::new(|| {
+    env::var("LIVE_MAX_ROWS_PER_POLL").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(5000)
+});
+
+//接続を維持するためのコメントを送る間隔
+static LIVE_KEEPALIVE_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("LIVE_KEEPALIVE_SECS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(15)
+});
+
+//起動時に号機の現在のロット・品種を調べる期間(時間)
+static LIVE_INITIAL_LOOKBACK_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("LIVE_INITIAL_LOOKBACK_HOURS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(24)
+});
+
+//号機ごとに保持する直近のアラーム数
+const LIVE_RECENT_ALARMS: usize = 10;
+
+//配信待ちのイベント数(超えた場合、遅いクライアントはlaggedイベントを受け取り、古いイベントは読み飛ばす)
+const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+static LIVE_SENDER: Lazy<broadcast::Sender<Arc<LiveEvent>>> = Lazy::new(|| broadcast::channel(LIVE_CHANNEL_CAPACITY).0);
+static LIVE_STATES: Lazy<Mutex<BTreeMap<i32,MachineLiveState>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+//チップに付いたアラーム
+#[derive(Debug,Clone,Serialize)]
+pub struct LiveAlarm{
+    pub machine_id:i32,
+    pub lot_name:Option<String>,
+    pub serial:Option<i32>,
+    pub ld_pickup_date:NaiveDateTime,
+    pub station:&'static str,
+    pub code:i32,
+    pub description:Option<String>,
+}
+
+//新しいチップ
+#[derive(Debug,Clone,Serialize)]
+pub struct LiveChip{
+    pub serial:Option<i32>,
+    pub type_name:Option<String>,
+    pub lot_name:Option<String>,
+    pub ld_pickup_date:NaiveDateTime,
+    pub station_codes:BTreeMap<&'static str,i32>,  //アラームが出たステーション→アラームコード
+}
+
+//号機の状態(件数は配信開始以降に取得したチップ数)
+#[derive(Debug,Clone,Serialize)]
+pub struct MachineLiveState{
+    pub machine_id:i32,
+    pub type_name:Option<String>,
+    pub lot_name:Option<String>,
+    pub chip_count:u64,
+    pub lot_chip_count:u64,
+    pub last_pickup_date:Option<NaiveDateTime>,
+    pub latest_alarms:VecDeque<LiveAlarm>,
+}
+
+impl MachineLiveState{
+    fn new(machine_id:i32)->Self{
+        MachineLiveState{
+            machine_id,
+            type_name: None, lot_name: None,
+            chip_count: 0, lot_chip_count: 0,
+            last_pickup_date: None,
+            latest_alarms: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum LiveEvent{
+    Chips{machine:MachineLiveState, chips:Vec<LiveChip>},
+    Alarm(LiveAlarm),
+}
+
+//購読する号機・アラームコード(Noneの場合は全て)
+pub struct LiveFilter{
+    pub machines:Option<HashSet<i32>>,
+    pub alarm_codes:Option<HashSet<i32>>,
+}
+
+impl LiveFilter{
+    //カンマ区切りの号機・アラームコード一覧から作成する(空の場合は全て)
+    pub fn from_query(machines:Option<&str>,alarm_codes:Option<&str>)->Result<Self,String>{
+        let parse = |value:Option<&str>, name:&str| -> Result<Option<HashSet<i32>>,String> {
+            let Some(value) = value.filter(|v| !v.trim().is_empty()) else { return Ok(None) };
+            value.split(',')
+                .map(|v| v.trim().parse::<i32>().map_err(|_| format!("Invalid {}: {}", name, v)))
+                .collect::<Result<HashSet<i32>,String>>()
+                .map(Some)
+        };
+        Ok(LiveFilter{
+            machines: parse(machines, "machines")?,
+            alarm_codes: parse(alarm_codes, "alarm_codes")?,
+        })
+    }
+
+    fn accepts_machine(&self,machine_id:i32)->bool{
+        self.machines.as_ref().map(|m| m.contains(&machine_id)).unwrap_or(true)
+    }
+
+    //購読対象のイベントであればSSEの形式にする
+    fn render(&self,event:&LiveEvent)->Option<Bytes>{
+        match event {
+            LiveEvent::Chips{machine, chips} if self.accepts_machine(machine.machine_id) => {
+                Some(sse_event("chips", &serde_json::json!({"machine":machine, "chips":chips})))
+            },
+            LiveEvent::Alarm(alarm) if self.accepts_machine(alarm.machine_id)
+                && self.alarm_codes.as_ref().map(|c| c.contains(&alarm.code)).unwrap_or(true) => {
+                Some(sse_event("alarm", &serde_json::json!(alarm)))
+            },
+            _ => None,
+        }
+    }
+}
+
+fn sse_event(event:&str,data:&serde_json::Value)->Bytes{
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+//ポーリングを開始する
+pub fn start_live_monitor(pool:&PgPool,alarm_json_path:&str){
+    if !*LIVE_ENABLED {
+        info!("Live monitoring is disabled");
+        return;
+    }
+    let alarm_detail = match load_alarm_detail(alarm_json_path) {
+        Ok(detail) => Some(detail),
+        Err(e) => {
+            warn!("Failed to load alarm detail, live alarms have no description: {}", e);
+            None
+        },
+    };
+
+    let pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        let mut watermark = loop {
+            match initialize_watermark(&pool).await {
+                Ok(watermark) => break watermark,
+                Err(e) => {
+                    error!("Failed to initialize live monitoring: {}", e);
+                    tokio::time::sleep(Duration::from_millis(*LIVE_POLL_INTERVAL_MS)).await;
+                },
+            }
+        };
+        info!("Live monitoring started from {:?} (interval {} ms)", watermark.0, *LIVE_POLL_INTERVAL_MS);
+
+        loop {
+            tokio::time::sleep(Duration::from_millis(*LIVE_POLL_INTERVAL_MS)).await;
+            if let Err(e) = poll_new_chips(&pool, &mut watermark, alarm_detail.as_ref()).await {
+                error!("Failed to poll new chips: {}", e);
+            }
+        }
+    });
+}
+
+//透かし: 最後に取得したチップの(LD_PICKUP_DATE, 号機, ロット, シリアル)
+//同じ日時のチップが1回の取得件数を超えても進められるように、WATERMARK_KEYの順に取得して行の値で比較する
+//NULLの号機・シリアルはi32::MIN、ロットは空文字として扱う
+type Watermark = (NaiveDateTime, i32, String, i32);
+
+const WATERMARK_KEY: &str = "ld_pickup_date, COALESCE(machine_id, -2147483648), COALESCE(lot_name, ''), COALESCE(serial, -2147483648)";
+
+//取得した行の透かし(WATERMARK_KEYと同じくNULLを置き換える)
+fn row_watermark(ld_pickup_date:NaiveDateTime,machine_id:Option<i32>,lot_name:Option<&str>,serial:Option<i32>)->Watermark{
+    (ld_pickup_date, machine_id.unwrap_or(i32::MIN), lot_name.unwrap_or_default().to_string(), serial.unwrap_or(i32::MIN))
+}
+
+//最新のチップを透かしにし、号機ごとの現在のロット・品種を読み込む
+//チップが無い場合は現在時刻から配信する(過去のチップを配信しない)
+async fn initialize_watermark(pool:&PgPool)->Result<Watermark,sqlx::Error>{
+    let latest: Option<Watermark> = sqlx::query_as(&format!(
+        "SELECT {key} FROM chipdata WHERE ld_pickup_date IS NOT NULL
+        ORDER BY ld_pickup_date DESC, 2 DESC, 3 DESC, 4 DESC LIMIT 1", key = WATERMARK_KEY
+    )).fetch_optional(pool).await?;
+    let Some(latest) = latest else {
+        let now: NaiveDateTime = sqlx::query_scalar("SELECT localtimestamp::timestamp").fetch_one(pool).await?;
+        return Ok((now, i32::MIN, String::new(), i32::MIN));
+    };
+
+    let sql = format!(
+        "SELECT DISTINCT ON (machine_id) machine_id, type_name, lot_name, ld_pickup_date FROM chipdata
+        WHERE ld_pickup_date >= $1::timestamp - interval '{} hours' AND machine_id IS NOT NULL
+        ORDER BY machine_id, ld_pickup_date DESC", *LIVE_INITIAL_LOOKBACK_HOURS
+    );
+    let rows: Vec<(i32, Option<String>, Option<String>, NaiveDateTime)> = sqlx::query_as(&sql)
+        .bind(latest.0)
+        .fetch_all(pool)
+        .await?;
+    {
+        let mut states = LIVE_STATES.lock().unwrap();
+        for (machine_id, type_name, lot_name, ld_pickup_date) in rows {
+            let state = states.entry(machine_id).or_insert_with(|| MachineLiveState::new(machine_id));
+            state.type_name = type_name;
+            state.lot_name = lot_name;
+            state.last_pickup_date = Some(ld_pickup_date);
+        }
+    }
+    Ok(latest)
+}
+
+//透かしより後のチップを取得して号機の状態を更新し、イベントを配信する
+async fn poll_new_chips(pool:&PgPool,watermark:&mut Watermark,alarm_detail:Option<&AlarmDetail>)->Result<(),Box<dyn Error>>{
+    let alarm_columns = ALARM_STATIONS.iter().map(|s| format!("{}_ALARM", s)).collect::<Vec<_>>().join(", ");
+    let sql = format!(
+        "SELECT machine_id, type_name, lot_name, serial, ld_pickup_date, {alarms} FROM chipdata
+        WHERE ld_pickup_date >= $1 AND ({key}) > ($1, $2, $3, $4)
+        ORDER BY {key} LIMIT $5", alarms = alarm_columns, key = WATERMARK_KEY
+    );
+    let rows = sqlx::query(&sql)
+        .bind(watermark.0)
+        .bind(watermark.1)
+        .bind(&watermark.2)
+        .bind(watermark.3)
+        .bind(*LIVE_MAX_ROWS_PER_POLL)
+        .fetch_all(pool)
+        .await?;
+
+    let mut chips_by_machine: BTreeMap<i32,Vec<LiveChip>> = BTreeMap::new();
+    let mut alarms: Vec<LiveAlarm> = Vec::new();
+    for row in rows {
+        let machine_id: Option<i32> = row.try_get(0)?;
+        let lot_name: Option<String> = row.try_get(2)?;
+        let serial: Option<i32> = row.try_get(3)?;
+        let ld_pickup_date: NaiveDateTime = row.try_get(4)?;
+
+        //透かしを進める(WATERMARK_KEYの順に取得しているため、最後の行が次回の起点になる)
+        *watermark = row_watermark(ld_pickup_date, machine_id, lot_name.as_deref(), serial);
+        let Some(machine_id) = machine_id else { continue };
+
+        let mut station_codes = BTreeMap::new();
+        for (i, station) in ALARM_STATIONS.iter().enumerate() {
+            if let Some(code) = row.try_get::<Option<i32>, _>(5 + i)? {
+                station_codes.insert(*station, code);
+                alarms.push(LiveAlarm{
+                    machine_id, lot_name: lot_name.clone(), serial, ld_pickup_date,
+                    station, code,
+                    description: alarm_detail.and_then(|d| d.description(station, code)).cloned(),
+                });
+            }
+        }
+        chips_by_machine.entry(machine_id).or_default().push(LiveChip{
+            serial,
+            type_name: row.try_get(1)?,
+            lot_name,
+            ld_pickup_date,
+            station_codes,
+        });
+    }
+    if chips_by_machine.is_empty() {
+        return Ok(());
+    }
+
+    //号機の状態を更新
+    let mut events = Vec::new();
+    {
+        let mut states = LIVE_STATES.lock().unwrap();
+        for (machine_id, chips) in chips_by_machine {
+            let state = states.entry(machine_id).or_insert_with(|| MachineLiveState::new(machine_id));
+            for chip in chips.iter() {
+                if state.lot_name != chip.lot_name {
+                    state.lot_name = chip.lot_name.clone();
+                    state.lot_chip_count = 0;
+                }
+                state.type_name = chip.type_name.clone();
+                state.chip_count += 1;
+                state.lot_chip_count += 1;
+                state.last_pickup_date = Some(chip.ld_pickup_date);
+            }
+            for alarm in alarms.iter().filter(|a| a.machine_id == machine_id) {
+                state.latest_alarms.push_front(alarm.clone());
+                state.latest_alarms.truncate(LIVE_RECENT_ALARMS);
+            }
+            events.push(LiveEvent::Chips{machine: state.clone(), chips});
+        }
+    }
+    events.extend(alarms.into_iter().map(LiveEvent::Alarm));
+
+    debug!("Live events: {} (subscribers: {})", events.len(), LIVE_SENDER.receiver_count());
+    for event in events {
+        //購読者がいない場合は送信エラーになるが、状態は更新済みのため無視する
+        let _ = LIVE_SENDER.send(Arc::new(event));
+    }
+    Ok(())
+}
+
+//購読を開始し、SSEのストリームを返す(最初に購読対象の号機の状態をsnapshotとして送る)
+pub fn subscribe(filter:LiveFilter)->Result<impl Stream<Item=Result<Bytes,actix_web::Error>>,String>{
+    if !*LIVE_ENABLED {
+        return Err("Live monitoring is disabled".to_string());
+    }
+    let receiver = LIVE_SENDER.subscribe();
+    let machines: Vec<MachineLiveState> = LIVE_STATES.lock().unwrap().values()
+        .filter(|state| filter.accepts_machine(state.machine_id))
+        .cloned()
+        .collect();
+    let snapshot = sse_event("snapshot", &serde_json::json!({"machines":machines}));
+
+    let keepalive_period = Duration::from_secs(*LIVE_KEEPALIVE_SECS);
+    let keepalive = tokio::time::interval_at(tokio::time::Instant::now() + keepalive_period, keepalive_period);
+
+    Ok(futures_util::stream::unfold((Some(snapshot), receiver, filter, keepalive), |(snapshot, mut receiver, filter, mut keepalive)| async move {
+        if let Some(snapshot) = snapshot {
+            return Some((Ok(snapshot), (None, receiver, filter, keepalive)));
+        }
+        let bytes = loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => if let Some(bytes) = filter.render(&event) { break bytes },
+                    Err(RecvError::Lagged(skipped)) => break sse_event("lagged", &serde_json::json!({"skipped":skipped})),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keepalive.tick() => break Bytes::from_static(b": keepalive\n\n"),
+            }
+        };
+        Some((Ok(bytes), (None, receiver, filter, keepalive)))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s:&str)->NaiveDateTime{
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn alarm(machine_id:i32,code:i32)->LiveAlarm{
+        LiveAlarm{
+            machine_id, lot_name: Some("LOT1".to_string()), serial: Some(1), ld_pickup_date: date("2026-10-01 00:00:00"),
+            station: "DC1", code, description: None,
+        }
+    }
+
+    fn set(values:&[i32])->Option<HashSet<i32>>{
+        Some(values.iter().copied().collect())
+    }
+
+    #[test]
+    fn filter_from_empty_query_accepts_all(){
+        for (machines, alarm_codes) in [(None, None), (Some(""), Some("")), (Some("  "), Some(" \t"))] {
+            let filter = LiveFilter::from_query(machines, alarm_codes).unwrap();
+            assert!(filter.machines.is_none() && filter.alarm_codes.is_none());
+        }
+    }
+
+    #[test]
+    fn filter_from_query_trims_values(){
+        let filter = LiveFilter::from_query(Some(" 1, 2 ,3"), Some("400 ")).unwrap();
+        assert_eq!(filter.machines, set(&[1, 2, 3]));
+        assert_eq!(filter.alarm_codes, set(&[400]));
+    }
+
+    #[test]
+    fn filter_from_invalid_query(){
+        let error = LiveFilter::from_query(Some("1,x"), None).err().unwrap();
+        assert!(error.contains("machines"), "{}", error);
+        let error = LiveFilter::from_query(None, Some("400,,401")).err().unwrap();
+        assert!(error.contains("alarm_codes"), "{}", error);
+    }
+
+    #[test]
+    fn render_filters_chips_by_machine(){
+        let filter = LiveFilter{machines: set(&[1]), alarm_codes: set(&[400])};
+        let chips = |machine_id| LiveEvent::Chips{machine: MachineLiveState::new(machine_id), chips: vec![]};
+
+        let bytes = filter.render(&chips(1)).unwrap();
+        assert!(bytes.starts_with(b"event: chips\n"));
+        assert!(filter.render(&chips(2)).is_none());
+        //アラームコードの絞り込みはチップには適用しない
+        assert!(LiveFilter{machines: None, alarm_codes: set(&[400])}.render(&chips(2)).is_some());
+    }
+
+    #[test]
+    fn render_filters_alarms_by_machine_and_code(){
+        let filter = LiveFilter{machines: set(&[1]), alarm_codes: set(&[400])};
+
+        let bytes = filter.render(&LiveEvent::Alarm(alarm(1, 400))).unwrap();
+        assert!(bytes.starts_with(b"event: alarm\n"));
+        assert!(filter.render(&LiveEvent::Alarm(alarm(1, 401))).is_none());
+        assert!(filter.render(&LiveEvent::Alarm(alarm(2, 400))).is_none());
+        assert!(LiveFilter{machines: None, alarm_codes: None}.render(&LiveEvent::Alarm(alarm(2, 401))).is_some());
+    }
+
+    #[test]
+    fn watermark_key_uses_same_null_values(){
+        assert_eq!(WATERMARK_KEY.matches(&format!("COALESCE(machine_id, {})", i32::MIN)).count(), 1);
+        assert_eq!(WATERMARK_KEY.matches(&format!("COALESCE(serial, {})", i32::MIN)).count(), 1);
+        assert_eq!(WATERMARK_KEY.matches("COALESCE(lot_name, '')").count(), 1);
+    }
+
+    //同じ日時のチップが1回の取得件数より多くても、透かしの順に取得すれば全て1回ずつ配信される
+    #[test]
+    fn watermark_pages_through_same_timestamp(){
+        let at = date("2026-10-01 12:00:00");
+        let rows = vec![
+            row_watermark(at, Some(2), Some("LOT1"), Some(1)),
+            row_watermark(at, Some(1), Some("LOT2"), Some(1)),
+            row_watermark(at, None, None, None),
+            row_watermark(at, Some(1), Some("LOT1"), Some(2)),
+            row_watermark(at, Some(1), None, Some(5)),
+            row_watermark(at, Some(1), Some("LOT1"), None),
+            row_watermark(date("2026-10-01 12:00:01"), None, None, None),
+        ];
+
+        //ORDER BY WATERMARK_KEY LIMIT 2 と (WATERMARK_KEY) > 透かし を繰り返す
+        let mut watermark = row_watermark(date("2026-10-01 11:59:59"), Some(9), Some("LOT9"), Some(9));
+        let mut delivered = Vec::new();
+        loop {
+            let mut page: Vec<&Watermark> = rows.iter().filter(|row| **row > watermark).collect();
+            page.sort();
+            page.truncate(2);
+            let Some(last) = page.last() else { break };
+            watermark = (*last).clone();
+            delivered.extend(page.into_iter().cloned());
+        }
+
+        let mut expected = rows.clone();
+        expected.sort();
+        assert_eq!(delivered, expected);
+        //NULLの号機・ロット・シリアルは同じ日時の中で先に並ぶ
+        assert_eq!(delivered[0], (at, i32::MIN, String::new(), i32::MIN));
+        assert_eq!(delivered[1], (at, 1, String::new(), 5));
+        assert_eq!(delivered[2], (at, 1, "LOT1".to_string(), i32::MIN));
+    }
+}
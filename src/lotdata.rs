@@ -1,6 +1,5 @@
 use sqlx::{PgPool, Row,Column};
 use serde::Serialize;
-use chrono;
 use tracing::debug;
 
 #[derive(Debug, Serialize)]
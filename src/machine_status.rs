@@ -0,0 +1,422 @@
+/* 号機ごとの現在の稼働状態(稼働中・待機・アラーム停止・オフライン)を返す */
+//最新のチップの取得日時(LD_PICKUP_DATE)と、そのチップのアラームから状態を判定する
+//  running: 最新のチップがサイクルタイム(cycle_time_secs)以内
+//  alarm_stopped: サイクルタイムを過ぎ、最新のチップに停止アラームが付いている
+//  idle: サイクルタイムを過ぎている(停止アラーム無し)
+//  offline: offline_after_secsを過ぎている、または直近MACHINE_STATUS_LOOKBACK_HOURS時間にチップが無い
+use chrono::{NaiveDateTime, TimeDelta};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::collections::{BTreeSet, HashMap};
+use std::{env, fs};
+use std::error::Error;
+use tracing::{debug, warn};
+
+use crate::alarmdata::load_alarm_detail;
+use crate::graph::sql::shift_start;
+use crate::variants::{ALARM_STATIONS, MACHINE_IDS};
+
+//号機ごとのしきい値の設定ファイル
+static MACHINE_STATUS_JSON_PATH: Lazy<String> = Lazy::new(|| {
+    env::var("MACHINE_STATUS_JSON_PATH").unwrap_or("C:\\workspace\\server_backend\\assets\\machine_status.json".to_string())
+});
+
+//最新のチップ・アラームを探す期間(時間)
+static MACHINE_STATUS_LOOKBACK_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("MACHINE_STATUS_LOOKBACK_HOURS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(24)
+});
+
+//設定ファイルが無い場合・項目が無い場合のしきい値
+const DEFAULT_CYCLE_TIME_SECS: f64 = 60.0;
+const DEFAULT_OFFLINE_AFTER_SECS: f64 = 3600.0;
+
+//しきい値(号機ごとの設定に無い項目はdefaultの値を使う)
+//stop_alarms: ステーション→停止アラームのコード一覧(省略した場合は全てのアラームを停止アラームとする)
+//ステーション名は大文字・小文字を区別しない(読み込み時に大文字に揃える)
+#[derive(Debug,Default,Clone,Deserialize)]
+struct StatusThresholds{
+    cycle_time_secs:Option<f64>,
+    offline_after_secs:Option<f64>,
+    stop_alarms:Option<HashMap<String,Vec<i32>>>,
+}
+
+impl StatusThresholds{
+    //しきい値とステーション名を確認し、ステーション名を大文字に揃える(不正な項目はerrorsに追加する)
+    fn validate(mut self,name:&str,errors:&mut Vec<String>)->Self{
+        for (item, value) in [("cycle_time_secs", self.cycle_time_secs), ("offline_after_secs", self.offline_after_secs)] {
+            if let Some(value) = value && !(value.is_finite() && value > 0.0) {
+                errors.push(format!("{}: {} must be a positive number: {}", name, item, value));
+            }
+        }
+        self.stop_alarms = self.stop_alarms.map(|stop_alarms| {
+            stop_alarms.into_iter().filter_map(|(station, codes)| {
+                let upper_station = station.to_uppercase();
+                if ALARM_STATIONS.contains(&upper_station.as_str()) {
+                    Some((upper_station, codes))
+                } else {
+                    errors.push(format!("{}: unknown station in stop_alarms: {} (expected one of {:?})", name, station, ALARM_STATIONS));
+                    None
+                }
+            }).collect()
+        });
+        self
+    }
+}
+
+//例: {"default":{"cycle_time_secs":60,"offline_after_secs":3600},"machines":{"3":{"cycle_time_secs":90,"stop_alarms":{"DC1":[400,401]}}}}
+#[derive(Debug,Default,Deserialize)]
+struct StatusConfigFile{
+    #[serde(default)]
+    default:StatusThresholds,
+    #[serde(default)]
+    machines:HashMap<String,StatusThresholds>,
+}
+
+#[derive(Debug,Default)]
+struct StatusConfig{
+    default:StatusThresholds,
+    machines:HashMap<i32,StatusThresholds>,
+}
+
+impl StatusConfig{
+    //設定ファイルを読み込む(ファイルが無い場合は既定値、形式・号機番号・ステーション名・しきい値が不正な場合はエラー)
+    fn load()->Result<Self,Box<dyn Error>>{
+        let s = match fs::read_to_string(&*MACHINE_STATUS_JSON_PATH) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Machine status JSON not found, using default thresholds: {}", &*MACHINE_STATUS_JSON_PATH);
+                return Ok(StatusConfig::default());
+            },
+            Err(e) => return Err(e.into()),
+        };
+        StatusConfig::parse(&s).map_err(|e| {
+            warn!("Invalid machine status JSON {}: {}", &*MACHINE_STATUS_JSON_PATH, e);
+            format!("Invalid machine status JSON {}: {}", &*MACHINE_STATUS_JSON_PATH, e).into()
+        })
+    }
+
+    //設定ファイルの内容を確認して読み込む(不正な項目は全てまとめてエラーにする)
+    fn parse(s:&str)->Result<Self,String>{
+        let file: StatusConfigFile = serde_json::from_str(s).map_err(|e| e.to_string())?;
+
+        let mut errors = Vec::new();
+        let default = file.default.validate("default", &mut errors);
+        let mut machines = HashMap::new();
+        for (key, thresholds) in file.machines {
+            match key.trim().parse::<i32>() {
+                Ok(machine_id) => { machines.insert(machine_id, thresholds.validate(&format!("machines.{}", key), &mut errors)); },
+                Err(_) => errors.push(format!("machines: machine id must be an integer: {}", key)),
+            }
+        }
+        if !errors.is_empty() {
+            errors.sort();
+            return Err(errors.join("; "));
+        }
+        Ok(StatusConfig{default, machines})
+    }
+
+    fn thresholds(&self,machine_id:i32)->StatusThresholds{
+        let machine = self.machines.get(&machine_id).cloned().unwrap_or_default();
+        StatusThresholds{
+            cycle_time_secs: machine.cycle_time_secs.or(self.default.cycle_time_secs),
+            offline_after_secs: machine.offline_after_secs.or(self.default.offline_after_secs),
+            stop_alarms: machine.stop_alarms.or(self.default.stop_alarms.clone()),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum MachineState{
+    Running,
+    Idle,
+    AlarmStopped,
+    Offline,
+}
+
+#[derive(Debug,Serialize)]
+pub struct LastAlarm{
+    pub station:&'static str,
+    pub code:i32,
+    pub description:Option<String>,
+    pub ld_pickup_date:NaiveDateTime,
+}
+
+#[derive(Debug,Serialize)]
+pub struct MachineStatus{
+    pub machine_id:i32,
+    pub state:MachineState,
+    pub state_since:Option<NaiveDateTime>,      //現在の状態になった日時(直近にチップが無いオフラインの場合はNone)
+    pub time_in_state_secs:Option<i64>,
+    pub lot_name:Option<String>,
+    pub type_name:Option<String>,
+    pub last_pickup_date:Option<NaiveDateTime>,
+    pub shift_chip_count:i64,                   //現在のシフトで処理したチップ数
+    pub last_alarm:Option<LastAlarm>,
+    pub cycle_time_secs:f64,
+    pub offline_after_secs:f64,
+}
+
+//号機ごとの最新のチップ
+struct LatestChip{
+    type_name:Option<String>,
+    lot_name:Option<String>,
+    ld_pickup_date:NaiveDateTime,
+    alarms:Vec<(&'static str,i32)>,
+}
+
+//号機ごとの稼働状態を返す(号機一覧と設定ファイルにある号機)
+//戻り値: (判定した日時, 現在のシフトの開始日時, 号機ごとの状態)
+pub async fn get_machine_status(pool:&PgPool,alarm_json_path:&str)->Result<(NaiveDateTime,NaiveDateTime,Vec<MachineStatus>),Box<dyn Error>>{
+    let config = StatusConfig::load()?;
+    let alarm_detail = load_alarm_detail(alarm_json_path)
+        .map_err(|e| warn!("Failed to load alarm detail, last alarms have no description: {}", e))
+        .ok();
+
+    let mut machine_ids: BTreeSet<i32> = MACHINE_IDS.iter().copied().collect();
+    machine_ids.extend(config.machines.keys().copied());
+    let machine_ids: Vec<i32> = machine_ids.into_iter().collect();
+    let thresholds: Vec<StatusThresholds> = machine_ids.iter().map(|m| config.thresholds(*m)).collect();
+    let cycle_times: Vec<f64> = thresholds.iter().map(|t| t.cycle_time_secs.unwrap_or(DEFAULT_CYCLE_TIME_SECS)).collect();
+
+    //判定の基準はDBの現在時刻にする(チップの取得日時はDBの時刻で記録されるため)
+    let now: NaiveDateTime = sqlx::query_scalar("SELECT localtimestamp::timestamp").fetch_one(pool).await?;
+    let current_shift_start = shift_start(&now);
+    let since = (now - TimeDelta::hours(*MACHINE_STATUS_LOOKBACK_HOURS)).min(current_shift_start);
+    debug!("Machine status: now={}, shift_start={}, since={}", now, current_shift_start, since);
+
+    let alarm_columns = ALARM_STATIONS.iter().map(|s| format!("{}_ALARM", s)).collect::<Vec<_>>();
+
+    //号機ごとの最新のチップ
+    let sql = format!(
+        "SELECT DISTINCT ON (machine_id) machine_id, type_name, lot_name, ld_pickup_date, {} FROM chipdata
+        WHERE ld_pickup_date >= $1 AND machine_id = ANY($2)
+        ORDER BY machine_id, ld_pickup_date DESC", alarm_columns.join(", ")
+    );
+    let rows = sqlx::query(&sql).bind(since).bind(&machine_ids).fetch_all(pool).await?;
+    let mut latest_chips: HashMap<i32,LatestChip> = HashMap::new();
+    for row in rows {
+        latest_chips.insert(row.try_get(0)?, LatestChip{
+            type_name: row.try_get(1)?,
+            lot_name: row.try_get(2)?,
+            ld_pickup_date: row.try_get(3)?,
+            alarms: chip_alarms(&row, 4)?,
+        });
+    }
+
+    //号機ごとの最新のアラーム
+    let sql = format!(
+        "SELECT DISTINCT ON (machine_id) machine_id, ld_pickup_date, {} FROM chipdata
+        WHERE ld_pickup_date >= $1 AND machine_id = ANY($2) AND COALESCE({}) IS NOT NULL
+        ORDER BY machine_id, ld_pickup_date DESC", alarm_columns.join(", "), alarm_columns.join(", ")
+    );
+    let rows = sqlx::query(&sql).bind(since).bind(&machine_ids).fetch_all(pool).await?;
+    let mut last_alarms: HashMap<i32,LastAlarm> = HashMap::new();
+    for row in rows {
+        let ld_pickup_date: NaiveDateTime = row.try_get(1)?;
+        if let Some((station, code)) = chip_alarms(&row, 2)?.into_iter().next() {
+            last_alarms.insert(row.try_get(0)?, LastAlarm{
+                station, code, ld_pickup_date,
+                description: alarm_detail.as_ref().and_then(|d| d.description(station, code)).cloned(),
+            });
+        }
+    }
+
+    //シフト内のチップ数と、現在の連続稼働の開始日時(直前のチップとの間隔がサイクルタイムを超えた最後のチップ)
+    let sql = "WITH t AS (
+            SELECT machine_id, ld_pickup_date, LAG(ld_pickup_date) OVER (PARTITION BY machine_id ORDER BY ld_pickup_date) AS prev
+            FROM chipdata WHERE ld_pickup_date >= $1 AND machine_id = ANY($2)
+        )
+        SELECT t.machine_id, COUNT(*) FILTER (WHERE t.ld_pickup_date >= $4),
+            MAX(t.ld_pickup_date) FILTER (WHERE t.prev IS NULL OR t.ld_pickup_date - t.prev > c.cycle_time_secs * interval '1 second')
+        FROM t JOIN unnest($2::integer[], $3::double precision[]) AS c(machine_id, cycle_time_secs) USING (machine_id)
+        GROUP BY t.machine_id";
+    let rows: Vec<(i32, i64, Option<NaiveDateTime>)> = sqlx::query_as(sql)
+        .bind(since).bind(&machine_ids).bind(&cycle_times).bind(current_shift_start)
+        .fetch_all(pool).await?;
+    let activity: HashMap<i32,(i64,Option<NaiveDateTime>)> = rows.into_iter().map(|(m, count, run_start)| (m, (count, run_start))).collect();
+
+    let mut statuses = Vec::new();
+    for ((machine_id, thresholds), cycle_time_secs) in machine_ids.iter().zip(thresholds).zip(cycle_times) {
+        let offline_after_secs = thresholds.offline_after_secs.unwrap_or(DEFAULT_OFFLINE_AFTER_SECS);
+        let latest = latest_chips.remove(machine_id);
+        let (shift_chip_count, run_start) = activity.get(machine_id).copied().unwrap_or((0, None));
+
+        let (state, state_since) = classify_state(now, latest.as_ref(), run_start, cycle_time_secs, offline_after_secs, thresholds.stop_alarms.as_ref());
+
+        statuses.push(MachineStatus{
+            machine_id: *machine_id,
+            state,
+            state_since,
+            time_in_state_secs: state_since.map(|since| (now - since).num_seconds().max(0)),
+            lot_name: latest.as_ref().and_then(|chip| chip.lot_name.clone()),
+            type_name: latest.as_ref().and_then(|chip| chip.type_name.clone()),
+            last_pickup_date: latest.as_ref().map(|chip| chip.ld_pickup_date),
+            shift_chip_count,
+            last_alarm: last_alarms.remove(machine_id),
+            cycle_time_secs,
+            offline_after_secs,
+        });
+    }
+
+    Ok((now, current_shift_start, statuses))
+}
+
+//最新のチップから稼働状態と、その状態になった日時を判定する
+//run_start: 現在の連続稼働の開始日時(稼働中の場合に使う)
+fn classify_state(now:NaiveDateTime,latest:Option<&LatestChip>,run_start:Option<NaiveDateTime>,cycle_time_secs:f64,offline_after_secs:f64,stop_alarms:Option<&HashMap<String,Vec<i32>>>)->(MachineState,Option<NaiveDateTime>){
+    let Some(chip) = latest else {
+        return (MachineState::Offline, None);
+    };
+    let elapsed_secs = (now - chip.ld_pickup_date).num_milliseconds() as f64 / 1000.0;
+    let is_stop_alarm = chip.alarms.iter().any(|(station, code)| match stop_alarms {
+        Some(stop_alarms) => stop_alarms.get(*station).map(|codes| codes.contains(code)).unwrap_or(false),
+        None => true,
+    });
+    if elapsed_secs > offline_after_secs {
+        (MachineState::Offline, Some(chip.ld_pickup_date + seconds(offline_after_secs)))
+    } else if elapsed_secs <= cycle_time_secs {
+        (MachineState::Running, Some(run_start.unwrap_or(chip.ld_pickup_date)))
+    } else if is_stop_alarm {
+        (MachineState::AlarmStopped, Some(chip.ld_pickup_date))
+    } else {
+        (MachineState::Idle, Some(chip.ld_pickup_date + seconds(cycle_time_secs)))
+    }
+}
+
+//ステーション順のアラーム(ステーション, アラームコード)を取得する(offsetはLD_ALARMの列番号)
+fn chip_alarms(row:&sqlx::postgres::PgRow,offset:usize)->Result<Vec<(&'static str,i32)>,sqlx::Error>{
+    let mut alarms = Vec::new();
+    for (i, station) in ALARM_STATIONS.iter().enumerate() {
+        if let Some(code) = row.try_get::<Option<i32>, _>(offset + i)? {
+            alarms.push((*station, code));
+        }
+    }
+    Ok(alarms)
+}
+
+fn seconds(secs:f64)->TimeDelta{
+    TimeDelta::milliseconds((secs * 1000.0) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s:&str)->NaiveDateTime{
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn chip(ld_pickup_date:&str,alarms:Vec<(&'static str,i32)>)->LatestChip{
+        LatestChip{type_name: None, lot_name: None, ld_pickup_date: date(ld_pickup_date), alarms}
+    }
+
+    fn stop_alarms(station:&str,codes:&[i32])->HashMap<String,Vec<i32>>{
+        [(station.to_string(), codes.to_vec())].into_iter().collect()
+    }
+
+    //サイクルタイム60秒、オフライン3600秒で判定する
+    fn classify(now:&str,latest:Option<&LatestChip>,stop_alarms:Option<&HashMap<String,Vec<i32>>>)->(MachineState,Option<NaiveDateTime>){
+        classify_state(date(now), latest, Some(date("2026-10-01 11:00:00")), 60.0, 3600.0, stop_alarms)
+    }
+
+    #[test]
+    fn offline_without_recent_chip(){
+        assert_eq!(classify("2026-10-01 12:00:00", None, None), (MachineState::Offline, None));
+    }
+
+    #[test]
+    fn running_within_cycle_time(){
+        let latest = chip("2026-10-01 12:00:00", vec![("DC1", 400)]);
+        //サイクルタイムちょうどまでは稼働中(最新のチップにアラームがあっても稼働中)
+        assert_eq!(classify("2026-10-01 12:01:00", Some(&latest), None), (MachineState::Running, Some(date("2026-10-01 11:00:00"))));
+        assert_eq!(classify("2026-10-01 12:00:00", Some(&latest), None).0, MachineState::Running);
+        //連続稼働の開始日時が無い場合は最新のチップの日時
+        let (state, since) = classify_state(date("2026-10-01 12:00:30"), Some(&latest), None, 60.0, 3600.0, None);
+        assert_eq!((state, since), (MachineState::Running, Some(date("2026-10-01 12:00:00"))));
+    }
+
+    #[test]
+    fn idle_after_cycle_time(){
+        let latest = chip("2026-10-01 12:00:00", vec![]);
+        assert_eq!(classify("2026-10-01 12:01:01", Some(&latest), None), (MachineState::Idle, Some(date("2026-10-01 12:01:00"))));
+        //停止アラームに無いアラームは待機
+        let latest = chip("2026-10-01 12:00:00", vec![("DC1", 401)]);
+        let stop = stop_alarms("DC1", &[400]);
+        assert_eq!(classify("2026-10-01 12:01:01", Some(&latest), Some(&stop)).0, MachineState::Idle);
+        let stop = stop_alarms("AC1", &[401]);
+        assert_eq!(classify("2026-10-01 12:01:01", Some(&latest), Some(&stop)).0, MachineState::Idle);
+    }
+
+    #[test]
+    fn alarm_stopped_after_cycle_time(){
+        let latest = chip("2026-10-01 12:00:00", vec![("DC1", 400)]);
+        let stop = stop_alarms("DC1", &[400]);
+        assert_eq!(classify("2026-10-01 12:01:01", Some(&latest), Some(&stop)), (MachineState::AlarmStopped, Some(date("2026-10-01 12:00:00"))));
+        //停止アラームの指定が無い場合は全てのアラームで停止
+        assert_eq!(classify("2026-10-01 12:01:01", Some(&latest), None).0, MachineState::AlarmStopped);
+        //オフラインの時間ちょうどまではアラーム停止
+        assert_eq!(classify("2026-10-01 13:00:00", Some(&latest), None).0, MachineState::AlarmStopped);
+    }
+
+    #[test]
+    fn offline_after_offline_time(){
+        let latest = chip("2026-10-01 12:00:00", vec![("DC1", 400)]);
+        assert_eq!(classify("2026-10-01 13:00:01", Some(&latest), None), (MachineState::Offline, Some(date("2026-10-01 13:00:00"))));
+        let latest = chip("2026-10-01 12:00:00", vec![]);
+        assert_eq!(classify("2026-10-01 13:00:00", Some(&latest), None).0, MachineState::Idle);
+        assert_eq!(classify("2026-10-01 13:00:01", Some(&latest), None).0, MachineState::Offline);
+    }
+
+    #[test]
+    fn validate_thresholds(){
+        let mut errors = Vec::new();
+        let thresholds = StatusThresholds{
+            cycle_time_secs: Some(0.0),
+            offline_after_secs: Some(-1.0),
+            stop_alarms: Some([("dc1".to_string(), vec![400]), ("XX".to_string(), vec![1])].into_iter().collect()),
+        }.validate("machines.3", &mut errors);
+        errors.sort();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("machines.3: cycle_time_secs must be a positive number"), "{:?}", errors);
+        assert!(errors[1].starts_with("machines.3: offline_after_secs must be a positive number"), "{:?}", errors);
+        assert!(errors[2].starts_with("machines.3: unknown station in stop_alarms: XX"), "{:?}", errors);
+        //ステーション名は大文字に揃える
+        assert_eq!(thresholds.stop_alarms, Some(stop_alarms("DC1", &[400])));
+
+        let mut errors = Vec::new();
+        StatusThresholds{cycle_time_secs: Some(0.5), offline_after_secs: None, stop_alarms: None}.validate("default", &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn parse_reports_all_errors(){
+        let error = StatusConfig::parse(r#"{"default":{"cycle_time_secs":-5},"machines":{"x":{},"3":{"stop_alarms":{"YY":[1]}}}}"#).err().unwrap();
+        assert!(error.contains("default: cycle_time_secs"), "{}", error);
+        assert!(error.contains("machine id must be an integer: x"), "{}", error);
+        assert!(error.contains("machines.3: unknown station in stop_alarms: YY"), "{}", error);
+        assert!(StatusConfig::parse("{").is_err());
+    }
+
+    #[test]
+    fn thresholds_fall_back_to_default(){
+        let config = StatusConfig::parse(r#"{
+            "default":{"cycle_time_secs":60,"offline_after_secs":3600,"stop_alarms":{"DC1":[400]}},
+            "machines":{" 3 ":{"cycle_time_secs":90,"stop_alarms":{"ac1":[500]}},"4":{"offline_after_secs":600}}
+        }"#).unwrap();
+
+        let machine3 = config.thresholds(3);
+        assert_eq!((machine3.cycle_time_secs, machine3.offline_after_secs), (Some(90.0), Some(3600.0)));
+        //停止アラームは号機の指定で置き換える(defaultとは結合しない)
+        assert_eq!(machine3.stop_alarms, Some(stop_alarms("AC1", &[500])));
+
+        let machine4 = config.thresholds(4);
+        assert_eq!((machine4.cycle_time_secs, machine4.offline_after_secs), (Some(60.0), Some(600.0)));
+        assert_eq!(machine4.stop_alarms, Some(stop_alarms("DC1", &[400])));
+
+        let other = config.thresholds(1);
+        assert_eq!((other.cycle_time_secs, other.offline_after_secs), (Some(60.0), Some(3600.0)));
+        assert_eq!(StatusConfig::default().thresholds(1).cycle_time_secs, None);
+    }
+}
@@ -0,0 +1,220 @@
+/* エンドポイントごとのクエリの制限時間と、中断時のクエリのキャンセル */
+use sqlx::{Executor, PgPool};
+use sqlx::postgres::{PgConnection, PgPoolOptions};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+
+//エンドポイントごとの制限時間
+//statement_timeout: 1クエリあたりの制限時間(Postgresのstatement_timeoutに設定する)
+//max_duration: リクエスト全体の制限時間(複数のクエリを含む)
+pub struct QueryLimits{
+    pub statement_timeout_ms:u64,
+    pub max_duration_ms:u64,
+}
+
+impl QueryLimits{
+    //{prefix}_STATEMENT_TIMEOUT_MS, {prefix}_MAX_DURATION_MSで上書き可能
+    fn from_env(prefix:&str,statement_timeout_ms:u64,max_duration_ms:u64)->Self{
+        let read = |name:String, default:u64| env::var(name).ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(default);
+        QueryLimits{
+            statement_timeout_ms: read(format!("{}_STATEMENT_TIMEOUT_MS", prefix), statement_timeout_ms),
+            max_duration_ms: read(format!("{}_MAX_DURATION_MS", prefix), max_duration_ms),
+        }
+    }
+}
+
+pub static GRAPH_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("GRAPH", 60_000, 120_000));
+pub static CORRELATION_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("CORRELATION", 60_000, 120_000));
+pub static LOT_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("LOT", 30_000, 60_000));
+pub static ALARM_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("ALARM", 30_000, 60_000));
+pub static STATUS_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("STATUS", 10_000, 20_000));
+//ロールアップのバックフィルは長期間を集計し直すため長めにする
+pub static ROLLUP_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("ROLLUP", 600_000, 3_600_000));
+//非同期ジョブはHTTPリクエストを待たせないため長めにする
+pub static JOB_LIMITS: Lazy<QueryLimits> = Lazy::new(|| QueryLimits::from_env("JOB", 600_000, 1_800_000));
+
+//タイムアウト時のエラーコード
+pub const TIMEOUT_ERROR_CODE: &str = "TIMEOUT";
+//タイムアウト以外のエラーコード
+pub const QUERY_ERROR_CODE: &str = "ERROR";
+
+//リクエスト全体の制限時間を超えた場合のエラー
+#[derive(Debug)]
+pub struct MaxDurationExceeded(u64);
+
+impl fmt::Display for MaxDurationExceeded{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        write!(f, "Request exceeded the maximum duration of {} ms. Narrow your filters or date range", self.0)
+    }
+}
+
+impl Error for MaxDurationExceeded{}
+
+//実行中のリクエストの情報(クエリ実行中のタスクから参照する)
+#[derive(Clone)]
+struct QueryScope{
+    id:u64,
+    statement_timeout_ms:u64,
+}
+
+tokio::task_local! {
+    static QUERY_SCOPE: QueryScope;
+}
+
+static NEXT_SCOPE_ID: AtomicU64 = AtomicU64::new(1);
+
+//接続のstatement_timeoutの既定値(ミリ秒、0は無制限)。最初の接続時に取得する
+static DEFAULT_STATEMENT_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+//リクエストが使用中の接続に付けるapplication_name(キャンセル時にpg_stat_activityから探す)
+//複数のプロセスで同じDBを使う場合に備えてプロセスIDを含める
+fn scope_tag(scope_id:u64)->String{
+    format!("server_backend:{}:{}", std::process::id(), scope_id)
+}
+
+//DB接続プールを作成する
+//リクエスト中に取得した接続だけにstatement_timeoutとキャンセル用のタグを設定し、プールに戻すときに既定値に戻す
+//(リクエスト外で接続を取得する場合は追加のクエリを発行しない)
+pub async fn connect_pool(db_url:&str)->Result<PgPool,sqlx::Error>{
+    pool_options().connect(db_url).await
+}
+
+//接続プールの設定
+//before_acquireはプールにある接続を再利用する場合のみ呼ばれるため、新しく開いた接続はafter_connectで設定する
+//(after_connectは接続を取得したタスクで実行されるため、リクエスト中であればQUERY_SCOPEを参照できる)
+fn pool_options()->PgPoolOptions{
+    PgPoolOptions::new()
+        .after_connect(|conn, _| Box::pin(async move {
+            let timeout_ms: String = sqlx::query_scalar("SELECT setting FROM pg_settings WHERE name = 'statement_timeout'")
+                .fetch_one(&mut *conn).await?;
+            DEFAULT_STATEMENT_TIMEOUT_MS.store(timeout_ms.parse().unwrap_or(0), Ordering::Relaxed);
+            prepare_connection(conn).await
+        }))
+        .before_acquire(|conn, _| Box::pin(async move { prepare_connection(conn).await.map(|_| true) }))
+        .after_release(|conn, _| Box::pin(async move { reset_connection(conn).await }))
+}
+
+//プールに戻す接続のstatement_timeoutとタグを既定値に戻す
+//戻せなかった接続は破棄する(他のリクエストに制限時間やタグが残らないようにする)
+async fn reset_connection(conn:&mut PgConnection)->Result<bool,sqlx::Error>{
+    match conn.execute("RESET statement_timeout; RESET application_name").await {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            warn!("Failed to reset connection settings: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+//リクエスト中であれば接続にタグを付け、statement_timeoutが既定値と異なる場合のみ設定する
+//プールにある接続は常に既定値に戻っているため、リクエスト外では何もしない
+async fn prepare_connection(conn:&mut PgConnection)->Result<(),sqlx::Error>{
+    let Ok(scope) = QUERY_SCOPE.try_with(|scope| scope.clone()) else {
+        return Ok(());
+    };
+    if scope.statement_timeout_ms == DEFAULT_STATEMENT_TIMEOUT_MS.load(Ordering::Relaxed) {
+        sqlx::query("SELECT set_config('application_name', $1, false)")
+            .bind(scope_tag(scope.id))
+            .execute(&mut *conn).await?;
+    } else {
+        sqlx::query("SELECT set_config('application_name', $1, false), set_config('statement_timeout', $2, false)")
+            .bind(scope_tag(scope.id))
+            .bind(scope.statement_timeout_ms.to_string())
+            .execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+//制限時間付きでDBの処理を実行する
+//制限時間を超えた場合・クライアントが切断して処理が中断された場合・エラーで終了した場合は、実行中のクエリをキャンセルする
+pub async fn run_with_limits<T,F>(pool:&PgPool,limits:&QueryLimits,future:F)->Result<T,Box<dyn Error>>
+where F:Future<Output=Result<T,Box<dyn Error>>>{
+    let scope = QueryScope{
+        id: NEXT_SCOPE_ID.fetch_add(1, Ordering::Relaxed),
+        statement_timeout_ms: limits.statement_timeout_ms,
+    };
+    let mut guard = CancelGuard{pool:pool.clone(), scope_id:scope.id, finished:false};
+
+    let result = match QUERY_SCOPE.scope(scope, tokio::time::timeout(Duration::from_millis(limits.max_duration_ms), future)).await {
+        Ok(result) => result,
+        Err(_) => Err(Box::new(MaxDurationExceeded(limits.max_duration_ms)) as Box<dyn Error>),
+    };
+    guard.finished = result.is_ok();
+    result
+}
+
+//エラーコードを返す(statement_timeoutまたはリクエスト全体の制限時間を超えた場合はTIMEOUT)
+pub fn error_code(error:&(dyn Error + 'static))->&'static str{
+    if error.is::<MaxDurationExceeded>() {
+        return TIMEOUT_ERROR_CODE;
+    }
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_error)) if db_error.code().as_deref() == Some("57014") => TIMEOUT_ERROR_CODE,
+        _ => QUERY_ERROR_CODE,
+    }
+}
+
+//正常終了せずにdropされた場合に、リクエストが使用していた接続のクエリをキャンセルする
+//(sqlxのクエリをdropしてもPostgres側では処理が続くため)
+struct CancelGuard{
+    pool:PgPool,
+    scope_id:u64,
+    finished:bool,
+}
+
+impl Drop for CancelGuard{
+    fn drop(&mut self){
+        if self.finished {
+            return;
+        }
+        //プールに戻った接続はタグが外れているため、このリクエストで実行中のクエリのみキャンセルされる
+        let pool = self.pool.clone();
+        let tag = scope_tag(self.scope_id);
+        tokio::spawn(async move {
+            let result = sqlx::query_scalar::<_, bool>(
+                "SELECT pg_cancel_backend(pid) FROM pg_stat_activity WHERE application_name = $1 AND state = 'active'"
+            ).bind(&tag).fetch_all(&pool).await;
+            match result {
+                Ok(cancelled) => debug!("Cancelled {} queries (tag: {})", cancelled.len(), tag),
+                Err(e) => warn!("Failed to cancel queries: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //DBが必要なためTEST_DB_URLを指定した場合のみ実行する
+    fn test_db_url()->Option<String>{
+        env::var("TEST_DB_URL").ok().filter(|url| !url.is_empty())
+    }
+
+    async fn current_settings(pool:&PgPool)->Result<(String,String),Box<dyn Error>>{
+        Ok(sqlx::query_as("SELECT current_setting('statement_timeout'), current_setting('application_name')")
+            .fetch_one(pool).await?)
+    }
+
+    #[tokio::test]
+    async fn fresh_connection_gets_scope_settings(){
+        let Some(db_url) = test_db_url() else { return };
+        //接続を開かずにプールを作成し、リクエスト中の最初のクエリで新しい接続を開かせる
+        let pool = pool_options().max_connections(1).connect_lazy(&db_url).unwrap();
+        let limits = QueryLimits{statement_timeout_ms: 1234, max_duration_ms: 10_000};
+
+        let (timeout, tag) = run_with_limits(&pool, &limits, current_settings(&pool)).await.unwrap();
+        assert_eq!(timeout, "1234ms");
+        assert!(tag.starts_with(&format!("server_backend:{}:", std::process::id())), "{}", tag);
+
+        //プールに戻した接続は既定値に戻っている
+        let (_, tag) = current_settings(&pool).await.unwrap();
+        assert!(!tag.starts_with("server_backend:"), "{}", tag);
+    }
+}
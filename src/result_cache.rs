@@ -0,0 +1,200 @@
+/* グラフ・アラームの取得結果のキャッシュ */
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{NaiveDate, NaiveDateTime};
+use once_cell::sync::Lazy;
+use tracing::debug;
+
+use crate::graph::variants::*;
+use crate::variants::{LotUnitData, MachineData};
+
+//キャッシュごとの最大件数(件数・サイズのどちらかを超えた場合は最も長く使われていない結果から削除する)
+static RESULT_CACHE_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    env::var("RESULT_CACHE_MAX_ENTRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(200)
+});
+
+//キャッシュごとの最大サイズ(推定バイト数、超えた場合は最も長く使われていない結果から削除する)
+static RESULT_CACHE_MAX_BYTES: Lazy<usize> = Lazy::new(|| {
+    env::var("RESULT_CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(256 * 1024 * 1024)
+});
+
+//1件の結果の最大サイズ(推定バイト数)。これより大きい結果はキャッシュしない
+static RESULT_CACHE_MAX_ENTRY_BYTES: Lazy<usize> = Lazy::new(|| {
+    env::var("RESULT_CACHE_MAX_ENTRY_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(32 * 1024 * 1024)
+});
+
+//終了日時が過去の期間の結果の保持時間(データが増えないため長く保持する)
+static RESULT_CACHE_PAST_TTL_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("RESULT_CACHE_PAST_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(6 * 60 * 60)
+});
+
+//終了日時が現在以降の期間の結果の保持時間(データが増えるため短くする)
+static RESULT_CACHE_RECENT_TTL_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("RESULT_CACHE_RECENT_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60)
+});
+
+//get_graphdata_from_dbの戻り値
+pub type GraphResult = (HashMap<String,Vec<PlotData>>,GridData,HashMap<String,SeriesStatistics>);
+
+pub static GRAPH_CACHE: Lazy<ResultCache<GraphResult>> = Lazy::new(|| ResultCache::new("graph"));
+pub static ALARM_CACHE: Lazy<ResultCache<HashMap<String,LotUnitData>>> = Lazy::new(|| ResultCache::new("alarm"));
+
+//キャッシュする結果のメモリ使用量の推定値(バイト)
+//要素数×要素のサイズで見積もる(ヒープ上の文字列などは概算)
+pub trait CacheSize{
+    fn estimated_bytes(&self)->usize;
+}
+
+impl CacheSize for GraphResult{
+    fn estimated_bytes(&self)->usize{
+        let (data_map, grid_data, statistics) = self;
+        let series_bytes: usize = data_map.iter().map(|(name, rows)| name.len() + rows.len() * std::mem::size_of::<PlotData>()).sum();
+        let statistics_bytes: usize = statistics.keys().map(|name| name.len() + std::mem::size_of::<SeriesStatistics>()).sum();
+        let downsample_bytes: usize = grid_data.downsample_info.as_ref()
+            .map(|info| info.keys().map(|name| name.len() + std::mem::size_of::<DownsampleInfo>()).sum())
+            .unwrap_or(0);
+        std::mem::size_of::<GraphResult>() + series_bytes + statistics_bytes + downsample_bytes
+    }
+}
+
+impl CacheSize for HashMap<String,LotUnitData>{
+    fn estimated_bytes(&self)->usize{
+        self.iter().map(|(lot_name, unit)| {
+            let counts = &unit.alarm_counts;
+            let code_count = counts.ld_alarm.len() + counts.dc1_alarm.len() + counts.ac1_alarm.len() + counts.ac2_alarm.len()
+                + counts.dc2_alarm.len() + counts.ip_alarm.len() + counts.uld_alarm.len();
+            lot_name.len() + std::mem::size_of::<LotUnitData>()
+                + unit.type_name.len() + unit.lot_start_time.len() + unit.lot_end_time.len()
+                + code_count * std::mem::size_of::<(i32, u32)>()
+        }).sum()
+    }
+}
+
+struct CacheEntry<V>{
+    value:Arc<V>,
+    bytes:usize,
+    expires_at:Instant,
+    last_used:Instant,
+}
+
+//保存中の結果と推定バイト数の合計
+struct CacheState<V>{
+    entries:HashMap<String,CacheEntry<V>>,
+    total_bytes:usize,
+}
+
+impl<V> CacheState<V>{
+    fn remove(&mut self,key:&str){
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_bytes -= entry.bytes;
+        }
+    }
+}
+
+pub struct ResultCache<V>{
+    name:&'static str,
+    state:Mutex<CacheState<V>>,
+}
+
+impl<V:CacheSize> ResultCache<V>{
+    fn new(name:&'static str)->Self{
+        ResultCache{name, state:Mutex::new(CacheState{entries:HashMap::new(), total_bytes:0})}
+    }
+
+    //保持時間内の結果を返す
+    pub fn get(&self,key:&str)->Option<Arc<V>>{
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.entries.get_mut(key) {
+            Some(entry) if entry.expires_at > now => {
+                entry.last_used = now;
+                Some(entry.value.clone())
+            },
+            Some(_) => {
+                state.remove(key);
+                None
+            },
+            None => None,
+        }
+    }
+
+    //結果を保存する(保持時間は期間の終了日時で決める)
+    //1件の上限サイズを超える結果は保存せずにそのまま返す
+    pub fn insert(&self,key:String,value:V,end_date:&NaiveDateTime)->Arc<V>{
+        let bytes = value.estimated_bytes() + key.len();
+        let value = Arc::new(value);
+        let max_entries = *RESULT_CACHE_MAX_ENTRIES;
+        let max_bytes = *RESULT_CACHE_MAX_BYTES;
+        if max_entries == 0 || bytes > *RESULT_CACHE_MAX_ENTRY_BYTES || bytes > max_bytes {
+            debug!("Skipped caching {} result ({} bytes): {}", self.name, bytes, key);
+            return value;
+        }
+
+        let ttl_secs = if *end_date < chrono::Local::now().naive_local() { *RESULT_CACHE_PAST_TTL_SECS } else { *RESULT_CACHE_RECENT_TTL_SECS };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state.entries.iter().filter(|(_, entry)| entry.expires_at <= now).map(|(key, _)| key.clone()).collect();
+        for expired_key in expired.iter() {
+            state.remove(expired_key);
+        }
+        state.remove(&key);
+        while state.entries.len() >= max_entries || state.total_bytes + bytes > max_bytes {
+            let oldest = state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => state.remove(&oldest),
+                None => break,
+            }
+        }
+        state.total_bytes += bytes;
+        debug!("Cached {} result for {} s ({} entries, {} bytes): {}", self.name, ttl_secs, state.entries.len() + 1, state.total_bytes, key);
+        state.entries.insert(key, CacheEntry{
+            value: value.clone(),
+            bytes,
+            expires_at: now + Duration::from_secs(ttl_secs),
+            last_used: now,
+        });
+        value
+    }
+
+    //全ての結果を削除して削除件数を返す
+    pub fn flush(&self)->usize{
+        let mut state = self.state.lock().unwrap();
+        let count = state.entries.len();
+        state.entries.clear();
+        state.total_bytes = 0;
+        count
+    }
+}
+
+//日時の文字列を解釈する(日付のみの場合は0時)
+pub fn parse_date(value:&str)->Option<NaiveDateTime>{
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok()
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+}
+
+//グラフ作成条件のキャッシュキー
+//同じ結果になる条件が同じキーになるように、日時の表記とアラームコードの順番を揃える
+//日時を解釈できない場合はキャッシュしない(None)
+pub fn graph_cache_key(graph_condition:&GraphCondition)->Option<(String,NaiveDateTime)>{
+    let start_date = parse_date(&graph_condition.start_date)?;
+    let end_date = parse_date(&graph_condition.end_date)?;
+
+    let mut value = serde_json::to_value(graph_condition).ok()?;
+    value["start_date"] = start_date.to_string().into();
+    value["end_date"] = end_date.to_string().into();
+    let mut codes = graph_condition.alarm.codes.clone();
+    codes.sort_unstable();
+    codes.dedup();
+    value["alarm"]["codes"] = codes.into();
+
+    Some((value.to_string(), end_date))
+}
+
+//アラーム集計条件のキャッシュキー
+pub fn alarm_cache_key(machine_data:&MachineData)->Option<(String,NaiveDateTime)>{
+    let start_date = parse_date(&machine_data.start_date)?;
+    let end_date = parse_date(&machine_data.end_date)?;
+    Some((format!("{}|{}|{}", machine_data.machine_id, start_date, end_date), end_date))
+}
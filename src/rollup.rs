@@ -0,0 +1,334 @@
+/* 時間単位の集計テーブル(ロールアップ)の作成・更新 */
+//長期間の問い合わせで生データを走査しないように、1時間ごと・号機/品種/ロットごとの集計値を保持する
+//  chipdata_hourly: チップ数
+//  chipdata_hourly_alarms: ステーション・アラームコードごとの件数
+//  chipdata_hourly_values: ROLLUP_COLUMNSのカラムごとの件数・合計・二乗和・最小・最大(平均・標準偏差の計算用)
+//  chipdata_rollup_hours: 集計済みの時間帯(集計したカラム一覧と集計日時)
+//直近ROLLUP_LOOKBACK_HOURS時間は更新のたびに集計し直し、それより前はROLLUP_RECHECK_INTERVAL_SECSごとにチップ数を比較して遅れて登録された分を集計し直す
+use chrono::{DurationRound, Local, NaiveDateTime, TimeDelta};
+use once_cell::sync::Lazy;
+use sqlx::PgPool;
+use std::env;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::graph::columns::column_type;
+use crate::result_cache::parse_date;
+use crate::variants::ALARM_STATIONS;
+
+//ロールアップを使うかどうか(falseの場合はテーブルの作成・更新もしない)
+static ROLLUP_ENABLED: Lazy<bool> = Lazy::new(|| {
+    env::var("ROLLUP_ENABLED").map(|v| !matches!(v.to_lowercase().as_str(), "false" | "0" | "off")).unwrap_or(true)
+});
+
+//平均・最小・最大を集計するカラム(カンマ区切り)
+static ROLLUP_COLUMNS: Lazy<Vec<String>> = Lazy::new(|| {
+    env::var("ROLLUP_COLUMNS").unwrap_or("DC1_STAGE_Z,DC1_PIN_Z,DC1_PROBE_RES,DC1_OFFSET".to_string())
+        .split(',')
+        .map(|c| c.trim().to_uppercase())
+        .filter(|c| !c.is_empty())
+        .collect()
+});
+
+//バックグラウンドで更新する間隔
+static ROLLUP_INTERVAL_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("ROLLUP_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(300)
+});
+
+//更新のたびに集計し直す直近の時間数(遅れて登録されたデータを反映するため)
+static ROLLUP_LOOKBACK_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("ROLLUP_LOOKBACK_HOURS").ok().and_then(|v| v.parse().ok()).filter(|n| *n >= 0).unwrap_or(2)
+});
+
+//遅れて登録されたチップを確認する過去の時間数(ROLLUP_LOOKBACK_HOURSより前の集計済みの時間が対象)
+static ROLLUP_RECHECK_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("ROLLUP_RECHECK_HOURS").ok().and_then(|v| v.parse().ok()).filter(|n| *n >= 0).unwrap_or(7 * 24)
+});
+
+//遅れて登録されたチップを確認する間隔
+static ROLLUP_RECHECK_INTERVAL_SECS: Lazy<u64> = Lazy::new(|| {
+    env::var("ROLLUP_RECHECK_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(3600)
+});
+
+//集計済みの時間帯が無い場合に最初に集計する時間数(それより前はバックフィルで集計する)
+static ROLLUP_INITIAL_HOURS: Lazy<i64> = Lazy::new(|| {
+    env::var("ROLLUP_INITIAL_HOURS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(24)
+});
+
+//1トランザクションで集計する時間数
+const ROLLUP_CHUNK_HOURS: i64 = 24;
+
+//テーブルの作成に成功した場合のみtrue
+static ROLLUP_READY: AtomicBool = AtomicBool::new(false);
+
+//バックグラウンドの更新とバックフィルが同時に走らないようにする
+static ROLLUP_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+const CREATE_TABLES_SQL: &str = "
+CREATE TABLE IF NOT EXISTS chipdata_hourly (
+    hour_start timestamp NOT NULL, machine_id integer, type_name text, lot_name text,
+    chip_count bigint NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chipdata_hourly_machine_idx ON chipdata_hourly (machine_id, hour_start);
+CREATE TABLE IF NOT EXISTS chipdata_hourly_alarms (
+    hour_start timestamp NOT NULL, machine_id integer, type_name text, lot_name text,
+    station text NOT NULL, alarm_code integer NOT NULL, alarm_count bigint NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chipdata_hourly_alarms_machine_idx ON chipdata_hourly_alarms (machine_id, hour_start);
+CREATE TABLE IF NOT EXISTS chipdata_hourly_values (
+    hour_start timestamp NOT NULL, machine_id integer, type_name text, lot_name text,
+    column_name text NOT NULL, chip_count bigint NOT NULL, value_count bigint NOT NULL,
+    value_sum numeric, value_sum_sq numeric, value_min double precision, value_max double precision
+);
+CREATE INDEX IF NOT EXISTS chipdata_hourly_values_column_idx ON chipdata_hourly_values (column_name, hour_start);
+CREATE TABLE IF NOT EXISTS chipdata_rollup_hours (
+    hour_start timestamp PRIMARY KEY, value_columns text[] NOT NULL, rolled_up_at timestamp NOT NULL
+);
+";
+
+//ロールアップを使用できるかどうか
+pub fn is_rollup_enabled()->bool{
+    *ROLLUP_ENABLED && ROLLUP_READY.load(Ordering::Relaxed)
+}
+
+//平均・最小・最大を集計するカラムのうち、CHIPDATAに存在する数値カラム
+fn value_columns()->Vec<String>{
+    ROLLUP_COLUMNS.iter()
+        .filter(|c| column_type(c).map(|t| t.is_numeric()).unwrap_or(false))
+        .cloned()
+        .collect()
+}
+
+//テーブルを作成してバックグラウンドの更新を開始する
+//作成に失敗した場合はロールアップを使わずに生データから取得する
+pub async fn start_rollup(pool:&PgPool){
+    if !*ROLLUP_ENABLED {
+        info!("Rollup is disabled");
+        return;
+    }
+    if let Err(e) = sqlx::raw_sql(CREATE_TABLES_SQL).execute(pool).await {
+        error!("Failed to create rollup tables, rollup is disabled: {}", e);
+        return;
+    }
+    ROLLUP_READY.store(true, Ordering::Relaxed);
+
+    let columns = value_columns();
+    let invalid: Vec<&String> = ROLLUP_COLUMNS.iter().filter(|c| !columns.contains(c)).collect();
+    if !invalid.is_empty() {
+        warn!("ROLLUP_COLUMNS contains columns that are not numeric CHIPDATA columns: {:?}", invalid);
+    }
+    info!("Rollup enabled for columns: {:?} (interval {} s)", columns, *ROLLUP_INTERVAL_SECS);
+
+    let pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        let mut last_recheck: Option<Instant> = None;
+        loop {
+            match refresh_recent_hours(&pool).await {
+                Ok(hours) => info!("Rolled up {} hours", hours),
+                Err(e) => error!("Failed to roll up recent hours: {}", e),
+            }
+            if last_recheck.is_none_or(|t| t.elapsed() >= Duration::from_secs(*ROLLUP_RECHECK_INTERVAL_SECS)) {
+                match recheck_late_hours(&pool).await {
+                    Ok(hours) if hours > 0 => info!("Rolled up {} hours again for late chips", hours),
+                    Ok(_) => {},
+                    Err(e) => error!("Failed to recheck rolled up hours: {}", e),
+                }
+                last_recheck = Some(Instant::now());
+            }
+            tokio::time::sleep(Duration::from_secs(*ROLLUP_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+//現在時刻の時間の開始(集計するのはこれより前の完了した時間のみ)
+fn current_hour()->NaiveDateTime{
+    let now = Local::now().naive_local();
+    now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now)
+}
+
+//前回集計した時間の続きから、完了した時間までを集計する(直近ROLLUP_LOOKBACK_HOURS時間は集計し直す)
+async fn refresh_recent_hours(pool:&PgPool)->Result<i64,sqlx::Error>{
+    let _lock = ROLLUP_LOCK.lock().await;
+    let end = current_hour();
+    let last: Option<NaiveDateTime> = sqlx::query_scalar("SELECT MAX(hour_start) FROM chipdata_rollup_hours")
+        .fetch_one(pool)
+        .await?;
+    let start = last.map(|h| h + TimeDelta::hours(1)).unwrap_or(end - TimeDelta::hours(*ROLLUP_INITIAL_HOURS)).min(end)
+        - TimeDelta::hours(*ROLLUP_LOOKBACK_HOURS);
+    rebuild_hours(pool, start, end).await
+}
+
+//直近ROLLUP_LOOKBACK_HOURS時間より前の集計済みの時間のうち、チップ数が生データと一致しない時間を集計し直す
+//(ロールアップ後に遅れて登録・削除されたチップを反映する。件数が変わらない値の更新は検出できないため、その場合はバックフィルで集計し直す)
+//戻り値: 集計し直した時間数
+async fn recheck_late_hours(pool:&PgPool)->Result<i64,sqlx::Error>{
+    let _lock = ROLLUP_LOCK.lock().await;
+    let end = current_hour() - TimeDelta::hours(*ROLLUP_LOOKBACK_HOURS);
+    let start = end - TimeDelta::hours(*ROLLUP_RECHECK_HOURS);
+    let stale_hours: Vec<NaiveDateTime> = sqlx::query_scalar(
+        "WITH raw AS (
+            SELECT date_trunc('hour', ld_pickup_date) AS hour_start, COUNT(*) AS chip_count
+            FROM chipdata WHERE ld_pickup_date >= $1 AND ld_pickup_date < $2 GROUP BY 1
+        ), rolled AS (
+            SELECT hour_start, SUM(chip_count) AS chip_count
+            FROM chipdata_hourly WHERE hour_start >= $1 AND hour_start < $2 GROUP BY 1
+        )
+        SELECT h.hour_start FROM chipdata_rollup_hours h
+        LEFT JOIN raw ON raw.hour_start = h.hour_start
+        LEFT JOIN rolled ON rolled.hour_start = h.hour_start
+        WHERE h.hour_start >= $1 AND h.hour_start < $2 AND COALESCE(raw.chip_count, 0) <> COALESCE(rolled.chip_count, 0)
+        ORDER BY 1"
+    ).bind(start).bind(end).fetch_all(pool).await?;
+
+    let mut hours = 0;
+    for hour_start in stale_hours {
+        hours += rebuild_hours(pool, hour_start, hour_start + TimeDelta::hours(1)).await?;
+    }
+    Ok(hours)
+}
+
+//指定した期間を集計し直す(日時は時間単位に広げ、現在の時間以降は集計しない)
+//戻り値: 集計した時間数
+pub async fn backfill_rollup(pool:&PgPool,start_date:&str,end_date:&str)->Result<i64,Box<dyn Error>>{
+    if !is_rollup_enabled() {
+        return Err("Rollup is disabled".into());
+    }
+    let start = parse_date(start_date).ok_or(format!("Invalid start_date: {}", start_date))?;
+    let end = parse_date(end_date).ok_or(format!("Invalid end_date: {}", end_date))?;
+    let start = start.duration_trunc(TimeDelta::hours(1))?;
+    let end_hour = end.duration_trunc(TimeDelta::hours(1))?;
+    let end = if end_hour == end { end } else { end_hour + TimeDelta::hours(1) };
+    let end = end.min(current_hour());
+    if start >= end {
+        return Err(format!("No completed hours to roll up between {} and {}", start_date, end_date).into());
+    }
+
+    let _lock = ROLLUP_LOCK.lock().await;
+    Ok(rebuild_hours(pool, start, end).await?)
+}
+
+//[start, end)の時間を集計し直す
+//ROLLUP_CHUNK_HOURSごとにトランザクションを分け、古い集計値を削除してから作り直す
+async fn rebuild_hours(pool:&PgPool,start:NaiveDateTime,end:NaiveDateTime)->Result<i64,sqlx::Error>{
+    let columns = value_columns();
+    let alarm_values = ALARM_STATIONS.iter()
+        .map(|s| format!("('{s}', {s}_ALARM)", s = s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let value_values = columns.iter()
+        .map(|c| format!("('{c}', {c}::numeric)", c = c))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut chunk_start = start;
+    while chunk_start < end {
+        let chunk_end = (chunk_start + TimeDelta::hours(ROLLUP_CHUNK_HOURS)).min(end);
+        let mut tx = pool.begin().await?;
+        //別プロセスのサーバーと同時に集計しないようにする
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('chipdata_rollup'))").execute(&mut *tx).await?;
+
+        for table in ["chipdata_hourly", "chipdata_hourly_alarms", "chipdata_hourly_values"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE hour_start >= $1 AND hour_start < $2", table))
+                .bind(chunk_start).bind(chunk_end)
+                .execute(&mut *tx).await?;
+        }
+
+        sqlx::query(
+            "INSERT INTO chipdata_hourly (hour_start, machine_id, type_name, lot_name, chip_count)
+            SELECT date_trunc('hour', ld_pickup_date), machine_id, type_name, lot_name, COUNT(*)
+            FROM chipdata WHERE ld_pickup_date >= $1 AND ld_pickup_date < $2
+            GROUP BY 1, 2, 3, 4"
+        ).bind(chunk_start).bind(chunk_end).execute(&mut *tx).await?;
+
+        sqlx::query(&format!(
+            "INSERT INTO chipdata_hourly_alarms (hour_start, machine_id, type_name, lot_name, station, alarm_code, alarm_count)
+            SELECT date_trunc('hour', ld_pickup_date), machine_id, type_name, lot_name, a.station, a.alarm_code, COUNT(*)
+            FROM chipdata CROSS JOIN LATERAL (VALUES {}) AS a(station, alarm_code)
+            WHERE ld_pickup_date >= $1 AND ld_pickup_date < $2 AND a.alarm_code IS NOT NULL
+            GROUP BY 1, 2, 3, 4, 5, 6", alarm_values
+        )).bind(chunk_start).bind(chunk_end).execute(&mut *tx).await?;
+
+        if !columns.is_empty() {
+            sqlx::query(&format!(
+                "INSERT INTO chipdata_hourly_values (hour_start, machine_id, type_name, lot_name, column_name,
+                    chip_count, value_count, value_sum, value_sum_sq, value_min, value_max)
+                SELECT date_trunc('hour', ld_pickup_date), machine_id, type_name, lot_name, v.column_name,
+                    COUNT(*), COUNT(v.value), SUM(v.value), SUM(v.value * v.value), MIN(v.value)::double precision, MAX(v.value)::double precision
+                FROM chipdata CROSS JOIN LATERAL (VALUES {}) AS v(column_name, value)
+                WHERE ld_pickup_date >= $1 AND ld_pickup_date < $2
+                GROUP BY 1, 2, 3, 4, 5", value_values
+            )).bind(chunk_start).bind(chunk_end).execute(&mut *tx).await?;
+        }
+
+        sqlx::query(
+            "INSERT INTO chipdata_rollup_hours (hour_start, value_columns, rolled_up_at)
+            SELECT generate_series($1, $2 - interval '1 hour', interval '1 hour'), $3, localtimestamp
+            ON CONFLICT (hour_start) DO UPDATE SET value_columns = EXCLUDED.value_columns, rolled_up_at = EXCLUDED.rolled_up_at"
+        ).bind(chunk_start).bind(chunk_end).bind(&columns).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        chunk_start = chunk_end;
+    }
+
+    Ok((end - start).num_hours().max(0))
+}
+
+//期間[start_date, end_date]のうち、ロールアップで答えられる時間の範囲[start, end)を返す
+//期間の端の1時間に満たない部分は呼び出し側で生データから集計する
+//期間に1時間全体が含まれない場合・範囲内に未集計の時間がある場合はNone
+//columnを指定した場合は、そのカラムの平均等も集計済みであることを確認する
+pub async fn rollup_range(pool:&PgPool,start_date:&str,end_date:&str,column:Option<&str>)->Result<Option<(NaiveDateTime,NaiveDateTime)>,sqlx::Error>{
+    if !is_rollup_enabled() {
+        return Ok(None);
+    }
+    let (start, end) = match (parse_date(start_date), parse_date(end_date)) {
+        (Some(start_date), Some(end_date)) => match full_hours(start_date, end_date) {
+            Some(range) => range,
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    let covered: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM chipdata_rollup_hours
+        WHERE hour_start >= $1 AND hour_start < $2 AND ($3::text IS NULL OR $3 = ANY(value_columns))"
+    ).bind(start).bind(end).bind(column.map(|c| c.to_uppercase())).fetch_one(pool).await?;
+
+    Ok(if covered == (end - start).num_hours() { Some((start, end)) } else { None })
+}
+
+//期間[start_date, end_date]に含まれる1時間全体の範囲[start, end)を返す(含まれない場合はNone)
+//終了はその時刻を含むため、hh:59:59まではその時間全体、hh:00:00はその時刻だけ(生データで集計する)になる
+fn full_hours(start_date:NaiveDateTime,end_date:NaiveDateTime)->Option<(NaiveDateTime,NaiveDateTime)>{
+    let hour = TimeDelta::hours(1);
+    let start = start_date.duration_trunc(hour).ok()?;
+    let start = if start == start_date { start } else { start + hour };
+    let end = (end_date + TimeDelta::seconds(1)).duration_trunc(hour).ok()?;
+    if start < end { Some((start, end)) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s:&str)->NaiveDateTime{
+        parse_date(s).unwrap()
+    }
+
+    #[test]
+    fn full_hours_of_aligned_range(){
+        assert_eq!(full_hours(date("2026-10-01 00:00:00"), date("2026-10-01 23:59:59")), Some((date("2026-10-01 00:00:00"), date("2026-10-02 00:00:00"))));
+        //終了がhh:00:00の場合、その時刻のチップは生データで集計する
+        assert_eq!(full_hours(date("2026-10-01 00:00:00"), date("2026-10-02 00:00:00")), Some((date("2026-10-01 00:00:00"), date("2026-10-02 00:00:00"))));
+    }
+
+    #[test]
+    fn full_hours_excludes_partial_edges(){
+        assert_eq!(full_hours(date("2026-10-01 08:30:00"), date("2026-10-01 17:15:00")), Some((date("2026-10-01 09:00:00"), date("2026-10-01 17:00:00"))));
+        assert_eq!(full_hours(date("2026-10-01 08:00:01"), date("2026-10-01 09:59:58")), None);
+        assert_eq!(full_hours(date("2026-10-01 08:30:00"), date("2026-10-01 09:59:59")), Some((date("2026-10-01 09:00:00"), date("2026-10-01 10:00:00"))));
+        assert_eq!(full_hours(date("2026-10-02 00:00:00"), date("2026-10-01 00:00:00")), None);
+    }
+}
@@ -15,21 +15,33 @@ pub struct MachineData{
     pub end_date:String,
 }
 
-/* アラームデータ取得関係の構造体 */
-#[derive(Debug)]
-pub struct ChipRecord {
-    pub machine_name: Option<String>,
-    pub type_name: Option<String>,
-    pub lot_name: Option<String>,
-    pub ld_alarm: Option<i32>,
-    pub dc1_alarm: Option<i32>,
-    pub ac1_alarm: Option<i32>,
-    pub ac2_alarm: Option<i32>,
-    pub dc2_alarm: Option<i32>,
-    pub ip_alarm: Option<i32>,
-    pub uld_alarm: Option<i32>,
+//非同期ジョブの指定
+#[derive(Debug,Deserialize)]
+pub struct JobIdData{
+    pub job_id:String,
+}
+
+//ライブ配信で購読する号機・アラームコード(カンマ区切り、省略時は全て)
+#[derive(Debug,Deserialize)]
+pub struct LiveSubscriptionData{
+    pub machines:Option<String>,
+    pub alarm_codes:Option<String>,
 }
 
+//ロールアップのバックフィルの期間
+#[derive(Debug,Deserialize)]
+pub struct RollupBackfillData{
+    pub start_date:String,
+    pub end_date:String,
+}
+
+/* アラームデータ取得関係の構造体 */
+//号機一覧
+pub const MACHINE_IDS: &[i32] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+//アラームのカラムがあるステーション(カラム名は{ステーション}_ALARM)
+pub const ALARM_STATIONS: &[&str] = &["LD", "DC1", "AC1", "AC2", "DC2", "IP", "ULD"];
+
 #[derive(Debug,Serialize,Clone)]
 pub struct AlarmCounts {
     pub ld_alarm: BTreeMap<i32, u32>,
@@ -42,34 +54,26 @@ pub struct AlarmCounts {
 }
 
 impl AlarmCounts{
-    pub fn new(&mut self,ld_vec:Vec<i32>,dc1_vec:Vec<i32>,ac1_vec:Vec<i32>,ac2_vec:Vec<i32>,dc2_vec:Vec<i32>,ip_vec:Vec<i32>,uld_vec:Vec<i32>){
-        //ld部分作成
-        for key in ld_vec{
-            self.ld_alarm.entry(key).or_insert(0);
-        }
-        //dc1部分作成
-        for key in dc1_vec{
-            self.dc1_alarm.entry(key).or_insert(0);
-        }
-        //ac1部分作成
-        for key in ac1_vec{
-            self.ac1_alarm.entry(key).or_insert(0);
+    //ステーションのアラームコードを件数0で追加する
+    pub fn add_codes(&mut self,station:&str,codes:Vec<i32>){
+        if let Some(counts) = self.station_mut(station) {
+            for key in codes{
+                counts.entry(key).or_insert(0);
+            }
         }
-        //ac2部分作成
-        for key in ac2_vec{
-            self.ac2_alarm.entry(key).or_insert(0);
-        }
-        //dc2部分作成
-        for key in dc2_vec{
-            self.dc2_alarm.entry(key).or_insert(0);
-        }
-        //ip部分作成
-        for key in ip_vec{
-            self.ip_alarm.entry(key).or_insert(0);
-        }
-        //uld部分作成
-        for key in uld_vec{
-            self.uld_alarm.entry(key).or_insert(0);
+    }
+
+    //ステーション名(LD,DC1,...)に対応するアラームコード→件数を返す
+    pub fn station_mut(&mut self,station:&str)->Option<&mut BTreeMap<i32,u32>>{
+        match station {
+            "LD" => Some(&mut self.ld_alarm),
+            "DC1" => Some(&mut self.dc1_alarm),
+            "AC1" => Some(&mut self.ac1_alarm),
+            "AC2" => Some(&mut self.ac2_alarm),
+            "DC2" => Some(&mut self.dc2_alarm),
+            "IP" => Some(&mut self.ip_alarm),
+            "ULD" => Some(&mut self.uld_alarm),
+            _ => None,
         }
     }
 }
@@ -85,6 +89,23 @@ pub struct AlarmDetail{
     pub uld_alarm:HashMap<String,String>,
 }
 
+impl AlarmDetail{
+    //ステーション名(LD,DC1,...)とアラームコードに対応する説明を返す
+    pub fn description(&self,station:&str,code:i32)->Option<&String>{
+        let alarms = match station {
+            "LD" => &self.ld_alarm,
+            "DC1" => &self.dc1_alarm,
+            "AC1" => &self.ac1_alarm,
+            "AC2" => &self.ac2_alarm,
+            "DC2" => &self.dc2_alarm,
+            "IP" => &self.ip_alarm,
+            "ULD" => &self.uld_alarm,
+            _ => return None,
+        };
+        alarms.get(&code.to_string())
+    }
+}
+
 #[derive(Debug,Serialize)]
 pub struct LotUnitData {
     pub machine_id: i32,
@@ -93,19 +114,3 @@ pub struct LotUnitData {
     pub lot_end_time: String,
     pub alarm_counts: AlarmCounts,
 }
-
-impl LotUnitData {
-    pub fn check_date(&mut self, ld_time: &str,uld_time:&str) {
-        if self.lot_start_time.is_empty(){
-            self.lot_start_time=ld_time.to_string();
-        }else if self.lot_start_time > ld_time.to_string() {
-            self.lot_start_time = ld_time.to_string();
-        }
-
-        if self.lot_end_time.is_empty(){
-            self.lot_end_time=uld_time.to_string();
-        }else if self.lot_end_time < uld_time.to_string() {
-            self.lot_end_time = uld_time.to_string();
-        }
-    }
-}